@@ -0,0 +1,49 @@
+use std::{fs, io, time::Duration};
+
+use crate::{
+    car::{Direction, Origin},
+    traffic_light::TrafficLight,
+};
+
+/// When a finished car crossed the stop line and entered and cleared the intersection box
+#[derive(Clone, Debug)]
+pub struct BoxCrossing {
+    pub id: usize,
+    pub origin: Origin,
+    pub direction: Direction,
+    pub crossed_stop_line: Option<Duration>,
+    pub entered_box: Option<Duration>,
+    pub cleared_box: Option<Duration>,
+}
+
+impl BoxCrossing {
+    /// Returns how long the car was in the box
+    pub fn time_in_box(&self) -> Option<Duration> {
+        Some(self.cleared_box?.saturating_sub(self.entered_box?))
+    }
+}
+
+/// Writes the crossings as CSV, one row per car, next to the crossing time the light assumes for
+/// the movement when it computes the red clearance. Events that didn't happen are left empty.
+pub fn write_csv(crossings: &[BoxCrossing], path: &str) -> io::Result<()> {
+    let seconds = |time: Option<Duration>| {
+        time.map_or(String::new(), |time| format!("{:.03}", time.as_secs_f64()))
+    };
+    let mut csv = String::from(
+        "id,origin,direction,crossed_stop_line,entered_box,cleared_box,time_in_box,predicted_crossing\n",
+    );
+    for crossing in crossings {
+        csv.push_str(&format!(
+            "{},{:?},{:?},{},{},{},{},{:.03}\n",
+            crossing.id,
+            crossing.origin,
+            crossing.direction,
+            seconds(crossing.crossed_stop_line),
+            seconds(crossing.entered_box),
+            seconds(crossing.cleared_box),
+            seconds(crossing.time_in_box()),
+            TrafficLight::crossing_time(crossing.direction).as_secs_f64()
+        ));
+    }
+    fs::write(path, csv)
+}
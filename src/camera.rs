@@ -0,0 +1,64 @@
+use piston_window::*;
+
+use crate::{HEIGHT, WIDTH};
+
+/// How much of the distance to its target the camera covers each update when following
+const FOLLOW_SMOOTHING: f64 = 0.1;
+/// How much one scroll step zooms in/out
+const ZOOM_STEP: f64 = 1.1;
+const MIN_ZOOM: f64 = 0.5;
+const MAX_ZOOM: f64 = 4.0;
+/// How far the camera pans per key press (in screen pixels)
+const PAN_STEP: f64 = 40.0;
+
+pub struct Camera {
+    /// The point of the map at the center of the screen
+    center: (f64, f64),
+    zoom: f64,
+}
+
+impl Camera {
+    pub fn new() -> Camera {
+        Camera {
+            center: (WIDTH as f64 / 2.0, HEIGHT as f64 / 2.0),
+            zoom: 1.0,
+        }
+    }
+
+    /// Smoothly moves the camera towards the target so that it ends up centered
+    pub fn follow(&mut self, target: (f64, f64)) {
+        self.center.0 += (target.0 - self.center.0) * FOLLOW_SMOOTHING;
+        self.center.1 += (target.1 - self.center.1) * FOLLOW_SMOOTHING;
+    }
+
+    /// Pans by a number of steps in each axis
+    pub fn pan(&mut self, steps: (f64, f64)) {
+        self.center.0 += steps.0 * PAN_STEP / self.zoom;
+        self.center.1 += steps.1 * PAN_STEP / self.zoom;
+    }
+
+    /// Zooms in (positive steps) or out (negative steps)
+    pub fn zoom(&mut self, steps: f64) {
+        self.zoom = (self.zoom * ZOOM_STEP.powf(steps)).clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+
+    pub fn reset(&mut self) {
+        *self = Camera::new();
+    }
+
+    /// Returns the context to draw the map with
+    pub fn apply(&self, context: &Context) -> Context {
+        context
+            .trans(WIDTH as f64 / 2.0, HEIGHT as f64 / 2.0)
+            .zoom(self.zoom)
+            .trans(-self.center.0, -self.center.1)
+    }
+
+    /// Converts a point on the screen to a point on the map
+    pub fn to_map(&self, point: [f64; 2]) -> (f64, f64) {
+        (
+            (point[0] - WIDTH as f64 / 2.0) / self.zoom + self.center.0,
+            (point[1] - HEIGHT as f64 / 2.0) / self.zoom + self.center.1,
+        )
+    }
+}
@@ -1,13 +1,22 @@
 use piston_window::*;
+use std::time::Duration;
 
 use crate::{
-    traffic_light::{self, TrafficLight},
-    HEIGHT, WIDTH,
+    controller::Controller,
+    intersection::{Occupancy, INTERSECTION_BOX},
+    simulation::TICK,
+    sprites::Sprites,
+    traffic_light, APPROACH_LENGTHS, APPROACH_SKEWS, BANNED_MOVEMENTS, COLLISION_PADDING,
+    CREEP_DISTANCE, DECELERATION, DESPAWN_PAST_INTERSECTION, DILEMMA_ZONE, DONT_BLOCK_THE_BOX,
+    EMERGENCY_BRAKING_GAP, EMERGENCY_DECELERATION, HEIGHT, LEFT_TURN_CREEP, LEFT_TURN_STORAGE,
+    MIN_SPAWN_GAP, PERMISSIVE_LEFT_GAP, ROTATION_HALF_LIFE, STARTUP_DELAY, STOP_LINE_OFFSETS,
+    STRICT_SINGLE_CAR, WIDTH,
 };
 
 pub const MAX_SPEED: f64 = 5.0;
 pub const ACCELERATION: f64 = 0.15;
-const DECELERATION: f64 = 0.3;
+/// Highest sideways acceleration drivers accept while turning
+const MAX_LATERAL_ACCELERATION: f64 = 0.12;
 
 const DISTANCE_THRESHOLD: f64 = 5.0;
 
@@ -19,6 +28,13 @@ pub const LANE_WIDTH: f64 = CAR_HEIGHT * 1.5;
 
 const NUM_PATH_POINTS: usize = 25; // Higher = more accurate path but more expensive
 
+/// Approach lengths that put the spawn points right at the edge of the window
+pub const NORTH_SOUTH_EDGE: f64 = INTERSECTION_BOX.extent().1 - CAR_WIDTH / 2.0;
+pub const EAST_WEST_EDGE: f64 = INTERSECTION_BOX.extent().0 - CAR_WIDTH / 2.0;
+
+/// Every approach, in the order they're gone through whenever the order matters
+pub const ORIGINS: [Origin; 4] = [Origin::North, Origin::South, Origin::East, Origin::West];
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum Origin {
     North,
@@ -54,13 +70,66 @@ impl Origin {
             Origin::West => Origin::East,
         }
     }
+
+    /// Index of the origin in `ORIGINS` (and the per approach config arrays)
+    pub fn index(&self) -> usize {
+        match self {
+            Origin::North => 0,
+            Origin::South => 1,
+            Origin::East => 2,
+            Origin::West => 3,
+        }
+    }
+}
+
+/// What kind of vehicle a car is. Heavier vehicles are bigger and accelerate slower.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum VehicleClass {
+    Car,
+    Truck,
+    Bus,
+}
+
+impl VehicleClass {
+    /// Size along the direction of travel
+    pub fn length(&self) -> f64 {
+        match self {
+            VehicleClass::Car => CAR_WIDTH,
+            VehicleClass::Truck => CAR_WIDTH * 1.8,
+            VehicleClass::Bus => CAR_WIDTH * 2.4,
+        }
+    }
+
+    pub fn width(&self) -> f64 {
+        match self {
+            VehicleClass::Car => CAR_HEIGHT,
+            VehicleClass::Truck | VehicleClass::Bus => CAR_HEIGHT * 1.15,
+        }
+    }
+
+    pub fn acceleration(&self) -> f64 {
+        match self {
+            VehicleClass::Car => ACCELERATION,
+            VehicleClass::Truck => ACCELERATION * 0.4,
+            VehicleClass::Bus => ACCELERATION * 0.5,
+        }
+    }
+
+    fn color(&self) -> [f32; 4] {
+        match self {
+            VehicleClass::Car => [1.0; 4],
+            VehicleClass::Truck => [0.6, 0.75, 1.0, 1.0],
+            VehicleClass::Bus => [1.0, 0.8, 0.3, 1.0],
+        }
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum Direction {
     Left,
     Right,
     Straight,
+    UTurn,
 }
 
 impl Direction {
@@ -69,9 +138,49 @@ impl Direction {
             0 => Direction::Left,
             1 => Direction::Right,
             2 => Direction::Straight,
+            3 => Direction::UTurn,
             _ => panic!("Invalid direction"),
         }
     }
+
+    /// Returns if the movement turns across the oncoming traffic (so it has to yield to it and
+    /// waits in the left turn bay)
+    pub fn crosses_oncoming(&self) -> bool {
+        matches!(self, Direction::Left | Direction::UTurn)
+    }
+}
+
+/// How a driver differs from the average one. Each is a multiplier (1.0 = average).
+#[derive(Clone, Copy, Debug)]
+pub struct Driver {
+    pub acceleration: f64,
+    pub max_speed: f64,
+    /// Gap kept to the car in front
+    pub gap: f64,
+}
+
+impl Driver {
+    pub const AVERAGE: Driver = Driver {
+        acceleration: 1.0,
+        max_speed: 1.0,
+        gap: 1.0,
+    };
+}
+
+/// A read-only copy of a car's state at one point in time
+#[derive(Clone, Copy, Debug)]
+pub struct CarState {
+    pub id: usize,
+    pub origin: Origin,
+    pub direction: Direction,
+    pub class: VehicleClass,
+    pub position: (f64, f64),
+    pub rotation: f64,
+    pub speed: f64,
+    pub stopped: bool,
+    pub path_index: usize,
+    pub through_intersection: bool,
+    pub colliding: bool,
 }
 
 #[derive(Clone)]
@@ -79,18 +188,61 @@ pub struct Car {
     pub id: usize,
     pub origin: Origin,
     direction: Direction,
+    class: VehicleClass,
     position: (f64, f64),
     rotation: f64,
     target_rotation: f64,
     speed: f64,
     stopped: bool,
     automatically_stopped: bool,
+    /// If the car is braking hard because it got too close to the car in front
+    emergency_braking: bool,
     path: Vec<(f64, f64)>,
     path_index: usize,
     path_index_on_red_change: Option<usize>,
+    /// Where the car waits for the light (the stop line, or inside the intersection once it crept)
     path_index_at_intersection: usize,
+    /// If the car pulled into the intersection to wait for a gap to turn in (`LEFT_TURN_CREEP`)
+    creeping: bool,
     pub finished: bool,
     through_intersection: bool,
+    /// If the car is counted in the traffic light's cars in the intersection
+    in_intersection: bool,
+    /// If the car is waiting in the left turn bay (out of the way of straight/right cars)
+    in_turn_bay: bool,
+    /// How many updates the car has spent stopped
+    wait_ticks: u32,
+    /// How many times the car came to a stop after moving
+    stops: u32,
+    /// If the car is waiting at a green for room past the intersection (`DONT_BLOCK_THE_BOX`)
+    held_for_box: bool,
+    /// How many times the car had to do that
+    box_holds: u32,
+    /// How many updates in a row the car went without getting to the next point of its path while
+    /// it had no reason to wait
+    stuck_ticks: u32,
+    /// If the car crossed the stop line when it should have stopped
+    ran_red: bool,
+    /// If the car is on top of another car (set by the simulation every update)
+    colliding: bool,
+    driver: Driver,
+    /// How many updates the driver has been free to pull away from a stop without going yet
+    /// (`STARTUP_DELAY`)
+    reaction_ticks: u32,
+    /// If the car came to rest at the stop line (first in line) and hasn't crossed it yet
+    stopped_at_line: bool,
+    /// How many updates the car has been on the map
+    age_ticks: u32,
+    /// Age at which the car reached each point of its path so far
+    point_ages: Vec<u32>,
+    /// Age (in updates) at which the front of the car first got past the stop line
+    crossed_stop_line: Option<u32>,
+    /// Age at which any part of the car first got into the intersection box
+    entered_box: Option<u32>,
+    /// Age at which the car was all the way out of the box again
+    cleared_box: Option<u32>,
+    /// Which of its approach's platoons the car arrived in (`DemandProfile::Platoons`)
+    platoon: Option<usize>,
 }
 
 impl Car {
@@ -100,68 +252,121 @@ impl Car {
             Origin::South => 270.0,
             Origin::East => 180.0,
             Origin::West => 0.0,
-        };
-        let path: Vec<(f64, f64)> = match direction {
-            Direction::Left => generate_left_turn_path(origin),
-            Direction::Right => generate_right_turn_path(origin),
-            Direction::Straight => generate_straight_path(origin),
-        };
+        } + APPROACH_SKEWS[origin.index()];
+        let (path, stop_index) = generate_path(origin, direction);
         Car {
             id,
             origin,
             direction,
-            position: get_position(origin),
+            class: VehicleClass::Car,
+            position: skew(get_position(origin)),
             rotation,
             target_rotation: rotation,
             speed: 0.0,
             stopped: false,
             automatically_stopped: false,
+            emergency_braking: false,
             path,
             path_index: 1,
             path_index_on_red_change: None,
-            path_index_at_intersection: NUM_PATH_POINTS / 3
-                + if direction == Direction::Straight {
-                    1
-                } else {
-                    0
-                },
+            path_index_at_intersection: stop_index,
+            creeping: false,
             finished: false,
             through_intersection: false,
+            in_intersection: false,
+            in_turn_bay: false,
+            wait_ticks: 0,
+            stops: 0,
+            held_for_box: false,
+            box_holds: 0,
+            stuck_ticks: 0,
+            ran_red: false,
+            colliding: false,
+            driver: Driver::AVERAGE,
+            reaction_ticks: 0,
+            stopped_at_line: false,
+            age_ticks: 0,
+            point_ages: Vec::new(),
+            crossed_stop_line: None,
+            entered_box: None,
+            cleared_box: None,
+            platoon: None,
         }
     }
 
+    pub fn state(&self) -> CarState {
+        CarState {
+            id: self.id,
+            origin: self.origin,
+            direction: self.direction,
+            class: self.class,
+            position: self.position,
+            rotation: self.rotation,
+            speed: self.speed,
+            stopped: self.stopped,
+            path_index: self.path_index,
+            through_intersection: self.through_intersection,
+            colliding: self.colliding,
+        }
+    }
+
+    pub fn with_class(mut self, class: VehicleClass) -> Car {
+        self.class = class;
+        self
+    }
+
+    pub fn with_driver(mut self, driver: Driver) -> Car {
+        self.driver = driver;
+        self
+    }
+
+    fn max_speed(&self) -> f64 {
+        MAX_SPEED * self.driver.max_speed
+    }
+
+    fn acceleration(&self) -> f64 {
+        self.class.acceleration() * self.driver.acceleration
+    }
+
+    /// Distance to keep (center to center, as if both were regular cars) to the car in front
+    fn following_distance(&self) -> f64 {
+        CAR_WIDTH + CAR_WIDTH * self.driver.gap
+    }
+
+    /// Returns how far the car goes before it comes to a stop when it brakes normally
+    fn braking_distance(&self) -> f64 {
+        self.speed.powi(2) / (2.0 * DECELERATION)
+    }
+
+    /// Starts the car off at the given speed instead of from a stop (call after `with_driver`, the
+    /// speed is capped to the driver's top speed)
+    pub fn with_speed(mut self, speed: f64) -> Car {
+        self.speed = speed.clamp(0.0, self.max_speed());
+        self
+    }
+
     fn get_distance_to_closest_car(&mut self, cars: &Vec<Car>) -> f64 {
         let mut closest_distance = f64::MAX;
 
         cars.clone()
             .iter()
             .filter(|c| c.origin == self.origin && c.id != self.id)
+            // Cars in the turn bay don't block the through lane
+            .filter(|c| !c.in_turn_bay || self.direction.crosses_oncoming())
             .for_each(|c| {
                 let (x, y) = self.position;
                 let (cx, cy) = c.position;
-                match self.origin {
-                    Origin::North => {
-                        if cy < y {
-                            return;
-                        }
-                    }
-                    Origin::South => {
-                        if cy > y {
-                            return;
-                        }
-                    }
-                    Origin::East => {
-                        if cx > x {
-                            return;
-                        }
-                    }
-                    Origin::West => {
-                        if cx < x {
-                            return;
-                        }
-                    }
+                // Only cars ahead of us (in the direction we're heading, so this still works
+                // once we've turned) and in our lane
+                let (sin, cos) = self.rotation.to_radians().sin_cos();
+                let ahead = (cx - x) * cos + (cy - y) * sin;
+                let beside = (cx - x) * sin - (cy - y) * cos;
+                if ahead < 0.0 || beside.abs() > LANE_WIDTH {
+                    return;
                 }
-                let distance = ((x - cx).powi(2) + (y - cy).powi(2)).sqrt();
+                // Longer vehicles keep the same gap as two regular cars would
+                let extra_length = (self.class.length() + c.class.length()) / 2.0 - CAR_WIDTH;
+                let distance = ((x - cx).powi(2) + (y - cy).powi(2)).sqrt() - extra_length;
                 closest_distance = closest_distance.min(distance);
             });
 
@@ -171,20 +376,57 @@ impl Car {
     fn automatically_stop(&mut self, cars: &Vec<Car>) {
         let closest_distance = self.get_distance_to_closest_car(cars);
         // Make sure cars that are on top of each other don't stop
-        if !self.stopped && closest_distance < CAR_WIDTH * 2.0 && closest_distance > 3.0 {
+        let following_distance = self.following_distance();
+        // Fast drivers that keep a short gap still need room to brake
+        let stopping_distance = following_distance
+            .max(CAR_WIDTH + self.speed + self.braking_distance() + DISTANCE_THRESHOLD);
+        if !self.stopped && closest_distance < stopping_distance && closest_distance > 3.0 {
             self.stopped = true;
             self.automatically_stopped = true;
-        } else if self.stopped && self.automatically_stopped && closest_distance > CAR_WIDTH * 2.0 {
+        } else if self.stopped
+            && self.automatically_stopped
+            && closest_distance > following_distance
+        {
             self.stopped = false;
             self.automatically_stopped = false;
         }
+        self.emergency_braking = self.automatically_stopped
+            && closest_distance - CAR_WIDTH < EMERGENCY_BRAKING_GAP
+            && closest_distance > 3.0;
     }
 
-    fn stop_for_traffic_light(&mut self, traffic_light: &mut TrafficLight) {
+    /// Returns if the given movements of the given approach leave a big enough gap to turn in
+    /// front of them. Cars that are stopped don't count, they'd need a while to get going.
+    fn gap_accepted(&self, cars: &Vec<Car>, origin: Origin, directions: &[Direction]) -> bool {
+        let gap_ticks = PERMISSIVE_LEFT_GAP / TICK.as_secs_f64();
+        cars.iter()
+            .filter(|c| c.origin == origin && directions.contains(&c.direction))
+            .all(|c| {
+                // Cars that are going through but haven't reached the stop line yet are judged by
+                // when they get there like the rest
+                if c.through_intersection && c.past_intersection() {
+                    !INTERSECTION_BOX.contains_car(c) || c.leaving_intersection()
+                } else {
+                    c.stopped || c.distance_to_stop_line() / c.speed.max(f64::EPSILON) >= gap_ticks
+                }
+            })
+    }
+
+    fn stop_for_traffic_light(&mut self, cars: &Vec<Car>, traffic_light: &mut dyn Controller) {
         if self.through_intersection {
             self.stopped = false;
             return;
         }
+        // Once in the intersection the car only waits for its gap, whatever the light does
+        if self.creeping {
+            self.stopped = self.path_index == self.path_index_at_intersection
+                && !self.gap_accepted(
+                    cars,
+                    self.origin.opposite(),
+                    &[Direction::Straight, Direction::Right],
+                );
+            return;
+        }
 
         // Red clearance time
         // Condition: not green && not yellow/can't go for other reasons
@@ -194,19 +436,14 @@ impl Car {
             }
 
             // Allow cars in intersection to clear
-            if let Some(past_green) = traffic_light.past_green() {
-                if past_green == self.origin
-                    && self.path_index_on_red_change.unwrap() >= self.path_index_at_intersection
-                {
-                    self.through_intersection = true;
-                    self.stopped = false;
-                } else if self.path_index == self.path_index_at_intersection {
-                    self.stopped = true;
-                }
-            } else {
-                if self.path_index == self.path_index_at_intersection {
-                    self.stopped = true;
-                }
+            if traffic_light.clearing(self.origin)
+                && self.path_index_on_red_change.unwrap() >= self.path_index_at_intersection
+            {
+                self.through_intersection = true;
+                self.stopped = false;
+                traffic_light.remove_car(self.origin, self.direction);
+            } else if self.path_index == self.path_index_at_intersection {
+                self.stopped = true;
             }
             return;
         }
@@ -217,20 +454,212 @@ impl Car {
         if !can_go && self.path_index != self.path_index_at_intersection {
             can_go = true;
         }
-        // Protected right turns
+        // Permissive left turns (and U-turns) go once there's a gap in the oncoming traffic
+        if !can_go
+            && self.direction.crosses_oncoming()
+            && traffic_light.permissive_left(self.origin)
+        {
+            if self.gap_accepted(
+                cars,
+                self.origin.opposite(),
+                &[Direction::Straight, Direction::Right],
+            ) {
+                can_go = true;
+            } else if LEFT_TURN_CREEP {
+                // Pull up into the intersection and wait for the gap there
+                self.creeping = true;
+                self.path_index_at_intersection = self.creep_index();
+                traffic_light.start_creeping();
+                can_go = true;
+            }
+        }
+        // U-turns end up in the same lane as the cars turning right on the same green, so they
+        // yield to them
+        if can_go
+            && self.direction == Direction::UTurn
+            && self.path_index == self.path_index_at_intersection
+            && !self.gap_accepted(cars, self.origin.right(), &[Direction::Right])
+        {
+            can_go = false;
+        }
+        // Protected right turns (once the through traffic from the approach across, which ends
+        // up in the same lane, is done clearing). Only with lights that give the greens to whole
+        // approaches, the others give the right turns their own greens.
         if self.direction == Direction::Right
+            && (traffic_light.green().is_some() || traffic_light.clearing(self.origin.left()))
             && traffic_light.is_green(self.origin.left(), self.direction)
+            && self.gap_accepted(cars, self.origin.right(), &[Direction::Straight])
         {
             can_go = true;
         }
+        // Right turns end up in the same lane as the oncoming left turns, so they yield to them
+        // when those have a protected green at the same time (permissive lefts yield to the rights
+        // instead)
+        let opposite = self.origin.opposite();
+        if can_go
+            && self.direction == Direction::Right
+            && self.path_index == self.path_index_at_intersection
+            && traffic_light.is_green(opposite, Direction::Left)
+            && !traffic_light.permissive_left(opposite)
+            && !self.gap_accepted(cars, opposite, &[Direction::Left])
+        {
+            can_go = false;
+        }
+
+        // Don't block the box: wait at the stop line until there's room past the intersection
+        let held_for_box = DONT_BLOCK_THE_BOX
+            && can_go
+            && self.path_index == self.path_index_at_intersection
+            && self.exit_blocked(cars);
+        if held_for_box {
+            can_go = false;
+            if !self.held_for_box {
+                self.box_holds += 1;
+            }
+        }
+        self.held_for_box = held_for_box;
 
         self.stopped = !can_go;
     }
 
-    pub fn update(&mut self, cars: &Vec<Car>, traffic_light: &mut TrafficLight) {
+    /// Returns the index of the last point of the path at most `CREEP_DISTANCE` past the stop line
+    fn creep_index(&self) -> usize {
+        let stop_distance =
+            distance_along_road(self.origin, self.path[self.path_index_at_intersection]);
+        (self.path_index_at_intersection..self.path.len())
+            .take_while(|&i| {
+                distance_along_road(self.origin, self.path[i]) - stop_distance <= CREEP_DISTANCE
+            })
+            .last()
+            .unwrap()
+    }
+
+    /// Returns if there isn't room for the car (and the gap it keeps) past the intersection on
+    /// its way out because of cars stopped there, so it would end up stuck in the intersection.
+    /// Cars that are still moving will be out of the way by then.
+    fn exit_blocked(&self, cars: &Vec<Car>) -> bool {
+        let outside = |&point: &(f64, f64)| !INTERSECTION_BOX.contains(point);
+        let exit_index = match (self.path_index_at_intersection + 1..self.path.len())
+            .find(|&i| outside(&self.path[i]) && !outside(&self.path[i - 1]))
+        {
+            Some(exit_index) => exit_index,
+            None => return false,
+        };
+        let exit = self.path[exit_index - 1];
+        let next = self.path[exit_index];
+        let length = ((next.0 - exit.0).powi(2) + (next.1 - exit.1).powi(2)).sqrt();
+        let heading = ((next.0 - exit.0) / length, (next.1 - exit.1) / length);
+        let room = self.class.length() + CAR_WIDTH * self.driver.gap;
+
+        cars.iter()
+            .filter(|c| c.id != self.id && c.stopped)
+            .any(|c| {
+                let (dx, dy) = (c.position.0 - exit.0, c.position.1 - exit.1);
+                let ahead = dx * heading.0 + dy * heading.1;
+                let beside = dx * heading.1 - dy * heading.0;
+                ahead > 0.0
+                    && ahead - c.class.length() / 2.0 < room
+                    && beside.abs() < LANE_WIDTH / 2.0
+            })
+    }
+
+    /// Only lets one car into the intersection at a time
+    fn wait_for_empty_intersection(&mut self, occupancy: &mut Occupancy) {
+        if !STRICT_SINGLE_CAR
+            || self.through_intersection
+            || self.path_index != self.path_index_at_intersection
+        {
+            return;
+        }
+
+        if self.stopped {
+            // Give the intersection back if the light stopped us before we got in
+            if self.in_intersection {
+                self.in_intersection = false;
+                occupancy.exit();
+            }
+        } else if !self.in_intersection {
+            if !occupancy.is_empty() {
+                self.stopped = true;
+            } else if self.distance_to_stop_line() <= self.braking_distance() + DISTANCE_THRESHOLD {
+                // Only claim the intersection once we couldn't stop before it anymore
+                self.in_intersection = true;
+                occupancy.enter();
+            }
+        }
+    }
+
+    /// Keeps the count of cars in the intersection up to date
+    fn update_intersection_occupancy(&mut self, occupancy: &mut Occupancy) {
+        if !self.in_intersection
+            && self.through_intersection
+            && !self.leaving_intersection()
+            && INTERSECTION_BOX.contains_car(self)
+        {
+            self.in_intersection = true;
+            occupancy.enter();
+        } else if self.in_intersection && self.leaving_intersection() {
+            self.in_intersection = false;
+            occupancy.exit();
+        }
+    }
+
+    /// Returns true while the car is at rest and free to go, but the driver hasn't reacted yet
+    fn reacting(&mut self) -> bool {
+        if self.stopped || self.speed > 0.0 {
+            self.reaction_ticks = 0;
+            return false;
+        }
+        self.reaction_ticks += 1;
+        TICK * self.reaction_ticks <= STARTUP_DELAY
+    }
+
+    /// Records when the car crosses the stop line and enters and clears the box, from where its
+    /// corners actually are (`through_intersection` only goes by the point of the path it's on)
+    fn update_box_times(&mut self) {
+        let stop_line = distance_along(self.origin, stop_line_position(self.origin));
+        if self.crossed_stop_line.is_none()
+            && self
+                .vertices()
+                .iter()
+                .any(|&vertex| distance_along_road(self.origin, vertex) > stop_line)
+        {
+            self.crossed_stop_line = Some(self.age_ticks);
+        }
+
+        let in_box = INTERSECTION_BOX.contains_car(self);
+        if self.entered_box.is_none() && in_box {
+            self.entered_box = Some(self.age_ticks);
+        } else if self.entered_box.is_some() && self.cleared_box.is_none() && !in_box {
+            self.cleared_box = Some(self.age_ticks);
+        }
+    }
+
+    /// Moves the car one tick along. Only its turning goes by `dt`, the rest is per tick.
+    pub fn update(
+        &mut self,
+        cars: &Vec<Car>,
+        traffic_light: &mut dyn Controller,
+        occupancy: &mut Occupancy,
+        dt: Duration,
+    ) {
+        self.age_ticks += 1;
+
         // If we have entered the intersection, remove ourselves from the traffic light
         if !self.through_intersection && self.past_intersection() {
+            // Crossed the stop line while trying to stop for the light
+            if self.stopped && !self.automatically_stopped {
+                self.ran_red = true;
+            }
             self.through_intersection = true;
+            if self.stopped_at_line {
+                self.stopped_at_line = false;
+                traffic_light.left_stop_line(self.origin, self.direction);
+            }
+            if self.creeping {
+                self.creeping = false;
+                traffic_light.stop_creeping();
+            }
             traffic_light.remove_car(self.origin, self.direction);
         }
         // If it's yellow and I'm right at the intersection, remove myself from the traffic light
@@ -238,25 +667,72 @@ impl Car {
         if traffic_light.is_yellow(self.origin)
             && self.path_index == self.path_index_at_intersection
             && !self.through_intersection
+            && !self.creeping
+        {
+            traffic_light.remove_car(self.origin, self.direction);
+            self.through_intersection = true;
+        }
+        // The same once the front of the car is past the stop line on a green (the middle of the
+        // car only gets to the stop line point later): it's going through whatever the light does
+        // next, so its clearance time has to count it
+        if traffic_light.is_green(self.origin, self.direction)
+            && self.path_index == self.path_index_at_intersection
+            && !self.through_intersection
+            && !self.creeping
+            && !self.stopped
+            && self.distance_to_stop_line() <= 0.0
         {
             traffic_light.remove_car(self.origin, self.direction);
             self.through_intersection = true;
         }
 
-        self.stop_for_traffic_light(traffic_light);
+        self.stop_for_traffic_light(cars, traffic_light);
+        self.wait_for_empty_intersection(occupancy);
         self.automatically_stop(cars);
+        let reacting = self.reacting();
+
+        if self.stopped || reacting {
+            self.wait_ticks += 1;
+        }
 
-        if !self.stopped {
-            self.speed += ACCELERATION;
-            if self.speed > MAX_SPEED {
-                self.speed = MAX_SPEED;
+        let was_moving = self.speed > 0.0;
+        if self.stopping_at_line() {
+            // Roll up to the stop line (or the car in front) and brake just in time to stop there
+            let room = self
+                .distance_to_stop_line()
+                .min(self.get_distance_to_closest_car(cars) - self.following_distance())
+                .max(0.0);
+            self.speed = (self.speed + self.acceleration())
+                .min(self.max_speed())
+                .min((2.0 * DECELERATION * room).sqrt())
+                .min(room);
+        } else if !self.stopped && !reacting {
+            self.speed += self.acceleration();
+            if self.speed > self.max_speed() {
+                self.speed = self.max_speed();
             }
         } else {
-            if self.speed > 0.0 {
-                self.speed -= DECELERATION;
+            let deceleration = if self.emergency_braking {
+                EMERGENCY_DECELERATION
             } else {
-                self.speed = 0.0;
-            }
+                DECELERATION
+            };
+            self.speed = (self.speed - deceleration).max(0.0);
+        }
+
+        debug_assert!(
+            (0.0..=self.max_speed()).contains(&self.speed),
+            "car {} has speed {} outside of [0, {}]",
+            self.id,
+            self.speed,
+            self.max_speed()
+        );
+
+        if was_moving && self.speed <= 0.0 {
+            self.stops += 1;
+        }
+        if self.stopping_at_line() && self.speed <= 0.0 {
+            self.stopped_at_line = true;
         }
 
         // Move towards next point in path
@@ -266,6 +742,8 @@ impl Car {
         self.position.1 += dy;
 
         if self.intersects_point(self.path[self.path_index]) {
+            self.stuck_ticks = 0;
+            self.point_ages.push(self.age_ticks);
             self.path_index += 1;
             if self.path_index >= self.path.len() {
                 self.path_index = 0;
@@ -277,24 +755,153 @@ impl Car {
                 let dy = self.path[self.path_index].1 - self.position.1;
                 self.target_rotation = dy.atan2(dx).to_degrees();
             }
+        } else if self.stopped && !self.through_intersection {
+            // Waiting for the light or the cars in front
+            self.stuck_ticks = 0;
+        } else {
+            self.stuck_ticks += 1;
         }
 
-        // Rotate towards target rotation
-        let mut diff = self.target_rotation - self.rotation;
-        if diff > 180.0 {
-            diff -= 360.0;
-        } else if diff < -180.0 {
-            diff += 360.0;
+        self.rotation = rotate_towards(self.rotation, self.target_rotation, dt);
+
+        self.update_intersection_occupancy(occupancy);
+        self.update_box_times();
+        if DESPAWN_PAST_INTERSECTION && self.out_of_intersection() {
+            self.finished = true;
         }
-        self.rotation += diff * 0.5;
 
         // self.draw(cars, context, graphics);
     }
 
+    pub fn wait_ticks(&self) -> u32 {
+        self.wait_ticks
+    }
+
+    pub fn stops(&self) -> u32 {
+        self.stops
+    }
+
+    /// Returns true from when the car gets into the intersection box until it's all the way out
+    pub fn crossing(&self) -> bool {
+        self.entered_box.is_some() && self.cleared_box.is_none()
+    }
+
+    pub fn age_ticks(&self) -> u32 {
+        self.age_ticks
+    }
+
+    /// Returns the ages (in updates) at which the car reached each point of its path, from the
+    /// second (the first is where it spawned) up to the last one it got to
+    pub fn point_ages(&self) -> &[u32] {
+        &self.point_ages
+    }
+
+    /// Returns the ages (in updates) at which the car crossed the stop line, entered the
+    /// intersection box and cleared it, for the ones that happened
+    pub fn box_times(&self) -> (Option<u32>, Option<u32>, Option<u32>) {
+        (self.crossed_stop_line, self.entered_box, self.cleared_box)
+    }
+
+    pub fn box_holds(&self) -> u32 {
+        self.box_holds
+    }
+
+    pub fn stuck_ticks(&self) -> u32 {
+        self.stuck_ticks
+    }
+
+    /// Gives back the car's spot in the light's queue and the intersection, for when it's taken
+    /// off the map early
+    pub fn release(&mut self, traffic_light: &mut dyn Controller, occupancy: &mut Occupancy) {
+        if self.creeping {
+            self.creeping = false;
+            traffic_light.stop_creeping();
+        }
+        if !self.through_intersection {
+            self.through_intersection = true;
+            traffic_light.remove_car(self.origin, self.direction);
+        }
+        if self.in_intersection {
+            self.in_intersection = false;
+            occupancy.exit();
+        }
+    }
+
+    pub fn ran_red(&self) -> bool {
+        self.ran_red
+    }
+
+    pub fn set_colliding(&mut self, colliding: bool) {
+        self.colliding = colliding;
+    }
+
+    pub fn colliding(&self) -> bool {
+        self.colliding
+    }
+
+    pub fn set_platoon(&mut self, platoon: Option<usize>) {
+        self.platoon = platoon;
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Returns true once the car has left the traffic light's queue
+    pub fn through_intersection(&self) -> bool {
+        self.through_intersection
+    }
+
+    /// Returns true if the point is inside of the car
+    pub fn contains(&self, point: (f64, f64)) -> bool {
+        let (dx, dy) = (point.0 - self.position.0, point.1 - self.position.1);
+        // Rotate the point into the car's frame
+        let (sin, cos) = (-self.rotation.to_radians()).sin_cos();
+        let (x, y) = (dx * cos - dy * sin, dx * sin + dy * cos);
+        x.abs() <= self.class.length() / 2.0 && y.abs() <= self.class.width() / 2.0
+    }
+
+    /// Returns true if the car is headed for the stop line and has to stop there
+    fn stopping_at_line(&self) -> bool {
+        self.stopped
+            && !self.automatically_stopped
+            && !self.through_intersection
+            && self.path_index == self.path_index_at_intersection
+    }
+
+    /// Returns how far the front of the car is from the stop line
+    fn distance_to_stop_line(&self) -> f64 {
+        distance_along_road(self.origin, self.path[self.path_index_at_intersection])
+            - distance_along_road(self.origin, self.position)
+            - self.class.length() / 2.0
+    }
+
     fn past_intersection(&self) -> bool {
         self.path_index > self.path_index_at_intersection
     }
 
+    /// Returns true once the car has gone through the intersection (so the light has been told)
+    /// and is all the way out of the box on its way out
+    fn out_of_intersection(&self) -> bool {
+        self.through_intersection
+            && self.past_intersection()
+            && !self.in_intersection
+            && self.cleared_box.is_some()
+            && self.leaving_intersection()
+    }
+
+    /// Returns true if the car is fully out of the intersection and driving away from it
+    fn leaving_intersection(&self) -> bool {
+        let middle = INTERSECTION_BOX.center();
+        let dx = self.position.0 - middle.0;
+        let dy = self.position.1 - middle.1;
+        let heading = (
+            self.rotation.to_radians().cos(),
+            self.rotation.to_radians().sin(),
+        );
+        !INTERSECTION_BOX.contains_car(self) && dx * heading.0 + dy * heading.1 > 0.0
+    }
+
     fn intersects_point(&self, point: (f64, f64)) -> bool {
         let dx = self.position.0 - point.0;
         let dy = self.position.1 - point.1;
@@ -325,6 +932,29 @@ impl Car {
         })
     }
 
+    /// Returns true if the two cars are on top of each other (cars in the turn bay are out of
+    /// the way of the through lane)
+    pub fn overlaps(&self, other: &Car) -> bool {
+        self.in_turn_bay == other.in_turn_bay && self.intersects_rect(other.vertices())
+    }
+
+    /// Returns true if the car is moving over its approach's dilemma zone detector
+    pub fn in_dilemma_zone(&self) -> bool {
+        !self.through_intersection
+            && !self.stopped
+            && self.speed > 0.0
+            && (DILEMMA_ZONE.0..=DILEMMA_ZONE.1).contains(&self.distance_to_stop_line())
+    }
+
+    /// Returns true if the two cars' collision boxes (padded by `COLLISION_PADDING`) touch
+    pub fn near(&self, other: &Car) -> bool {
+        self.in_turn_bay == other.in_turn_bay
+            && Car::intersects_rect_with_two_cars(
+                self.padded_vertices(COLLISION_PADDING),
+                other.padded_vertices(COLLISION_PADDING),
+            )
+    }
+
     fn intersects_rect_with_two_cars(
         vertices: [(f64, f64); 4],
         other_vertices: [(f64, f64); 4],
@@ -386,8 +1016,13 @@ impl Car {
     }
 
     pub fn vertices(&self) -> [(f64, f64); 4] {
-        let half_width = CAR_WIDTH / 2.0;
-        let half_height = CAR_HEIGHT / 2.0;
+        self.padded_vertices(0.0)
+    }
+
+    /// Returns the corners of the car's box grown by the padding on every side
+    fn padded_vertices(&self, padding: f64) -> [(f64, f64); 4] {
+        let half_width = self.class.length() / 2.0 + padding;
+        let half_height = self.class.width() / 2.0 + padding;
 
         let front_left = (-half_width, -half_height);
         let front_right = (half_width, -half_height);
@@ -419,28 +1054,93 @@ impl Car {
         ]
     }
 
-    pub fn draw(&self, cars: &Vec<Car>, context: &Context, graphics: &mut G2d) {
+    /// Draws the car with its class's sprite (or as a rectangle without one), red if it's on top
+    /// of another car and dimmed while it's stopped if `dim_stopped`
+    pub fn draw(
+        &self,
+        sprites: &Sprites,
+        dim_stopped: bool,
+        context: &Context,
+        graphics: &mut G2d,
+    ) {
         let transform = context
             .transform
             .trans(self.position.0, self.position.1)
             .rot_deg(self.rotation);
 
-        let fill_color = if cars
-            .iter()
-            .filter(|c| c.id != self.id)
-            .any(|c| self.intersects_rect(c.vertices()))
-        {
-            [1.0, 0.0, 0.0, 1.0]
-        } else {
-            [1.0; 4]
-        };
-        rectangle_from_to(
-            fill_color,
-            [-CAR_WIDTH / 2.0, -CAR_HEIGHT / 2.0],
-            [CAR_WIDTH / 2.0, CAR_HEIGHT / 2.0],
-            transform,
-            graphics,
-        );
+        let (half_length, half_width) = (self.class.length() / 2.0, self.class.width() / 2.0);
+        match sprites.get(self.class) {
+            Some(texture) => {
+                Image::new()
+                    .rect([
+                        -half_length,
+                        -half_width,
+                        self.class.length(),
+                        self.class.width(),
+                    ])
+                    .draw(texture, &context.draw_state, transform, graphics);
+                if self.colliding {
+                    rectangle_from_to(
+                        [1.0, 0.0, 0.0, 0.5],
+                        [-half_length, -half_width],
+                        [half_length, half_width],
+                        transform,
+                        graphics,
+                    );
+                }
+            }
+            None => {
+                let fill_color = if self.colliding {
+                    [1.0, 0.0, 0.0, 1.0]
+                } else {
+                    self.class.color()
+                };
+                rectangle_from_to(
+                    fill_color,
+                    [-half_length, -half_width],
+                    [half_length, half_width],
+                    transform,
+                    graphics,
+                );
+            }
+        }
+
+        // Tint every other platoon so it shows where one ends and the next starts
+        if self.platoon.map_or(false, |platoon| platoon % 2 == 1) {
+            rectangle_from_to(
+                [0.3, 0.4, 1.0, 0.4],
+                [-half_length, -half_width],
+                [half_length, half_width],
+                transform,
+                graphics,
+            );
+        }
+
+        // Dim stopped cars so a standing queue stands out from the moving traffic
+        if dim_stopped && self.stopped {
+            rectangle_from_to(
+                [0.0, 0.0, 0.0, 0.35],
+                [-half_length, -half_width],
+                [half_length, half_width],
+                transform,
+                graphics,
+            );
+        }
+
+        // Outline cars waiting inside the intersection for a gap
+        if self.creeping {
+            Rectangle::new_border([1.0, 0.6, 0.0, 1.0], 1.5).draw(
+                [
+                    -half_length,
+                    -half_width,
+                    self.class.length(),
+                    self.class.width(),
+                ],
+                &context.draw_state,
+                transform,
+                graphics,
+            );
+        }
 
         match self.direction {
             Direction::Straight => Line::new_round([0.0, 0.0, 0.0, 1.0], ARROW_STROKE_WEIGHT)
@@ -467,11 +1167,38 @@ impl Car {
                     transform,
                     graphics,
                 ),
+            Direction::UTurn => Line::new_round([0.0, 0.0, 0.0, 1.0], ARROW_STROKE_WEIGHT)
+                .draw_arrow(
+                    [CAR_WIDTH / 2.5, 0.0, -CAR_WIDTH / 2.5, 0.0],
+                    CAR_HEIGHT / 2.5,
+                    &DrawState::default(),
+                    transform,
+                    graphics,
+                ),
         }
 
         // self.draw_path(context, graphics);
     }
 
+    /// Draws a line to the point of the path the car is steering towards, with a marker on it
+    pub fn draw_target(&self, context: &Context, graphics: &mut G2d) {
+        let target = self.path[self.path_index];
+        line_from_to(
+            [0.0, 0.0, 1.0, 0.8],
+            1.5,
+            [self.position.0, self.position.1],
+            [target.0, target.1],
+            context.transform,
+            graphics,
+        );
+        ellipse(
+            [0.0, 0.0, 1.0, 1.0],
+            [target.0 - 4.0, target.1 - 4.0, 8.0, 8.0],
+            context.transform,
+            graphics,
+        );
+    }
+
     fn draw_path(&self, context: &Context, graphics: &mut G2d) {
         self.path.iter().for_each(|&point| {
             line_from_to(
@@ -486,24 +1213,240 @@ impl Car {
     }
 
     pub fn calculate_waiting_point_index(car: &traffic_light::SimplifiedCar) -> usize {
-        NUM_PATH_POINTS / 3
-            + if car.direction == Direction::Straight {
-                1
-            } else {
-                0
-            }
+        generate_path(car.origin, car.direction).1
     }
 
     pub fn calculate_path(car: &traffic_light::SimplifiedCar) -> Vec<(f64, f64)> {
-        match car.direction {
-            Direction::Left => generate_left_turn_path(car.origin),
-            Direction::Right => generate_right_turn_path(car.origin),
-            Direction::Straight => generate_straight_path(car.origin),
+        generate_path(car.origin, car.direction).0
+    }
+}
+
+/// Returns how fast a car can go through the intersection with the given movement without
+/// exceeding the lateral acceleration limit (v = sqrt(a * r))
+pub fn turning_speed(direction: Direction) -> f64 {
+    let turn_radius = match direction {
+        Direction::Straight => return MAX_SPEED,
+        Direction::Left => LANE_WIDTH * 3.0,
+        Direction::Right | Direction::UTurn => LANE_WIDTH,
+    };
+    (MAX_LATERAL_ACCELERATION * turn_radius)
+        .sqrt()
+        .min(MAX_SPEED)
+}
+
+/// Returns the heading (in degrees) after turning from `rotation` towards `target` for `dt`, the
+/// short way around. Closes half the remaining turn every `ROTATION_HALF_LIFE`, so it doesn't
+/// matter how `dt` is split up.
+fn rotate_towards(rotation: f64, target: f64, dt: Duration) -> f64 {
+    let mut diff = target - rotation;
+    if diff > 180.0 {
+        diff -= 360.0;
+    } else if diff < -180.0 {
+        diff += 360.0;
+    }
+    let smoothing = 1.0 - 0.5f64.powf(dt.as_secs_f64() / ROTATION_HALF_LIFE.as_secs_f64());
+    rotation + diff * smoothing
+}
+
+/// Returns true if there is room for a new vehicle of the given class at the start of the
+/// approach
+pub fn spawn_clear(cars: &[Car], origin: Origin, class: VehicleClass) -> bool {
+    let position = skew(get_position(origin));
+    cars.iter().filter(|c| c.origin == origin).all(|c| {
+        ((c.position.0 - position.0).powi(2) + (c.position.1 - position.1).powi(2)).sqrt()
+            >= (c.class.length() + class.length()) / 2.0 + MIN_SPAWN_GAP
+    })
+}
+
+/// Returns false if the movement is prohibited at this intersection
+pub fn movement_allowed(origin: Origin, direction: Direction) -> bool {
+    !BANNED_MOVEMENTS.contains(&(origin, direction))
+}
+
+/// Puts the left turning cars closest to the intersection into the left turn bay of their
+/// approach. Returns the approaches where left turning cars are spilling out of the bay into the
+/// through lane.
+pub fn fill_turn_bays(cars: &mut [Car]) -> Vec<Origin> {
+    let bay_length = LEFT_TURN_STORAGE as f64 * CAR_WIDTH * 2.0;
+    let mut spillover = Vec::new();
+
+    for origin in ORIGINS {
+        let mut waiting: Vec<(f64, &mut Car)> = cars
+            .iter_mut()
+            .filter(|c| {
+                c.origin == origin && c.direction.crosses_oncoming() && !c.through_intersection
+            })
+            .map(|c| {
+                let stop_point = c.path[c.path_index_at_intersection];
+                let distance = ((c.position.0 - stop_point.0).powi(2)
+                    + (c.position.1 - stop_point.1).powi(2))
+                .sqrt();
+                (distance, c)
+            })
+            .collect();
+        waiting.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        for (i, (distance, car)) in waiting.iter_mut().enumerate() {
+            car.in_turn_bay = i < LEFT_TURN_STORAGE && *distance <= bay_length;
+        }
+        if LEFT_TURN_STORAGE > 0 && waiting.len() > LEFT_TURN_STORAGE {
+            spillover.push(origin);
+        }
+    }
+
+    spillover
+}
+
+/// Returns the points of the movement's path from its stop line until it's out of the
+/// intersection again
+pub fn path_through_intersection(origin: Origin, direction: Direction) -> Vec<(f64, f64)> {
+    let (path, stop_line) = generate_path(origin, direction);
+    let mut points = vec![path[stop_line]];
+    let mut entered = false;
+    for &point in &path[stop_line + 1..] {
+        points.push(point);
+        if INTERSECTION_BOX.contains(point) {
+            entered = true;
+        } else if entered {
+            break;
         }
     }
+    points
+}
+
+/// Returns where the stop line of the approach crosses the middle of its lane
+pub fn stop_line_position(origin: Origin) -> (f64, f64) {
+    let middle = INTERSECTION_BOX.center();
+    let distance = INTERSECTION_BOX.half_size() + STOP_LINE_OFFSETS[origin.index()];
+    match origin {
+        Origin::North => (middle.0 - LANE_WIDTH, middle.1 - distance),
+        Origin::South => (middle.0 + LANE_WIDTH, middle.1 + distance),
+        Origin::East => (middle.0 + distance, middle.1 - LANE_WIDTH),
+        Origin::West => (middle.0 - distance, middle.1 + LANE_WIDTH),
+    }
+}
+
+/// Returns where the point's line along the approach's road meets the side of the intersection
+/// box. Skewing turns the point around there, so every lane still meets the box where it did.
+fn road_anchor(origin: Origin, point: (f64, f64)) -> (f64, f64) {
+    let (left, top, right, bottom) = INTERSECTION_BOX.extent();
+    match origin {
+        Origin::North => (point.0, top),
+        Origin::South => (point.0, bottom),
+        Origin::East => (right, point.1),
+        Origin::West => (left, point.1),
+    }
+}
+
+/// Moves a point of the map laid out with every road meeting the intersection straight on to
+/// where it is with the roads turned by `APPROACH_SKEWS`. Points in the intersection box stay
+/// where they are (the ones on its edges belong to the road).
+pub fn skew(point: (f64, f64)) -> (f64, f64) {
+    let (left, top, right, bottom) = INTERSECTION_BOX.extent();
+    let road = if point.1 <= top {
+        Origin::North
+    } else if point.1 >= bottom {
+        Origin::South
+    } else if point.0 >= right {
+        Origin::East
+    } else if point.0 <= left {
+        Origin::West
+    } else {
+        return point;
+    };
+    skew_on_road(road, point)
+}
+
+/// Moves a point the way `skew` moves the points of the approach's road: turned (clockwise on the
+/// screen) around where its lane meets the box
+pub fn skew_on_road(origin: Origin, point: (f64, f64)) -> (f64, f64) {
+    let degrees = APPROACH_SKEWS[origin.index()];
+    if degrees == 0.0 {
+        return point;
+    }
+    let anchor = road_anchor(origin, point);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (dx, dy) = (point.0 - anchor.0, point.1 - anchor.1);
+    (
+        anchor.0 + dx * cos - dy * sin,
+        anchor.1 + dx * sin + dy * cos,
+    )
+}
+
+/// Returns how far along the approach's road (turned by its skew) a point of the map is, like
+/// `distance_along` does for the map without skews
+fn distance_along_road(origin: Origin, point: (f64, f64)) -> f64 {
+    let degrees = APPROACH_SKEWS[origin.index()];
+    if degrees == 0.0 {
+        return distance_along(origin, point);
+    }
+    // Skewing keeps points as far from the box along the road as they were straight out from it
+    let edge = distance_along(origin, road_anchor(origin, point));
+    edge - (edge - distance_along(origin, point)) / degrees.to_radians().cos()
+}
+
+/// Returns how far along the approach (in the direction cars from it drive) a point is
+fn distance_along(origin: Origin, point: (f64, f64)) -> f64 {
+    match origin {
+        Origin::North => point.1,
+        Origin::South => -point.1,
+        Origin::East => -point.0,
+        Origin::West => point.0,
+    }
+}
+
+/// Generates the path of a movement with the stop line put in as one of the points, so that
+/// every movement from an approach stops at the same place. Returns the path and the index of
+/// the stop line point.
+fn generate_path(origin: Origin, direction: Direction) -> (Vec<(f64, f64)>, usize) {
+    let mut path = match direction {
+        Direction::Left => generate_left_turn_path(origin),
+        Direction::Right => generate_right_turn_path(origin),
+        Direction::Straight => generate_straight_path(origin),
+        Direction::UTurn => generate_u_turn_path(origin),
+    };
+
+    let stop_line = stop_line_position(origin);
+    let stop_distance = distance_along(origin, stop_line);
+    let mut index = path
+        .iter()
+        .position(|&point| distance_along(origin, point) >= stop_distance)
+        .unwrap();
+    // Don't leave a point right on top of the stop line
+    if distance_along(origin, path[index]) - stop_distance < DISTANCE_THRESHOLD {
+        path.remove(index);
+    }
+    // Cars find out they have to stop when the stop line becomes their next point, so drop the
+    // points that are too close to it to still be able to brake in time
+    let braking_room = VehicleClass::Bus.length() / 2.0 + MAX_SPEED.powi(2) / (2.0 * DECELERATION);
+    let first_removed = (1..index)
+        .find(|&i| distance_along(origin, path[i]) > stop_distance - braking_room)
+        .unwrap_or(index);
+    path.splice(first_removed..index, [stop_line]);
+    index = first_removed;
+
+    // Everything above is laid out as if the roads met the intersection straight on
+    for point in &mut path {
+        *point = skew(*point);
+    }
+
+    (path, index)
 }
 
-fn get_position(origin: Origin) -> (f64, f64) {
+/// Returns where cars of the approach spawn
+pub fn get_position(origin: Origin) -> (f64, f64) {
+    let middle = INTERSECTION_BOX.center();
+    let distance = INTERSECTION_BOX.half_size() + APPROACH_LENGTHS[origin.index()];
+    match origin {
+        Origin::North => (middle.0 - LANE_WIDTH, middle.1 - distance),
+        Origin::South => (middle.0 + LANE_WIDTH, middle.1 + distance),
+        Origin::East => (middle.0 + distance, middle.1 - LANE_WIDTH),
+        Origin::West => (middle.0 - distance, middle.1 + LANE_WIDTH),
+    }
+}
+
+/// Returns where cars of the approach would spawn if it started at the edge of the window
+fn window_spawn_position(origin: Origin) -> (f64, f64) {
     let middle = (WIDTH as f64 / 2.0, HEIGHT as f64 / 2.0);
     match origin {
         Origin::North => (middle.0 - LANE_WIDTH, CAR_WIDTH / 2.0),
@@ -515,24 +1458,21 @@ fn get_position(origin: Origin) -> (f64, f64) {
 
 /// Generates the initial straight that all cars have to do before they can turn
 fn generate_straight_path_third(origin: Origin) -> Vec<(f64, f64)> {
-    let vertical_point_gap = (HEIGHT as f64 / 2.0 - LANE_WIDTH * 2.0 - CAR_WIDTH / 2.0) as f64
-        / (NUM_PATH_POINTS / 3) as f64;
-    let horizontal_point_gap = (WIDTH as f64 / 2.0 - LANE_WIDTH * 2.0 - CAR_WIDTH / 2.0) as f64
-        / (NUM_PATH_POINTS / 3) as f64;
+    let point_gap = APPROACH_LENGTHS[origin.index()] / (NUM_PATH_POINTS / 3) as f64;
     let position = get_position(origin);
 
     match origin {
         Origin::North => (0..NUM_PATH_POINTS / 3)
-            .map(|i| (position.0, position.1 + i as f64 * vertical_point_gap))
+            .map(|i| (position.0, position.1 + i as f64 * point_gap))
             .collect(),
         Origin::South => (0..NUM_PATH_POINTS / 3)
-            .map(|i| (position.0, position.1 - (i as f64 * vertical_point_gap)))
+            .map(|i| (position.0, position.1 - (i as f64 * point_gap)))
             .collect(),
         Origin::East => (0..NUM_PATH_POINTS / 3)
-            .map(|i| (position.0 - (i as f64 * horizontal_point_gap), position.1))
+            .map(|i| (position.0 - (i as f64 * point_gap), position.1))
             .collect(),
         Origin::West => (0..NUM_PATH_POINTS / 3)
-            .map(|i| (position.0 + (i as f64 * horizontal_point_gap), position.1))
+            .map(|i| (position.0 + (i as f64 * point_gap), position.1))
             .collect(),
     }
 }
@@ -541,24 +1481,13 @@ fn generate_left_turn_path(origin: Origin) -> Vec<(f64, f64)> {
     // Initial straight
     let mut path = generate_straight_path_third(origin);
 
-    // Turn
+    // Turn (around the far corner of the intersection on the left)
+    let (left, top, right, bottom) = INTERSECTION_BOX.extent();
     let turn_origin = match origin {
-        Origin::North => (
-            WIDTH as f64 / 2.0 + LANE_WIDTH as f64 * 2.0,
-            HEIGHT as f64 / 2.0 - LANE_WIDTH as f64 * 2.0,
-        ),
-        Origin::South => (
-            WIDTH as f64 / 2.0 - LANE_WIDTH as f64 * 2.0,
-            HEIGHT as f64 / 2.0 + LANE_WIDTH as f64 * 2.0,
-        ),
-        Origin::East => (
-            WIDTH as f64 / 2.0 + LANE_WIDTH as f64 * 2.0,
-            HEIGHT as f64 / 2.0 + LANE_WIDTH as f64 * 2.0,
-        ),
-        Origin::West => (
-            WIDTH as f64 / 2.0 - LANE_WIDTH as f64 * 2.0,
-            HEIGHT as f64 / 2.0 - LANE_WIDTH as f64 * 2.0,
-        ),
+        Origin::North => (right, top),
+        Origin::South => (left, bottom),
+        Origin::East => (right, bottom),
+        Origin::West => (left, top),
     };
     let turn_path = match origin {
         Origin::North => (0..NUM_PATH_POINTS / 3)
@@ -608,7 +1537,7 @@ fn generate_left_turn_path(origin: Origin) -> Vec<(f64, f64)> {
     };
     path.extend(turn_path.iter().rev().collect::<Vec<_>>());
 
-    let straight_path = generate_straight_path(match origin {
+    let straight_path = generate_window_straight_path(match origin {
         Origin::North => Origin::West,
         Origin::South => Origin::East,
         Origin::East => Origin::North,
@@ -627,24 +1556,13 @@ fn generate_right_turn_path(origin: Origin) -> Vec<(f64, f64)> {
     // Initial straight
     let mut path = generate_straight_path_third(origin);
 
-    // Turn
+    // Turn (around the corner of the intersection on the right)
+    let (left, top, right, bottom) = INTERSECTION_BOX.extent();
     let turn_origin = match origin {
-        Origin::North => (
-            WIDTH as f64 / 2.0 - LANE_WIDTH * 2.0,
-            HEIGHT as f64 / 2.0 - LANE_WIDTH * 2.0,
-        ),
-        Origin::South => (
-            WIDTH as f64 / 2.0 + LANE_WIDTH * 2.0,
-            HEIGHT as f64 / 2.0 + LANE_WIDTH * 2.0,
-        ),
-        Origin::East => (
-            WIDTH as f64 / 2.0 + LANE_WIDTH * 2.0,
-            HEIGHT as f64 / 2.0 - LANE_WIDTH * 2.0,
-        ),
-        Origin::West => (
-            WIDTH as f64 / 2.0 - LANE_WIDTH * 2.0,
-            HEIGHT as f64 / 2.0 + LANE_WIDTH * 2.0,
-        ),
+        Origin::North => (left, top),
+        Origin::South => (right, bottom),
+        Origin::East => (right, top),
+        Origin::West => (left, bottom),
     };
     let turn_path = match origin {
         Origin::North => (0..NUM_PATH_POINTS / 3)
@@ -690,7 +1608,7 @@ fn generate_right_turn_path(origin: Origin) -> Vec<(f64, f64)> {
     };
     path.extend(turn_path);
 
-    let straight_path = generate_straight_path(match origin {
+    let straight_path = generate_window_straight_path(match origin {
         Origin::North => Origin::East,
         Origin::South => Origin::West,
         Origin::East => Origin::South,
@@ -705,11 +1623,95 @@ fn generate_right_turn_path(origin: Origin) -> Vec<(f64, f64)> {
     path
 }
 
+/// Generates a U-turn around the approach's half of the intersection, back out the same road
+fn generate_u_turn_path(origin: Origin) -> Vec<(f64, f64)> {
+    // Initial straight
+    let mut path = generate_straight_path_third(origin);
+
+    // Into the intersection and around a half circle in the approach's half of it
+    let (forward, left) = match origin {
+        Origin::North => ((0.0, 1.0), (1.0, 0.0)),
+        Origin::South => ((0.0, -1.0), (-1.0, 0.0)),
+        Origin::East => ((-1.0, 0.0), (0.0, 1.0)),
+        Origin::West => ((1.0, 0.0), (0.0, -1.0)),
+    };
+    let spawn = get_position(origin);
+    let approach_length = APPROACH_LENGTHS[origin.index()];
+    let entry = (
+        spawn.0 + forward.0 * approach_length,
+        spawn.1 + forward.1 * approach_length,
+    );
+    path.extend((0..3).map(|i| {
+        let distance = i as f64 / 3.0 * LANE_WIDTH;
+        (
+            entry.0 + forward.0 * distance,
+            entry.1 + forward.1 * distance,
+        )
+    }));
+    let center = (
+        entry.0 + (forward.0 + left.0) * LANE_WIDTH,
+        entry.1 + (forward.1 + left.1) * LANE_WIDTH,
+    );
+    let turn_points = NUM_PATH_POINTS / 3;
+    path.extend((0..=turn_points).map(|i| {
+        let angle = i as f64 / turn_points as f64 * std::f64::consts::PI;
+        (
+            center.0 - left.0 * angle.cos() * LANE_WIDTH + forward.0 * angle.sin() * LANE_WIDTH,
+            center.1 - left.1 * angle.cos() * LANE_WIDTH + forward.1 * angle.sin() * LANE_WIDTH,
+        )
+    }));
+
+    // Back out the way the car came, in the lanes of the opposite approach's straight
+    let exit = path[path.len() - 1];
+    let exit_origin = origin.opposite();
+    path.extend(
+        generate_window_straight_path(exit_origin)
+            .into_iter()
+            .filter(|&point| {
+                distance_along(exit_origin, point) > distance_along(exit_origin, exit)
+            }),
+    );
+    path
+}
+
+/// Generates the straight from where the approach starts, which can be before or past the edge
+/// of the window
 fn generate_straight_path(origin: Origin) -> Vec<(f64, f64)> {
+    let window_path = generate_window_straight_path(origin);
+    let spawn = get_position(origin);
+    let start = distance_along(origin, spawn);
+    let point_gap = distance_along(origin, window_path[1]) - distance_along(origin, window_path[0]);
+    let heading = (
+        (window_path[1].0 - window_path[0].0) / point_gap,
+        (window_path[1].1 - window_path[0].1) / point_gap,
+    );
+
+    // Points leading up to the edge of the window for longer approaches
+    let mut path = vec![spawn];
+    let mut distance = point_gap;
+    while start + distance < distance_along(origin, window_path[0]) {
+        path.push((
+            spawn.0 + heading.0 * distance,
+            spawn.1 + heading.1 * distance,
+        ));
+        distance += point_gap;
+    }
+    // Shorter approaches just skip the start of the window's straight
+    path.extend(
+        window_path
+            .into_iter()
+            .filter(|&point| distance_along(origin, point) > start),
+    );
+    path
+}
+
+/// Generates a straight across the whole window, starting at the edge of the window (the turns
+/// use the end of it to leave the intersection)
+fn generate_window_straight_path(origin: Origin) -> Vec<(f64, f64)> {
     let vertical_point_gap = (HEIGHT as f64 + CAR_WIDTH / 2.0) as f64 / NUM_PATH_POINTS as f64;
     let horizontal_point_gap = (WIDTH as f64 + CAR_WIDTH / 2.0) as f64 / NUM_PATH_POINTS as f64;
 
-    let position = get_position(origin);
+    let position = window_spawn_position(origin);
     match origin {
         Origin::North => {
             let mut path = Vec::new();
@@ -742,8 +1744,12 @@ fn generate_straight_path(origin: Origin) -> Vec<(f64, f64)> {
     }
 }
 
+/// Rounding errors can put points that are in a line (like the sides of cars one behind the other
+/// on a skewed road) a tiny bit to either side of it, so those count as in the line
+const COLLINEAR_EPSILON: f64 = 1e-6;
+
 fn ccw(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> bool {
-    (c.1 - a.1) * (b.0 - a.0) > (b.1 - a.1) * (c.0 - a.0)
+    (c.1 - a.1) * (b.0 - a.0) - (b.1 - a.1) * (c.0 - a.0) > COLLINEAR_EPSILON
 }
 
 fn line_intersect(line: ((f64, f64), (f64, f64)), other_line: &((f64, f64), (f64, f64))) -> bool {
@@ -753,3 +1759,147 @@ fn line_intersect(line: ((f64, f64), (f64, f64)), other_line: &((f64, f64), (f64
     let d = other_line.1;
     ccw(a, c, d) != ccw(b, c, d) && ccw(a, b, c) != ccw(a, b, d)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        scripted_controller::ScriptedController,
+        simulation::Simulation,
+        traffic_light::{SimplifiedCar, TrafficLight},
+        SEED,
+    };
+
+    /// A light that stays red for every movement
+    fn all_red() -> Box<dyn Controller> {
+        Box::new(ScriptedController::parse("60").unwrap())
+    }
+
+    #[test]
+    fn cars_stop_at_the_stop_line() {
+        for origin in ORIGINS {
+            let mut positions: Vec<(f64, f64)> = Vec::new();
+            for direction in (0..=3).map(Direction::from) {
+                let mut simulation = Simulation::with_seed(all_red(), SEED);
+                simulation.set_spawning(false);
+                let id = simulation.spawn_car(origin, direction, VehicleClass::Car, 0.0);
+                for _ in 0..600 {
+                    simulation.step(TICK);
+                }
+
+                let car = simulation.car(id).unwrap();
+                assert_eq!(car.speed, 0.0, "{:?} {:?}", origin, direction);
+                assert!(
+                    car.distance_to_stop_line().abs() < 1e-3,
+                    "{:?} {:?} stopped {} from the line",
+                    origin,
+                    direction,
+                    car.distance_to_stop_line()
+                );
+                positions.push(car.position);
+            }
+            for pair in positions.windows(2) {
+                let (first, second) = (pair[0], pair[1]);
+                assert!(
+                    (first.0 - second.0).abs() < 1e-3 && (first.1 - second.1).abs() < 1e-3,
+                    "{:?} cars stopped at {:?}",
+                    origin,
+                    positions
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn protected_rights_wait_for_the_through_traffic_from_across() {
+        for origin in ORIGINS {
+            // The green goes to the approach on the right turner's left
+            let mut traffic_light = TrafficLight::new();
+            traffic_light.add_car(SimplifiedCar::new(origin.left(), Direction::Straight));
+            traffic_light.start_first_green();
+            assert_eq!(traffic_light.green(), Some(origin.left()));
+
+            let mut turner = Car::new(0, origin, Direction::Right);
+            turner.path_index = turner.path_index_at_intersection;
+            turner.stop_for_traffic_light(&Vec::new(), &mut traffic_light);
+            assert!(!turner.stopped, "{:?}", origin);
+
+            // Still clearing the intersection from the other side, into the turner's lane
+            let mut through =
+                Car::new(1, origin.right(), Direction::Straight).with_speed(MAX_SPEED);
+            through.path_index = through.path_index_at_intersection;
+            through.position = through.path[through.path_index - 1];
+            turner.stop_for_traffic_light(&vec![through], &mut traffic_light);
+            assert!(turner.stopped, "{:?}", origin);
+        }
+    }
+
+    #[test]
+    fn rotation_doesnt_depend_on_the_tick_size() {
+        for (rotation, target) in [(0.0, 90.0), (90.0, -45.0), (170.0, -170.0), (-170.0, 170.0)] {
+            let mut small_ticks = rotation;
+            for _ in 0..6 {
+                small_ticks = rotate_towards(small_ticks, target, TICK);
+            }
+            let mut big_ticks = rotation;
+            for _ in 0..2 {
+                big_ticks = rotate_towards(big_ticks, target, TICK * 3);
+            }
+            let one_tick = rotate_towards(rotation, target, TICK * 6);
+            assert!(
+                (small_ticks - big_ticks).abs() < 1e-9,
+                "{} to {}",
+                rotation,
+                target
+            );
+            assert!(
+                (small_ticks - one_tick).abs() < 1e-9,
+                "{} to {}",
+                rotation,
+                target
+            );
+        }
+
+        // Across 180 degrees it turns the short way, past 180 instead of back through 0
+        assert!(rotate_towards(170.0, -170.0, TICK) > 170.0);
+        assert!(rotate_towards(-170.0, 170.0, TICK) < -170.0);
+    }
+
+    #[test]
+    fn cars_turn_to_their_heading_in_the_same_time_at_any_tick_size() {
+        for origin in ORIGINS {
+            let mut simulation = Simulation::with_seed(all_red(), SEED);
+            simulation.set_spawning(false);
+            let id = simulation.spawn_car(origin, Direction::Straight, VehicleClass::Car, 0.0);
+            for _ in 0..600 {
+                simulation.step(TICK);
+            }
+            // Stopped at the red, facing a quarter turn away from the road (so only its heading
+            // changes while it turns back)
+            let mut turned = simulation.car(id).unwrap().clone();
+            turned.rotation -= 90.0;
+
+            let headings = [(TICK, 6), (TICK * 3, 2), (TICK * 6, 1)].map(|(dt, steps)| {
+                let mut car = turned.clone();
+                let mut traffic_light = all_red();
+                let mut occupancy = Occupancy::default();
+                for _ in 0..steps {
+                    car.update(&Vec::new(), traffic_light.as_mut(), &mut occupancy, dt);
+                }
+                assert_eq!(car.speed, 0.0, "{:?}", origin);
+                car.rotation
+            });
+            for heading in &headings[1..] {
+                assert!(
+                    (heading - headings[0]).abs() < 1e-9,
+                    "{:?} turned to {:?}",
+                    origin,
+                    headings
+                );
+            }
+            // Most of the way there (the target can be a whole turn off the heading)
+            let left = (headings[0] - turned.target_rotation).rem_euclid(360.0);
+            assert!(left.min(360.0 - left) < 90.0 / 32.0, "{:?}", origin);
+        }
+    }
+}
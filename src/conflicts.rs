@@ -0,0 +1,137 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use crate::{
+    car::{Car, Direction, Origin},
+    simulation::TICK,
+    traffic_light::{SimplifiedCar, TrafficLight},
+    CONFLICT_THRESHOLDS, CONFLICT_WINDOW,
+};
+
+/// How long a finished car is kept to compare with the cars finishing after it. Cars only take a
+/// few seconds from the intersection to the edge of the window, so this leaves plenty of room.
+const MEMORY: Duration = Duration::from_secs(30);
+
+type Movement = (Origin, Direction);
+
+/// Pairs of cars from different approaches that got to the point where their paths cross within
+/// `CONFLICT_WINDOW` of each other, by how far apart they got there (post-encroachment time)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConflictCounts {
+    /// At least `CONFLICT_THRESHOLDS.1` apart
+    pub safe: usize,
+    /// At least `CONFLICT_THRESHOLDS.0` but less than `CONFLICT_THRESHOLDS.1` apart
+    pub marginal: usize,
+    /// Less than `CONFLICT_THRESHOLDS.0` apart, all but on top of each other (whether or not
+    /// they actually collided)
+    pub collision: usize,
+}
+
+impl ConflictCounts {
+    /// Returns the conflicts since `start`
+    pub fn since(&self, start: &ConflictCounts) -> ConflictCounts {
+        ConflictCounts {
+            safe: self.safe - start.safe,
+            marginal: self.marginal - start.marginal,
+            collision: self.collision - start.collision,
+        }
+    }
+
+    fn record(&mut self, separation: Duration) {
+        if separation < CONFLICT_THRESHOLDS.0 {
+            self.collision += 1;
+        } else if separation < CONFLICT_THRESHOLDS.1 {
+            self.marginal += 1;
+        } else {
+            self.safe += 1;
+        }
+    }
+}
+
+impl fmt::Display for ConflictCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} safe / {} marginal / {} collision",
+            self.safe, self.marginal, self.collision
+        )
+    }
+}
+
+/// A finished car's movement, and when it reached each point of its path
+#[derive(Clone)]
+struct Passage {
+    movement: Movement,
+    finished: Duration,
+    /// Starting from the second point of the path, like `Car::point_ages`
+    point_times: Vec<Duration>,
+}
+
+impl Passage {
+    fn reached(&self, index: usize) -> Option<Duration> {
+        self.point_times.get(index.checked_sub(1)?).copied()
+    }
+}
+
+/// Compares every finished car with the recently finished cars whose paths cross its own
+#[derive(Clone, Default)]
+pub struct ConflictLog {
+    /// Where the paths of two movements first cross (see `TrafficLight::conflict_point`), worked
+    /// out the first time it's needed
+    points: HashMap<(Movement, Movement), Option<(usize, usize)>>,
+    recent: VecDeque<Passage>,
+    counts: ConflictCounts,
+}
+
+impl ConflictLog {
+    /// Compares a car that just finished (at `now`) with the cars that finished before it
+    pub fn record(&mut self, car: &Car, now: Duration) {
+        while self
+            .recent
+            .front()
+            .map_or(false, |passage| passage.finished + MEMORY < now)
+        {
+            self.recent.pop_front();
+        }
+
+        let passage = Passage {
+            movement: (car.origin, car.direction()),
+            finished: now,
+            point_times: car
+                .point_ages()
+                .iter()
+                .map(|&age| now - TICK * (car.age_ticks() - age))
+                .collect(),
+        };
+        for other in &self.recent {
+            if other.movement.0 == passage.movement.0 {
+                continue;
+            }
+            let point = *self
+                .points
+                .entry((other.movement, passage.movement))
+                .or_insert_with(|| {
+                    let first = SimplifiedCar::new(other.movement.0, other.movement.1);
+                    let second = SimplifiedCar::new(passage.movement.0, passage.movement.1);
+                    TrafficLight::movements_conflict(&first, &second)
+                        .then(|| TrafficLight::conflict_point(&first, &second))
+                        .flatten()
+                });
+            let times = point.and_then(|(i, j)| Some((other.reached(i)?, passage.reached(j)?)));
+            if let Some((first, second)) = times {
+                let separation = first.abs_diff(second);
+                if separation < CONFLICT_WINDOW {
+                    self.counts.record(separation);
+                }
+            }
+        }
+        self.recent.push_back(passage);
+    }
+
+    pub fn counts(&self) -> ConflictCounts {
+        self.counts
+    }
+}
@@ -0,0 +1,274 @@
+use piston_window::*;
+use std::{f64::consts::PI, time::Duration};
+
+use crate::{
+    car::{self, Direction, Origin, LANE_WIDTH},
+    phase_group::PhaseGroups,
+    phase_log::PhaseEvent,
+    scripted_controller::ScriptedController,
+    traffic_light::{SimplifiedCar, TrafficLight},
+    HEIGHT, LIGHT_SCRIPT, PHASE_GROUPS, WIDTH,
+};
+
+/// Decides which movements get the green. The simulation and the cars only go through this, so
+/// another strategy can be swapped in with `Simulation::with_controller`.
+///
+/// The optional features (permissive lefts, creeping, the detectors and the counters) default to
+/// doing nothing.
+pub trait Controller: DrawController {
+    /// Advances the controller to the given simulation time
+    fn update(&mut self, now: Duration);
+
+    /// Returns if the given movement may enter the intersection
+    fn is_green(&self, origin: Origin, direction: Direction) -> bool;
+
+    /// Returns if the approach's light is yellow and cars should still clear the intersection
+    fn is_yellow(&self, origin: Origin) -> bool;
+
+    /// Returns if the approach's light is showing yellow, whether or not its cars may still go
+    /// on it (see `is_yellow`)
+    fn in_yellow(&self, origin: Origin) -> bool;
+
+    /// Returns the approach with the green, if the greens are given to whole approaches
+    fn green(&self) -> Option<Origin>;
+
+    /// A car joined the queue for the light
+    fn add_car(&mut self, car: SimplifiedCar);
+
+    /// A car making the given movement left the queue for the light
+    fn remove_car(&mut self, origin: Origin, direction: Direction);
+
+    /// Returns how many cars from the given approach are waiting for the light
+    fn queue_by_origin(&self, origin: Origin) -> usize;
+
+    /// Returns how many cars making the given movement are waiting for the light
+    fn queue_by_movement(&self, origin: Origin, direction: Direction) -> usize;
+
+    fn box_clone(&self) -> Box<dyn Controller>;
+
+    /// Returns if the approach's green just ended and no approach has the green yet, so its cars
+    /// that had already reached the stop line can still go through
+    fn clearing(&self, _origin: Origin) -> bool {
+        false
+    }
+
+    /// Called once the cars of an update have all been added, so the first green can go to one
+    /// of them
+    fn start_first_green(&mut self) {}
+
+    /// Returns if the approach's left turners can go after yielding to the oncoming traffic
+    fn permissive_left(&self, _origin: Origin) -> bool {
+        false
+    }
+
+    /// A car that was at rest at its stop line crossed it
+    fn left_stop_line(&mut self, _origin: Origin, _direction: Direction) {}
+
+    /// A left turner pulled into the intersection to wait for its gap
+    fn start_creeping(&mut self) {}
+
+    /// A left turner that was waiting in the intersection got going
+    fn stop_creeping(&mut self) {}
+
+    /// Sets which approaches' dilemma zone detectors have a car over them
+    fn detect_dilemma_zones(&mut self, _occupied: [bool; 4]) {}
+
+    /// Sets the cars of each approach that are waiting to spawn
+    fn detect_backed_up(&mut self, _backed_up: [Vec<SimplifiedCar>; 4]) {}
+
+    fn yellow_traps(&self) -> usize {
+        0
+    }
+
+    fn dilemma_zone_extensions(&self) -> usize {
+        0
+    }
+
+    fn conflicting_greens(&self) -> usize {
+        0
+    }
+
+    /// Returns how many times a car was removed without a car of its movement waiting (which
+    /// means a car was removed twice)
+    fn queue_underflows(&self) -> usize {
+        0
+    }
+
+    /// Returns every green given so far, oldest first
+    fn phase_log(&self) -> &[PhaseEvent] {
+        &[]
+    }
+}
+
+impl Clone for Box<dyn Controller> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// How a controller shows itself in the window, kept apart from what the simulation and the cars
+/// need from it
+pub trait DrawController {
+    /// Draws the signal heads (and whatever else the controller shows on the map)
+    fn draw(&self, context: &Context, graphics: &mut G2d);
+
+    /// Draws the controller's own stats, below the simulation's
+    fn draw_stats(&self, _glyphs: &mut Glyphs, _context: &Context, _graphics: &mut G2d) {}
+}
+
+/// The controller the config asks for: `LIGHT_SCRIPT` if there is one, then `PHASE_GROUPS` if
+/// there are any, and the light responding to the traffic otherwise. `configure` sets up that
+/// light first (the phase groups take their timing, weights and conflicts from it).
+pub fn from_config(configure: impl FnOnce(&mut TrafficLight)) -> Box<dyn Controller> {
+    if let Some(path) = LIGHT_SCRIPT {
+        let script = ScriptedController::load(path)
+            .unwrap_or_else(|err| panic!("Couldn't load the light script: {}", err));
+        return Box::new(script);
+    }
+    let mut traffic_light = TrafficLight::new();
+    configure(&mut traffic_light);
+    if !PHASE_GROUPS.is_empty() {
+        return Box::new(PhaseGroups::new(PHASE_GROUPS, &traffic_light));
+    }
+    Box::new(traffic_light)
+}
+
+/// The cars waiting for the light on each approach (indexed by `Origin::index`), in the order
+/// they got there
+#[derive(Clone, Debug, Default)]
+pub struct Queues([Vec<SimplifiedCar>; 4]);
+
+impl Queues {
+    pub fn add(&mut self, car: SimplifiedCar) {
+        self.0[car.origin.index()].push(car);
+    }
+
+    /// Takes the first waiting car of the movement off its approach's queue (cars in the turn bay
+    /// can go out of order). If no car of the movement is waiting a car was removed twice, which
+    /// fails debug builds; otherwise the queue is left alone and this warns and returns false.
+    pub fn remove(&mut self, origin: Origin, direction: Direction) -> bool {
+        let queue = &mut self.0[origin.index()];
+        let index = queue.iter().position(|car| car.direction == direction);
+        debug_assert!(
+            index.is_some(),
+            "{:?} {:?} car removed without one waiting",
+            origin,
+            direction
+        );
+        match index {
+            Some(index) => {
+                queue.remove(index);
+                true
+            }
+            None => {
+                eprintln!(
+                    "Warning: {:?} {:?} car removed without one waiting",
+                    origin, direction
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns the approach's waiting cars, first one first
+    pub fn get(&self, origin: Origin) -> &[SimplifiedCar] {
+        &self.0[origin.index()]
+    }
+
+    pub fn by_origin(&self, origin: Origin) -> usize {
+        self.get(origin).len()
+    }
+
+    pub fn by_movement(&self, origin: Origin, direction: Direction) -> usize {
+        self.get(origin)
+            .iter()
+            .filter(|car| car.direction == direction)
+            .count()
+    }
+}
+
+pub const LIGHT_RADIUS: f64 = 15.0;
+pub const LIGHT_SPACING: f64 = 10.0;
+pub const GREEN: [f32; 4] = [0.24, 0.96, 0.21, 1.0];
+pub const YELLOW: [f32; 4] = [0.92, 0.95, 0.13, 1.0];
+pub const RED: [f32; 4] = [0.96, 0.19, 0.19, 1.0];
+pub const DARK_GREEN: [f32; 4] = [0.05, 0.22, 0.04, 1.0];
+pub const DARK_YELLOW: [f32; 4] = [0.3, 0.32, 0.04, 1.0];
+pub const DARK_RED: [f32; 4] = [0.34, 0.06, 0.06, 1.0];
+
+/// Returns the colors of the approach's red, yellow and green lights as the controller reports
+/// them: yellow while it's showing yellow, green while any of its movements has the green and red
+/// otherwise
+pub fn signal_colors(controller: &dyn Controller, origin: Origin) -> [[f32; 4]; 3] {
+    let any_green = (0..=3)
+        .map(Direction::from)
+        .any(|direction| controller.is_green(origin, direction));
+    if controller.in_yellow(origin) {
+        [DARK_RED, YELLOW, DARK_GREEN]
+    } else if any_green {
+        [DARK_RED, DARK_YELLOW, GREEN]
+    } else {
+        [RED, DARK_YELLOW, DARK_GREEN]
+    }
+}
+
+/// Draws the approach's signal head with the given colors of its red, yellow and green lights,
+/// and returns the transform it was drawn with (the head's top left corner, turned to face the
+/// approach) to draw arrows on it with
+pub fn draw_signal_head(
+    origin: Origin,
+    colors: [[f32; 4]; 3],
+    context: &Context,
+    graphics: &mut G2d,
+) -> math::Matrix2d {
+    let transform = context
+        .transform
+        .trans(WIDTH as f64 / 2.0, HEIGHT as f64 / 2.0)
+        .rot_rad(match origin {
+            Origin::North => PI,
+            Origin::East => 3.0 * PI / 2.0,
+            Origin::South => 0.0,
+            Origin::West => PI / 2.0,
+        })
+        .trans(LANE_WIDTH.trunc() * 2.0, LANE_WIDTH.trunc() * 2.0);
+    let size = [
+        0.0,
+        0.0,
+        LIGHT_RADIUS * 5.0,
+        (LIGHT_RADIUS * 2.0 + LIGHT_SPACING) * 3.0 + LIGHT_SPACING * 2.0,
+    ];
+    Rectangle::new_round([0.0, 0.0, 0.0, 1.0], LIGHT_RADIUS * 2.5).draw(
+        size,
+        &context.draw_state,
+        transform,
+        graphics,
+    );
+    Rectangle::new_round_border([1.0; 4], LIGHT_RADIUS * 2.5, 1.5).draw(
+        size,
+        &context.draw_state,
+        transform,
+        graphics,
+    );
+    for (i, color) in colors.into_iter().enumerate() {
+        ellipse(
+            color,
+            [
+                LIGHT_RADIUS + LIGHT_RADIUS * 0.5,
+                LIGHT_RADIUS + (i as f64 * (LIGHT_RADIUS * 2.0 + LIGHT_SPACING)),
+                LIGHT_RADIUS * 2.0,
+                LIGHT_RADIUS * 2.0,
+            ],
+            transform,
+            graphics,
+        );
+    }
+    transform
+}
+
+/// Draws every approach's signal head from what the controller reports, for controllers without
+/// anything else to show
+pub fn draw_signals(controller: &dyn Controller, context: &Context, graphics: &mut G2d) {
+    for origin in car::ORIGINS {
+        draw_signal_head(origin, signal_colors(controller, origin), context, graphics);
+    }
+}
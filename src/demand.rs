@@ -0,0 +1,167 @@
+use rand::{rngs::StdRng, Rng};
+use std::{collections::VecDeque, fs, time::Duration};
+
+use crate::car::{Origin, ORIGINS};
+
+/// How cars arrive at the intersection
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DemandProfile {
+    /// One car at a time from a random approach, coming faster and faster (turning into a fixed
+    /// rotation through the approaches once it's fast enough)
+    Ramp,
+    /// Each approach's cars arrive as a Poisson process at its `ARRIVAL_RATES`
+    Poisson,
+    /// Cars arrive in groups of `size`, `headway` apart, like they would from an upstream signal.
+    /// The groups start as a Poisson process, so each approach still averages about its
+    /// `ARRIVAL_RATES` (a bit less when the groups are long compared to the gaps between them).
+    Platoons { size: usize, headway: Duration },
+    /// Cars arrive as counted by real detectors, from a CSV file of counts per time bin (see
+    /// `DetectorData`). Nobody arrives after the last bin.
+    DetectorData(&'static str),
+}
+
+/// Detector counts of each approach per time bin, to replay real arrivals.
+///
+/// The files are CSV with a header naming the columns: the time the bin starts (in seconds) first,
+/// then a column per approach, e.g. `start,North,South,East,West`. Every row is a bin that lasts
+/// until the next row's start (the last one as long as the one before it). Approaches without a
+/// column and empty cells count as no arrivals. Empty lines and lines starting with `#` are
+/// ignored.
+#[derive(Clone, Debug)]
+pub struct DetectorData {
+    /// Start, length and counts (indexed by `Origin::index`) of every bin
+    bins: Vec<(Duration, Duration, [usize; 4])>,
+}
+
+impl DetectorData {
+    pub fn load(path: &str) -> Result<DetectorData, String> {
+        let contents = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        DetectorData::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<DetectorData, String> {
+        let mut lines = contents
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        let (_, header) = lines.next().ok_or("the file has no header")?;
+        let columns = header
+            .split(',')
+            .skip(1)
+            .map(|name| {
+                ORIGINS
+                    .into_iter()
+                    .find(|origin| format!("{:?}", origin) == name.trim())
+                    .ok_or_else(|| format!("header: unknown approach {}", name.trim()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut rows: Vec<(Duration, [usize; 4])> = Vec::new();
+        for (line_number, line) in lines {
+            let mut cells = line.split(',').map(str::trim);
+            let seconds: f64 = cells
+                .next()
+                .unwrap()
+                .parse()
+                .map_err(|_| format!("line {}: invalid start time", line_number))?;
+            if seconds < 0.0 || !seconds.is_finite() {
+                return Err(format!(
+                    "line {}: start time can't be negative",
+                    line_number
+                ));
+            }
+            let start = Duration::from_secs_f64(seconds);
+            if rows
+                .last()
+                .map_or(false, |&(last_start, _)| start <= last_start)
+            {
+                return Err(format!("line {}: bins have to be in order", line_number));
+            }
+
+            let mut counts = [0; 4];
+            for (i, cell) in cells.enumerate() {
+                let origin = columns
+                    .get(i)
+                    .ok_or_else(|| format!("line {}: more cells than columns", line_number))?;
+                if !cell.is_empty() {
+                    counts[origin.index()] = cell
+                        .parse()
+                        .map_err(|_| format!("line {}: invalid count {}", line_number, cell))?;
+                }
+            }
+            rows.push((start, counts));
+        }
+        if rows.len() < 2 {
+            return Err(String::from(
+                "the file needs at least two bins to know how long they are",
+            ));
+        }
+
+        let mut bins: Vec<(Duration, Duration, [usize; 4])> = rows
+            .windows(2)
+            .map(|pair| (pair[0].0, pair[1].0 - pair[0].0, pair[0].1))
+            .collect();
+        let (start, counts) = *rows.last().unwrap();
+        bins.push((start, bins.last().unwrap().1, counts));
+        Ok(DetectorData { bins })
+    }
+
+    /// Returns when each of the approach's cars arrives, in order, with every bin's cars spread
+    /// at random over the bin
+    pub fn arrivals(&self, origin: Origin, rng: &mut StdRng) -> VecDeque<Duration> {
+        let mut arrivals = Vec::new();
+        for &(start, length, counts) in &self.bins {
+            let mut bin: Vec<Duration> = (0..counts[origin.index()])
+                .map(|_| start + length.mul_f64(rng.gen_range(0.0..1.0)))
+                .collect();
+            bin.sort();
+            arrivals.extend(bin);
+        }
+        arrivals.into()
+    }
+}
+
+/// Queue lengths estimated the way an actuated controller sees them: an arrival detector adds a
+/// car, a car crossing the stop line takes one away and the estimate slowly forgets cars so that
+/// missed pulses don't stick around forever.
+#[derive(Clone, Debug)]
+pub struct EstimatedDemand {
+    /// Estimated cars on each approach (indexed by `Origin::index`)
+    counts: [f64; 4],
+    /// Exponential rate (per second) at which the estimate is forgotten
+    decay: f64,
+}
+
+impl EstimatedDemand {
+    pub fn new(decay: f64) -> EstimatedDemand {
+        EstimatedDemand {
+            counts: [0.0; 4],
+            decay,
+        }
+    }
+
+    /// A car went over the approach's arrival detector
+    pub fn actuate(&mut self, origin: Origin) {
+        self.counts[origin.index()] += 1.0;
+    }
+
+    /// A car from the approach crossed the stop line
+    pub fn cross(&mut self, origin: Origin) {
+        let count = &mut self.counts[origin.index()];
+        *count = (*count - 1.0).max(0.0);
+    }
+
+    pub fn update(&mut self, elapsed: Duration) {
+        let factor = (-self.decay * elapsed.as_secs_f64()).exp();
+        for count in &mut self.counts {
+            *count *= factor;
+        }
+    }
+
+    /// Returns the estimated number of cars waiting on the approach
+    pub fn get(&self, origin: Origin) -> usize {
+        self.counts[origin.index()].round() as usize
+    }
+}
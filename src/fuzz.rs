@@ -0,0 +1,123 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
+
+use crate::{
+    car::{Direction, ORIGINS},
+    controller,
+    simulation::{CollisionPolicy, Simulation, TICK},
+    sweep::Variation,
+    DEMAND_SCALE_RANGE, FUZZ_DURATION,
+};
+
+/// Settings of one `--fuzz` run, all picked at random from its seed. Only what can change while
+/// running is picked, the rest of the config is as is.
+#[derive(Clone, Debug)]
+pub struct FuzzCase {
+    pub seed: u64,
+    pub variations: Vec<Variation>,
+    pub demand_scale: f64,
+    /// Times at which arrivals are turned off, then back on, then off again and so on
+    pub spawning_toggles: Vec<Duration>,
+    pub collision_policy: CollisionPolicy,
+}
+
+impl FuzzCase {
+    pub fn new(seed: u64) -> FuzzCase {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut variations = vec![
+            Variation::MinimumGreenTime(Duration::from_millis(rng.gen_range(0..8000))),
+            Variation::YellowTime(Duration::from_millis(rng.gen_range(500..6000))),
+            Variation::SwitchRatio(rng.gen_range(0.25..4.0)),
+        ];
+        for _ in 0..rng.gen_range(0..4) {
+            variations.push(Variation::MovementWeight(
+                ORIGINS[rng.gen_range(0..ORIGINS.len())],
+                Direction::from(rng.gen_range(0..=3)),
+                rng.gen_range(0.1..5.0),
+            ));
+        }
+        let mut spawning_toggles = (0..rng.gen_range(0..5))
+            .map(|_| FUZZ_DURATION.mul_f64(rng.gen_range(0.0..1.0)))
+            .collect::<Vec<_>>();
+        spawning_toggles.sort();
+
+        FuzzCase {
+            seed,
+            variations,
+            demand_scale: rng.gen_range(DEMAND_SCALE_RANGE.0..=DEMAND_SCALE_RANGE.1),
+            spawning_toggles,
+            collision_policy: if rng.gen_bool(0.5) {
+                CollisionPolicy::Continue
+            } else {
+                CollisionPolicy::Remove
+            },
+        }
+    }
+
+    /// Runs the case for `FUZZ_DURATION` of simulated time (or until it halts), and panics if a
+    /// car was taken off the light's queue twice. The simulation's other queue and car count
+    /// checks are debug assertions, so they only run in debug builds.
+    pub fn run(&self) {
+        let traffic_light = controller::from_config(|traffic_light| {
+            for variation in &self.variations {
+                variation.apply(traffic_light);
+            }
+        });
+        let mut simulation = Simulation::with_seed(traffic_light, self.seed);
+        simulation.set_demand_scale(self.demand_scale);
+        simulation.set_collision_policy(self.collision_policy);
+
+        let mut toggles = self.spawning_toggles.iter().peekable();
+        while simulation.time() < FUZZ_DURATION && !simulation.halted() {
+            if toggles
+                .next_if(|&&time| time <= simulation.time())
+                .is_some()
+            {
+                let spawning = !simulation.spawning();
+                simulation.set_spawning(spawning);
+            }
+            simulation.step(TICK);
+        }
+        simulation.summary();
+        assert_eq!(
+            simulation.traffic_light().queue_underflows(),
+            0,
+            "Cars were removed from the light's queue twice"
+        );
+    }
+}
+
+/// Runs the cases of the given seeds, and returns the ones that panicked with what they panicked
+/// with
+pub fn run(seeds: impl Iterator<Item = u64>) -> Vec<(FuzzCase, String)> {
+    seeds
+        .filter_map(|seed| {
+            let case = FuzzCase::new(seed);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| case.run()));
+            let payload = result.err()?;
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Some((case, message))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fewer than `FUZZ_RUNS`, to keep the tests quick
+    const RUNS: u64 = 10;
+
+    #[test]
+    fn fuzz_runs_dont_panic() {
+        let failures = run(0..RUNS);
+        assert!(failures.is_empty(), "{:?}", failures);
+    }
+}
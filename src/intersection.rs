@@ -0,0 +1,67 @@
+use crate::{
+    car::{Car, LANE_WIDTH},
+    HEIGHT, WIDTH,
+};
+
+/// The square in the middle of the map where the approaches cross
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct IntersectionBox {
+    center: (f64, f64),
+    /// Distance from the center to each side
+    half_size: f64,
+}
+
+/// The intersection of the map, two lanes out from the middle of the window to every side
+pub const INTERSECTION_BOX: IntersectionBox = IntersectionBox {
+    center: (WIDTH as f64 / 2.0, HEIGHT as f64 / 2.0),
+    half_size: LANE_WIDTH * 2.0,
+};
+
+impl IntersectionBox {
+    pub const fn center(&self) -> (f64, f64) {
+        self.center
+    }
+
+    pub const fn half_size(&self) -> f64 {
+        self.half_size
+    }
+
+    /// Returns the (left, top, right, bottom) edges
+    pub const fn extent(&self) -> (f64, f64, f64, f64) {
+        (
+            self.center.0 - self.half_size,
+            self.center.1 - self.half_size,
+            self.center.0 + self.half_size,
+            self.center.1 + self.half_size,
+        )
+    }
+
+    /// Returns true if the point is inside the box (points on its edges are outside)
+    pub fn contains(&self, (x, y): (f64, f64)) -> bool {
+        let (left, top, right, bottom) = self.extent();
+        x > left && x < right && y > top && y < bottom
+    }
+
+    /// Returns true if any part of the car is inside the box
+    pub fn contains_car(&self, car: &Car) -> bool {
+        car.vertices().iter().any(|&point| self.contains(point))
+    }
+}
+
+/// How many cars are in the intersection (or cleared to enter it), for `STRICT_SINGLE_CAR`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Occupancy(usize);
+
+impl Occupancy {
+    pub fn enter(&mut self) {
+        self.0 += 1;
+    }
+
+    pub fn exit(&mut self) {
+        self.0 = self.0.saturating_sub(1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
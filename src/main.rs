@@ -1,233 +1,989 @@
 extern crate find_folder;
 extern crate piston_window;
-use car::LANE_WIDTH;
+use camera::Camera;
+use car::{
+    Direction, Origin, VehicleClass, CAR_WIDTH, EAST_WEST_EDGE, LANE_WIDTH, NORTH_SOUTH_EDGE,
+    ORIGINS,
+};
+use demand::DemandProfile;
+use intersection::INTERSECTION_BOX;
+use phase_group::PhaseGroup;
 use piston_window::*;
-use rand::Rng;
+use simulation::{CollisionPolicy, EndCondition, Simulation, VehicleMix, TICK};
+use sprites::Sprites;
 use std::{
-    path,
-    time::{self, Instant},
+    collections::VecDeque,
+    env,
+    path::{self, Path},
+    process,
+    time::{Duration, Instant},
 };
+use sweep::Variation;
+use traffic_light::{DemandSource, LeftTurnOrder, TrafficLight, YellowTimes};
 
+mod box_log;
+mod camera;
 mod car;
+mod conflicts;
+mod controller;
+mod demand;
+mod fuzz;
+mod intersection;
+mod phase_group;
+mod phase_log;
+mod plot;
+mod scripted_controller;
+mod simulation;
+mod sprites;
+mod sweep;
 mod traffic_light;
+mod units;
 
 pub const WIDTH: u32 = 1280;
 pub const HEIGHT: u32 = 1280;
+/// How long a pixel is in the real world, for showing distances and speeds in meters and km/h (a
+/// car is `CAR_WIDTH` pixels long). An update is `TICK` of simulated time.
+pub const METERS_PER_PIXEL: f64 = 0.09;
 
 pub const USE_ENTRY_TIME: bool = true;
-/// Allow the lane opposite of the current green to go straight/right when possible
-pub const ALLOW_MOVING_ON_RED: bool = false;
+/// Multiplies the computed all red time between conflicting movements (1.0 = as computed, higher is
+/// safer but wastes more of the cycle). `CONFLICT_DELAY_OVERRIDES` are used as is.
+pub const RED_CLEARANCE_FACTOR: f64 = 1.0;
+/// End the opposite lane's short green together with the green, so left turners facing a yellow
+/// never have oncoming traffic that keeps going (a "yellow trap")
+pub const PREVENT_YELLOW_TRAP: bool = false;
 /// Allow cars to go into the intersection when they have a yellow light
 pub const ALLOW_GO_ON_YELLOW: bool = false;
+/// Only allow one car in the intersection at a time, regardless of the lights
+pub const STRICT_SINGLE_CAR: bool = false;
+/// Cars don't enter the intersection on a green until there's room for them past it, so they
+/// can't end up stuck in it
+pub const DONT_BLOCK_THE_BOX: bool = false;
+/// How many left turning cars fit in the left turn bay of each approach (0 = no bay)
+pub const LEFT_TURN_STORAGE: usize = 0;
+/// Movements that are prohibited (no cars spawn with them and they get no signal)
+pub const BANNED_MOVEMENTS: &[(Origin, Direction)] = &[];
+/// How far before the intersection the stop line of each approach is (North, South, East, West)
+pub const STOP_LINE_OFFSETS: [f64; 4] = [0.0, 0.0, 0.0, 0.0];
+/// How many degrees each approach's road (North, South, East, West) is turned clockwise from
+/// meeting the intersection straight on, e.g. 20.0 for West brings it in at 70°. The roads turn
+/// around the middle of their side of the intersection box, which stays square.
+pub const APPROACH_SKEWS: [f64; 4] = [0.0, 0.0, 0.0, 0.0];
+/// When the protected left turns of the North/South and East/West approaches happen: whenever a
+/// left turner is first in line, leading (before the opposite approach goes), lagging (after it)
+/// or leading followed by permissive left turns (protected + permissive).
+pub const LEFT_TURN_ORDER: [LeftTurnOrder; 2] = [LeftTurnOrder::AsNeeded, LeftTurnOrder::AsNeeded];
+/// Longest a leading left turn phase lasts, and latest a lagging one starts (seconds into the green)
+pub const LEFT_TURN_PHASE_TIME: f64 = 6.0;
+/// Smallest gap (in seconds until the next oncoming car gets to the intersection) a permissive left
+/// turner will turn in front of
+pub const PERMISSIVE_LEFT_GAP: f64 = 4.0;
+/// Lets a permissive left turner waiting for a gap pull into the intersection and wait there, so it
+/// can go as soon as the gap comes (or once the oncoming traffic stops for the red)
+pub const LEFT_TURN_CREEP: bool = false;
+/// How far past the stop line (in pixels) they pull up to
+pub const CREEP_DISTANCE: f64 = LANE_WIDTH;
+/// Approaches (North, South, East, West) whose left turn signal shows a flashing yellow arrow while
+/// left turners have to yield. The others show the same green ball as the through traffic.
+pub const FLASHING_YELLOW_ARROW: [bool; 4] = [true; 4];
+/// How far before the intersection cars of each approach spawn (North, South, East, West). Longer
+/// approaches have more room for queues, the camera can zoom out to see past the window.
+pub const APPROACH_LENGTHS: [f64; 4] = [
+    NORTH_SOUTH_EDGE,
+    NORTH_SOUTH_EDGE,
+    EAST_WEST_EDGE,
+    EAST_WEST_EDGE,
+];
+/// How long each approach's yellow is: the same for all of them, worked out from how fast cars
+/// come up to the light, or set for each one
+pub const YELLOW_TIMES: YellowTimes = YellowTimes::Uniform;
+/// Approach that has the green when the simulation starts (`None` = whichever approach the first
+/// car arrives on)
+pub const INITIAL_GREEN: Option<Origin> = None;
+/// Keep the first green until at least this many cars are waiting at once, so the first few cars
+/// don't make the light switch back and forth (0 = switch right away)
+pub const MIN_CARS_TO_SWITCH: usize = 0;
+/// Phases the light chooses between, each giving a set of movements that don't cross the green
+/// together (`&PhaseGroup::STANDARD` has the usual ones). Empty gives one approach at a time the
+/// green instead.
+pub const PHASE_GROUPS: &[PhaseGroup] = &[];
+/// Hold the green while a car of its approach is in the dilemma zone (too close to stop
+/// comfortably, too far to make it through before the red)
+pub const DILEMMA_ZONE_PROTECTION: bool = false;
+/// Where the dilemma zone detectors are: from and to how far (in pixels) before the stop line
+pub const DILEMMA_ZONE: (f64, f64) = (45.0, 150.0);
+/// Longest a green can be held for cars in the dilemma zone
+pub const DILEMMA_ZONE_MAX_GREEN: Duration = Duration::from_secs(10);
+/// Give the green to another approach with cars waiting once none of the green approach's waiting
+/// cars has gone for this long, e.g. when they're backed up from past the intersection (`None` =
+/// only the queue lengths decide)
+pub const STALE_GREEN_TIMEOUT: Option<Duration> = None;
+/// Approaches that get a green every cycle, even when no cars are waiting
+pub const RECALL: &[Origin] = &[];
+/// Conflict delays (moving movement, waiting movement, seconds) to use instead of the ones
+/// computed from the intersection geometry
+pub const CONFLICT_DELAY_OVERRIDES: &[((Origin, Direction), (Origin, Direction), f64)] = &[];
+/// How much each waiting car of a movement counts for when the light compares queues (1 for the
+/// movements left out), e.g. to keep a main road's through traffic going
+pub const MOVEMENT_WEIGHTS: &[(Origin, Direction, f64)] = &[];
+/// How many times longer than the green approach's queue another queue has to be to take the
+/// green from it (once the minimum green is over). Higher values make greens stickier, lower
+/// values switch more often (at 1.0 any queue as long as the green one's takes over).
+pub const SWITCH_RATIO: f64 = 1.75;
+/// How much an approach's priority grows per second it goes without a green (0 = only the queue
+/// length matters). Higher values trade throughput for fairness.
+pub const FAIRNESS_WEIGHT: f64 = 0.0;
+/// Have the controller estimate the queues from detector pulses instead of knowing them exactly
+pub const USE_ESTIMATED_DEMAND: bool = false;
+/// How fast the estimated queues forget cars (exponential rate per second)
+pub const DEMAND_DECAY: f64 = 0.02;
+/// If the controller only counts the cars on the map or also the ones backed up past the start of
+/// the approach, waiting for room to spawn
+pub const DEMAND_SOURCE: DemandSource = DemandSource::Physical;
+
+/// Seed for all of the randomness in the simulation
+pub const SEED: u64 = 0;
+/// How cars arrive. `DemandProfile::Platoons { size: 6, headway: Duration::from_millis(1200) }`
+/// brings them in groups like a coordinated corridor would, `DemandProfile::DetectorData(path)`
+/// replays counts from real detectors.
+pub const DEMAND_PROFILE: DemandProfile = DemandProfile::Ramp;
+/// Arrival rate of each approach in vehicles/hour (North, South, East, West), for every
+/// `DEMAND_PROFILE` but `Ramp`
+pub const ARRIVAL_RATES: [f64; 4] = [450.0, 450.0, 450.0, 450.0];
+/// How far the demand can be turned down and up (as a multiple of the configured one) with - and
+/// + while running. Replayed detector data always plays back as recorded.
+pub const DEMAND_SCALE_RANGE: (f64, f64) = (0.25, 4.0);
+/// How much each press of - or + divides or multiplies the demand by
+pub const DEMAND_SCALE_STEP: f64 = 1.25;
+/// Share of each approach's cars (North, South, East, West) that turn left, turn right, go
+/// straight and make a U-turn. Each approach's are scaled to add up to 1, and banned movements are
+/// left out. Mostly through traffic like a real intersection (`[1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0,
+/// 0.0]` is the even split the model used to have).
+pub const TURN_FRACTIONS: [[f64; 4]; 4] = [[0.2, 0.2, 0.6, 0.0]; 4];
+/// Space left between the back of a spawned car and the next car spawning behind it. Limits how
+/// fast cars can spawn on each approach.
+pub const MIN_SPAWN_GAP: f64 = CAR_WIDTH;
+/// How much drivers' acceleration, top speed and following gap vary (0.1 = up to 10% either way
+/// of the average)
+pub const DRIVER_VARIABILITY: f64 = 0.0;
+/// How fast cars slow down for routine stops (pixels per update, per update)
+pub const DECELERATION: f64 = 0.3;
+/// How fast cars slow down when they get within `EMERGENCY_BRAKING_GAP` of the car in front
+pub const EMERGENCY_DECELERATION: f64 = 0.6;
+/// How long a car takes to turn halfway towards the heading of the next point of its path (it
+/// keeps closing half the remaining turn every half-life, whatever the update rate). Much more
+/// than a couple of updates and cars turn too slowly to hit the points of their paths.
+pub const ROTATION_HALF_LIFE: Duration = TICK;
+/// Gap (in pixels, bumper to bumper) to the car in front below which cars brake as hard as they
+/// can
+pub const EMERGENCY_BRAKING_GAP: f64 = 25.0;
+/// How long a driver takes to react once they can pull away from a stop, at a green or behind a
+/// car that started moving. Each car in a queue adds its own, so they start one after the other.
+pub const STARTUP_DELAY: Duration = Duration::ZERO;
+/// How often each kind of vehicle spawns
+pub const VEHICLE_MIX: VehicleMix = VehicleMix {
+    car: 1.0,
+    truck: 0.0,
+    bus: 0.0,
+};
+
+/// Light plan file to play on a loop instead of responding to the traffic (the format is
+/// described in scripted_controller.rs)
+pub const LIGHT_SCRIPT: Option<&str> = None;
+
+/// How many updates per second the window normally runs at
+pub const UPS: u64 = 60;
+/// How long the simulation has to go without any cars before the window slows down to
+/// `IDLE_UPS` to save CPU
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Updates per second while idle (each update covers several ticks so time still passes at the
+/// same speed)
+pub const IDLE_UPS: u64 = 4;
+/// Render skip factors that F cycles through. With a factor of N every update steps the
+/// simulation N ticks and only draws the last one, so it runs N times faster than real time.
+pub const RENDER_SKIPS: &[u64] = &[1, 2, 4, 8, 16];
+
+/// Images (in the assets folder, facing right) to draw vehicle classes with instead of rectangles
+pub const VEHICLE_SPRITES: &[(VehicleClass, &str)] = &[];
+
+/// Colors and lane markings of the map (`MapStyle::DEFAULT` or `MapStyle::DARK`, or your own)
+pub const MAP_STYLE: MapStyle = MapStyle::DEFAULT;
+/// Draws the path through the intersection of every movement that can go, so it's clear which
+/// combination of movements the controller picked (yellow while clearing, thinner for lefts that
+/// have to yield)
+pub const SHOW_GREEN_MOVEMENTS: bool = true;
+/// Draws the paths of the green movements and of the next one in line for the green, circling
+/// where they meet and with the next one's path up to there (its entry time) thicker
+pub const SHOW_CONFLICTS: bool = false;
+/// Dims the cars that are stopped, so standing queues stand out from the moving traffic (can be
+/// toggled with Q while running)
+pub const DIM_STOPPED_CARS: bool = false;
+
+/// How often the state is saved for replaying it while paused
+pub const REPLAY_INTERVAL: Duration = Duration::from_millis(250);
+/// How far back the replay goes
+pub const REPLAY_LENGTH: Duration = Duration::from_secs(30);
+/// How much simulated time the plot of waiting and finished cars in the debug overlay (H) shows
+pub const PLOT_WINDOW: Duration = Duration::from_secs(30);
+
+/// How long a car can go without getting anywhere (while not waiting for the light or the cars in
+/// front) before it's reported as stuck
+pub const STUCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Take stuck cars off the map instead of just reporting them
+pub const REMOVE_STUCK_CARS: bool = false;
+/// Take cars off the map as soon as they're out of the intersection instead of at the edge of the
+/// window, so there are fewer cars to update. Cars driving away then don't hold up the ones coming
+/// out behind them (`DONT_BLOCK_THE_BOX` never has to hold anyone).
+pub const DESPAWN_PAST_INTERSECTION: bool = false;
+/// Room (in pixels, on every side) around a car's collision box that another car getting into
+/// counts as a near miss. Doesn't change the drawn car or what counts as a collision.
+pub const COLLISION_PADDING: f64 = 5.0;
+/// How far apart in time two cars getting to the point where their paths cross have to be for
+/// the summary's conflicts to count them as marginal, and then as safe (post-encroachment time)
+pub const CONFLICT_THRESHOLDS: (Duration, Duration) =
+    (Duration::from_millis(500), Duration::from_millis(1500));
+/// Cars getting to the point where their paths cross further apart than this aren't a conflict
+pub const CONFLICT_WINDOW: Duration = Duration::from_secs(5);
+/// What happens when cars collide (can be cycled with C while running)
+pub const COLLISION_POLICY: CollisionPolicy = CollisionPolicy::Continue;
+
+/// When to stop the simulation and print a summary
+pub const END_CONDITION: EndCondition = EndCondition::Never;
+/// Simulated time at the start of a run, while the intersection fills up, whose cars (and
+/// incidents) are left out of the summary. The end condition still counts from the start.
+pub const WARM_UP: Duration = Duration::ZERO;
+/// CSV file to write every phase change to when the simulation ends (see phase_log.rs)
+pub const PHASE_LOG: Option<&str> = None;
+/// CSV file to write when each finished car crossed the stop line and entered and cleared the
+/// intersection box to when the simulation ends, for checking the clearance times (see box_log.rs)
+pub const BOX_LOG: Option<&str> = None;
+/// Folder to save a PNG of the last frame to when the end condition is reached (not in headless
+/// mode). It's named after the controller, arrivals, end condition and seed of the run.
+pub const SCREENSHOT_FOLDER: Option<&str> = None;
+/// Settings to try with `--sweep`, each in its own headless run (with the rest of the config as
+/// is). The results are printed as CSV.
+pub const SWEEP: &[Variation] = &[
+    Variation::MinimumGreenTime(Duration::from_millis(1500)),
+    Variation::MinimumGreenTime(Duration::from_millis(2200)),
+    Variation::MinimumGreenTime(Duration::from_millis(3000)),
+    Variation::MinimumGreenTime(Duration::from_millis(5000)),
+];
+/// How many runs `--fuzz` does, each with its own seed and random settings (see fuzz.rs). Build
+/// without `--release` so the queue and car count checks run too.
+pub const FUZZ_RUNS: u64 = 100;
+/// Simulated time of each `--fuzz` run
+pub const FUZZ_DURATION: Duration = Duration::from_secs(120);
+/// Runs a second simulation (same seed and demand) with this setting changed, drawn on the right
+/// half of the window next to the one with the config as is
+pub const COMPARE: Option<Variation> = None;
+/// How often (in simulated time) to log how many cars came in and went out of each approach, to
+/// catch cars getting lost or counted twice
+pub const CONSERVATION_LOG: Option<Duration> = None;
+/// How often (in real time) the `--dashboard` of a headless run is redrawn
+pub const DASHBOARD_REFRESH: Duration = Duration::from_millis(500);
+
+/// Colors and lane markings of the map
+pub struct MapStyle {
+    pub background: [f32; 4],
+    /// The grass in the four corners
+    pub grass: [f32; 4],
+    pub lane_markings: [f32; 4],
+    /// Dashes on each side of the intersection, in each direction
+    pub num_dashes: u32,
+    /// Gap between dashes as a fraction of the dash length
+    pub dash_gap_percent: f64,
+    pub dash_width: f64,
+    pub stop_line_width: f64,
+}
+
+impl MapStyle {
+    pub const DEFAULT: MapStyle = MapStyle {
+        background: [0.1; 4],
+        grass: [0.0, 1.0, 0.0, 1.0],
+        lane_markings: [1.0; 4],
+        num_dashes: 10,
+        dash_gap_percent: 2.0 / 5.0,
+        dash_width: 3.0,
+        stop_line_width: 2.0,
+    };
+
+    /// Darker and easier on the eyes
+    pub const DARK: MapStyle = MapStyle {
+        background: [0.05, 0.05, 0.05, 1.0],
+        grass: [0.08, 0.16, 0.08, 1.0],
+        lane_markings: [0.6, 0.6, 0.6, 1.0],
+        num_dashes: 10,
+        dash_gap_percent: 2.0 / 5.0,
+        dash_width: 2.0,
+        stop_line_width: 2.0,
+    };
+}
 
-fn draw_map(context: &Context, graphics: &mut G2d) {
-    let middle = (WIDTH as f64 / 2.0, HEIGHT as f64 / 2.0);
-    [
-        [0.0, 0.0],
-        [middle.0 + LANE_WIDTH * 2.0, 0.0],
-        [0.0, middle.1 + LANE_WIDTH * 2.0],
-        [middle.0 + LANE_WIDTH * 2.0, middle.1 + LANE_WIDTH * 2.0],
-    ]
-    .iter()
-    .for_each(|&start| {
-        rectangle(
-            [0.0, 1.0, 0.0, 1.0],
-            [
-                start[0],
-                start[1],
-                WIDTH as f64 / 2.0 - LANE_WIDTH * 2.0,
-                HEIGHT as f64 / 2.0 - LANE_WIDTH * 2.0,
-            ],
+fn draw_map(style: &MapStyle, context: &Context, graphics: &mut G2d) {
+    let middle = INTERSECTION_BOX.center();
+    let (left, top, right, bottom) = INTERSECTION_BOX.extent();
+    // Grass everywhere but the intersection and the roads (out past the edge of the window, turned
+    // by their skews)
+    rectangle(
+        style.grass,
+        [0.0, 0.0, WIDTH as f64, HEIGHT as f64],
+        context.transform,
+        graphics,
+    );
+    rectangle(
+        style.background,
+        [left, top, right - left, bottom - top],
+        context.transform,
+        graphics,
+    );
+    let far = WIDTH.max(HEIGHT) as f64;
+    for origin in ORIGINS {
+        let corners = match origin {
+            Origin::North => [(left, top), (right, top), (right, -far), (left, -far)],
+            Origin::South => [(left, bottom), (right, bottom), (right, far), (left, far)],
+            Origin::East => [(right, top), (right, bottom), (far, bottom), (far, top)],
+            Origin::West => [(left, top), (left, bottom), (-far, bottom), (-far, top)],
+        };
+        polygon(
+            style.background,
+            &corners.map(|point| {
+                let (x, y) = car::skew_on_road(origin, point);
+                [x, y]
+            }),
             context.transform,
             graphics,
         );
-    });
+    }
 
-    let dash_gap_percent = 2.0 / 5.0;
-    let num_dashes: u32 = 10;
-    let dash_width = 3.0;
+    let dash_gap_percent = style.dash_gap_percent;
+    let num_dashes = style.num_dashes;
+    let dash_width = style.dash_width;
 
     // Horizontal dashes
-    let dash_length =
-        (middle.0 - LANE_WIDTH * 2.0) / (num_dashes as f64 * (1.0 + dash_gap_percent));
+    let dash_length = left / (num_dashes as f64 * (1.0 + dash_gap_percent));
     let dash_gap = dash_length * dash_gap_percent;
-    for i in 0..(((middle.0 - LANE_WIDTH * 2.0) / (dash_length + dash_gap)) as u32) {
+    for i in 0..((left / (dash_length + dash_gap)) as u32) {
         let mut start = i as f64 * (dash_length + dash_gap) + dash_gap / 2.0;
         for _ in 0..2 {
+            let (from, to) = (
+                car::skew((start, middle.1)),
+                car::skew((start + dash_length, middle.1)),
+            );
             line_from_to(
-                [1.0; 4],
+                style.lane_markings,
                 dash_width,
-                [start, middle.1],
-                [start + dash_length, middle.1],
+                [from.0, from.1],
+                [to.0, to.1],
                 context.transform,
                 graphics,
             );
-            start += middle.0 + LANE_WIDTH * 2.0;
+            start += right;
         }
     }
 
     // Vertical dashes
-    let dash_length =
-        (middle.1 - LANE_WIDTH * 2.0) / (num_dashes as f64 * (1.0 + dash_gap_percent));
+    let dash_length = top / (num_dashes as f64 * (1.0 + dash_gap_percent));
     let dash_gap = dash_length * dash_gap_percent;
-    for i in 0..(((middle.1 - LANE_WIDTH * 2.0) / (dash_length + dash_gap)) as u32) {
+    for i in 0..((top / (dash_length + dash_gap)) as u32) {
         let mut start = i as f64 * (dash_length + dash_gap) + dash_gap / 2.0;
         for _ in 0..2 {
+            let (from, to) = (
+                car::skew((middle.0, start)),
+                car::skew((middle.0, start + dash_length)),
+            );
             line_from_to(
-                [1.0; 4],
+                style.lane_markings,
                 dash_width,
-                [middle.0, start],
-                [middle.0, start + dash_length],
+                [from.0, from.1],
+                [to.0, to.1],
                 context.transform,
                 graphics,
             );
-            start += middle.1 + LANE_WIDTH * 2.0;
+            start += bottom;
         }
     }
 
     // Stop lines
-    for i in 0..2 {
-        line_from_to(
-            [1.0; 4],
-            2.0,
-            [
-                WIDTH as f64 / 2.0 - LANE_WIDTH * 2.0 + LANE_WIDTH * 2.0 * i as f64,
-                HEIGHT as f64 / 2.0 - LANE_WIDTH * 2.0 + LANE_WIDTH * 4.0 * i as f64,
-            ],
-            [
-                WIDTH as f64 / 2.0 + LANE_WIDTH * 2.0 * i as f64,
-                HEIGHT as f64 / 2.0 - LANE_WIDTH * 2.0 + LANE_WIDTH * 4.0 * i as f64,
-            ],
-            context.transform,
-            graphics,
+    for origin in ORIGINS {
+        let (x, y) = car::stop_line_position(origin);
+        let (start, end) = match origin {
+            Origin::North | Origin::South => ((x - LANE_WIDTH, y), (x + LANE_WIDTH, y)),
+            Origin::East | Origin::West => ((x, y - LANE_WIDTH), (x, y + LANE_WIDTH)),
+        };
+        let (start, end) = (
+            car::skew_on_road(origin, start),
+            car::skew_on_road(origin, end),
         );
         line_from_to(
-            [1.0; 4],
-            2.0,
-            [
-                WIDTH as f64 / 2.0 - LANE_WIDTH * 2.0 + LANE_WIDTH * 4.0 * i as f64,
-                HEIGHT as f64 / 2.0 - LANE_WIDTH * 2.0 * i as f64,
-            ],
-            [
-                WIDTH as f64 / 2.0 - LANE_WIDTH * 2.0 + LANE_WIDTH * 4.0 * i as f64,
-                HEIGHT as f64 / 2.0 + LANE_WIDTH * 2.0 - LANE_WIDTH * 2.0 * i as f64,
-            ],
+            style.lane_markings,
+            style.stop_line_width,
+            [start.0, start.1],
+            [end.0, end.1],
             context.transform,
             graphics,
         );
     }
 }
 
+/// Returns the context to draw one side (0 is left, 1 is right) of the split screen with: the whole
+/// screen at half the size, centered vertically in its half and clipped to it
+fn split_screen_context(context: &Context, side: usize) -> Context {
+    let x = side as f64 * WIDTH as f64 / 2.0;
+    // The scissor is in pixels of the framebuffer, which can be bigger than the window
+    let pixels = context.viewport.map_or(1.0, |viewport| {
+        viewport.draw_size[0] as f64 / viewport.window_size[0]
+    });
+    let mut context = context.trans(x, HEIGHT as f64 / 4.0).scale(0.5, 0.5);
+    context.draw_state = context.draw_state.scissor([
+        (x * pixels) as u32,
+        0,
+        (WIDTH as f64 / 2.0 * pixels) as u32,
+        (HEIGHT as f64 * pixels) as u32,
+    ]);
+    context
+}
+
+/// Converts a point on the split screen to the side it's on and where it would be on a full screen
+fn from_split_screen(point: [f64; 2]) -> (usize, [f64; 2]) {
+    let half = WIDTH as f64 / 2.0;
+    let side = if point[0] < half { 0 } else { 1 };
+    (
+        side,
+        [
+            (point[0] - side as f64 * half) * 2.0,
+            (point[1] - HEIGHT as f64 / 4.0) * 2.0,
+        ],
+    )
+}
+
+/// Runs every variation of `SWEEP` and prints the summaries as CSV
+fn run_sweep() {
+    if let EndCondition::Never = END_CONDITION {
+        eprintln!("Sweeps need an END_CONDITION");
+        return;
+    }
+    print!("{}", sweep::to_csv(&sweep::run(SWEEP)));
+}
+
+/// Runs `FUZZ_RUNS` headless runs with random settings, and lists the ones that panicked. Exits
+/// with an error if any did.
+fn run_fuzz() {
+    let failures = fuzz::run(0..FUZZ_RUNS);
+    for (case, message) in &failures {
+        eprintln!("{:?}: {}", case, message);
+    }
+    println!("{} of {} fuzz runs failed", failures.len(), FUZZ_RUNS);
+    if !failures.is_empty() {
+        process::exit(1);
+    }
+}
+
+/// Runs the simulation without a window as fast as possible until the end condition is reached
+fn run_headless(dashboard: bool) {
+    if let EndCondition::Never = END_CONDITION {
+        eprintln!("Headless mode needs an END_CONDITION");
+        return;
+    }
+
+    let mut simulation = Simulation::new();
+    let mut last_refresh: Option<Instant> = None;
+    // Same steps as the window, just without waiting between them
+    while !simulation.finished() && !simulation.halted() {
+        simulation.step(TICK);
+        if dashboard && last_refresh.map_or(true, |time| time.elapsed() >= DASHBOARD_REFRESH) {
+            last_refresh = Some(Instant::now());
+            print_dashboard(&simulation);
+        }
+    }
+    if dashboard {
+        print_dashboard(&simulation);
+    }
+    println!("{}", simulation.summary());
+    write_logs(&simulation);
+}
+
+fn write_logs(simulation: &Simulation) {
+    if let Some(path) = PHASE_LOG {
+        if let Err(err) = phase_log::write_csv(simulation.phase_history(), path) {
+            eprintln!("Couldn't write the phase log to {}: {}", path, err);
+        }
+    }
+    if let Some(path) = BOX_LOG {
+        if let Err(err) = box_log::write_csv(simulation.box_crossings(), path) {
+            eprintln!("Couldn't write the box log to {}: {}", path, err);
+        }
+    }
+}
+
+/// Names the run after the settings that change between experiments
+fn run_name() -> String {
+    let controller = if LIGHT_SCRIPT.is_some() {
+        "script"
+    } else if !PHASE_GROUPS.is_empty() {
+        "phase-groups"
+    } else {
+        "adaptive"
+    };
+    let arrivals = match DEMAND_PROFILE {
+        DemandProfile::Ramp => "ramp",
+        DemandProfile::Poisson => "poisson",
+        DemandProfile::Platoons { .. } => "platoons",
+        DemandProfile::DetectorData(_) => "detectors",
+    };
+    let end = match END_CONDITION {
+        EndCondition::Never => String::from("open"),
+        EndCondition::CarsFinished(cars) => format!("{}cars", cars),
+        EndCondition::Time(time) => format!("{}s", time.as_secs()),
+    };
+    format!("{}-{}-{}-seed{}", controller, arrivals, end, SEED)
+}
+
+/// Saves the frame that was just drawn (and not shown yet) to `folder` as a PNG
+fn save_screenshot(window: &mut PistonWindow, folder: &str) {
+    gl::load_with(|name| window.window.get_proc_address(name));
+    let size = window.draw_size();
+    let (width, height) = (size.width as u32, size.height as u32);
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    unsafe {
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        gl::ReadPixels(
+            0,
+            0,
+            width as i32,
+            height as i32,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            pixels.as_mut_ptr() as *mut _,
+        );
+    }
+    // The background is drawn see-through
+    pixels.chunks_mut(4).for_each(|pixel| pixel[3] = 255);
+    let mut screenshot = ::image::RgbaImage::from_raw(width, height, pixels).unwrap();
+    // OpenGL reads the rows bottom up
+    ::image::imageops::flip_vertical_in_place(&mut screenshot);
+
+    let path = Path::new(folder).join(format!("{}.png", run_name()));
+    match screenshot.save(&path) {
+        Ok(()) => println!("Saved a screenshot to {}", path.display()),
+        Err(err) => eprintln!("Couldn't save a screenshot to {}: {}", path.display(), err),
+    }
+}
+
+/// Redraws the headless run's progress in place of the last one
+fn print_dashboard(simulation: &Simulation) {
+    let traffic_light = simulation.traffic_light();
+    let summary = simulation.summary();
+    let average_waits = simulation.average_waits();
+
+    let yellow = ORIGINS
+        .into_iter()
+        .filter(|&origin| traffic_light.in_yellow(origin))
+        .map(|origin| format!("{:?}", origin))
+        .collect::<Vec<_>>();
+    let green = TrafficLight::all_movements()
+        .into_iter()
+        .filter(|car| traffic_light.is_green(car.origin, car.direction))
+        .map(|car| format!("{:?}:{:?}", car.origin, car.direction))
+        .collect::<Vec<_>>();
+    let phase = if !yellow.is_empty() {
+        format!("{} yellow", yellow.join("/"))
+    } else if green.is_empty() {
+        String::from("all red")
+    } else {
+        // Light plans don't log their greens, so those are listed instead
+        match traffic_light.phase_log().last() {
+            Some(event) => format!("{} green", event.phase),
+            None => format!("{} green", green.join(" ")),
+        }
+    };
+    let mut lines = vec![
+        format!(
+            "Time: {:.01}s   Phase: {}",
+            simulation.time().as_secs_f64(),
+            phase
+        ),
+        format!(
+            "Finished: {}   Throughput: {:.01} cars/h   Near misses: {}",
+            summary.cars_finished,
+            summary.throughput,
+            simulation.near_miss_count()
+        ),
+        format!("{:<8}{:>8}{:>10}{:>12}", "", "Queue", "Pending", "Avg wait"),
+    ];
+    for origin in ORIGINS {
+        lines.push(format!(
+            "{:<8}{:>8}{:>10}{:>12}",
+            format!("{:?}", origin),
+            traffic_light.queue_by_origin(origin),
+            simulation.pending_spawns(origin),
+            average_waits
+                .get(&origin)
+                .map_or(String::from("-"), |wait| format!("{:.02}s", wait))
+        ));
+    }
+    // Back to the top left and clear everything below it
+    println!("\x1b[H\x1b[J{}", lines.join("\n"));
+}
+
+/// Returns how many ticks the window steps the simulation each update
+fn steps_per_update(idle: bool, render_skip: usize) -> u64 {
+    let steps = if idle { UPS / IDLE_UPS } else { 1 };
+    steps * RENDER_SKIPS[render_skip]
+}
+
 fn main() {
+    if env::args().any(|arg| arg == "--sweep") {
+        run_sweep();
+        return;
+    }
+    if env::args().any(|arg| arg == "--fuzz") {
+        run_fuzz();
+        return;
+    }
+    if env::args().any(|arg| arg == "--headless") {
+        run_headless(env::args().any(|arg| arg == "--dashboard"));
+        return;
+    }
+    // Print which movements conflict (and with what delay) to check the safety logic
+    if env::args().any(|arg| arg == "--conflicts") {
+        println!("{}", TrafficLight::new().dump_conflict_matrix());
+        return;
+    }
+
     let mut window: PistonWindow =
         WindowSettings::new("Insersection Traffic Manager", [WIDTH, HEIGHT])
-            .exit_on_esc(true)
+            .exit_on_esc(false)
             .resizable(false)
             .build()
             .unwrap();
 
-    let assets: path::PathBuf = find_folder::Search::ParentsThenKids(3, 3)
+    let assets = find_folder::Search::ParentsThenKids(3, 3)
         .for_folder("assets")
-        .unwrap();
-    let mut glyphs: Glyphs = window.load_font(assets.join("Consolas.ttf")).unwrap();
-
-    let mut cars: Vec<car::Car> = Vec::new();
-    let mut id: usize = 0;
-
-    let mut spawn_start = Instant::now();
-    let mut spawn_increment = time::Duration::from_millis(1000);
-    let origins = [
-        car::Origin::North,
-        car::Origin::South,
-        car::Origin::East,
-        car::Origin::West,
-    ];
-    let mut origin_index = 0;
+        .map_err(|err| err.to_string());
+    // Without the font everything still runs, there's just no text
+    let mut glyphs: Option<Glyphs> = match assets.clone().and_then(|assets: path::PathBuf| {
+        window
+            .load_font(assets.join("Consolas.ttf"))
+            .map_err(|err| err.to_string())
+    }) {
+        Ok(glyphs) => Some(glyphs),
+        Err(err) => {
+            eprintln!(
+                "Warning: couldn't load assets/Consolas.ttf ({}), running without text",
+                err
+            );
+            None
+        }
+    };
+    let sprites = match &assets {
+        Ok(assets) => Sprites::load(&mut window, assets),
+        Err(_) => Sprites::default(),
+    };
 
-    let mut traffic_light = traffic_light::TrafficLight::new();
+    let mut simulation = Simulation::new();
+    // Stepped in lockstep with the simulation and drawn next to it
+    let mut comparison = COMPARE.map(|variation| {
+        Simulation::with_controller(controller::from_config(|traffic_light| {
+            variation.apply(traffic_light)
+        }))
+    });
 
     let mut paused: bool = false;
-    let mut last_paused: Instant = Instant::now();
+    let mut show_debug: bool = true;
+    // Lines from every car to the point of its path it's steering towards
+    let mut show_targets = false;
+    let mut dim_stopped = DIM_STOPPED_CARS;
+    let mut camera = Camera::new();
+    // The car the camera is following
+    let mut selected: Option<usize> = None;
+    // Which side of the split screen the selected car is on (ids don't match between the two)
+    let mut selected_side = 0;
+    let mut cursor = [0.0, 0.0];
+    // How long the simulation has gone without any cars
+    let mut idle_time = Duration::ZERO;
+    let mut idle = false;
+    // Saved states to scrub through while paused, oldest first
+    let mut history: VecDeque<Simulation> = VecDeque::new();
+    let mut since_checkpoint = Duration::ZERO;
+    // The saved state being shown instead of the live simulation
+    let mut replay: Option<usize> = None;
+    let history_length = (REPLAY_LENGTH.as_millis() / REPLAY_INTERVAL.as_millis()) as usize;
+    // Index into RENDER_SKIPS
+    let mut render_skip = 0;
+    // The end condition was reached and the last frame is left to draw for the screenshot
+    let mut finished = false;
 
-    window.set_max_fps(60);
+    window.set_max_fps(UPS);
+    window.set_ups(UPS);
     while let Some(event) = window.next() {
-        window.draw_2d(&event, |context, graphics, device| {
-            clear([0.1; 4], graphics);
+        if event.update_args().is_some() && !paused && !finished {
+            let steps = steps_per_update(idle, render_skip);
+            for _ in 0..steps {
+                // One side can reach the end condition before the other
+                if !simulation.finished() {
+                    simulation.step(TICK);
+                }
+                if let Some(comparison) = comparison
+                    .as_mut()
+                    .filter(|comparison| !comparison.finished())
+                {
+                    comparison.step(TICK);
+                }
+                since_checkpoint += TICK;
+                if since_checkpoint >= REPLAY_INTERVAL {
+                    since_checkpoint = Duration::ZERO;
+                    history.push_back(simulation.clone());
+                    if history.len() > history_length {
+                        history.pop_front();
+                    }
+                }
+                if simulation.is_idle() && comparison.as_ref().map_or(true, Simulation::is_idle) {
+                    idle_time += TICK;
+                } else {
+                    idle_time = Duration::ZERO;
+                }
+                if simulation.halted() || comparison.as_ref().map_or(false, Simulation::halted) {
+                    paused = true;
+                    break;
+                }
+                if simulation.finished() && comparison.as_ref().map_or(true, Simulation::finished) {
+                    break;
+                }
+            }
+            if simulation.finished() && comparison.as_ref().map_or(true, Simulation::finished) {
+                println!("{}", simulation.summary());
+                if let Some(comparison) = &comparison {
+                    println!("With {:?}: {}", COMPARE.unwrap(), comparison.summary());
+                }
+                write_logs(&simulation);
+                if SCREENSHOT_FOLDER.is_none() {
+                    break;
+                }
+                finished = true;
+            }
 
-            draw_map(&context, graphics);
+            if idle != (idle_time >= IDLE_TIMEOUT) {
+                idle = !idle;
+                let ups = if idle { IDLE_UPS } else { UPS };
+                window.set_ups(ups);
+                window.set_max_fps(ups);
+            }
+        }
+        let shown = replay.map_or(&simulation, |index| &history[index]);
+        if event.update_args().is_some() {
+            if let Some(id) = selected {
+                let side = match &comparison {
+                    Some(comparison) if selected_side == 1 => comparison,
+                    _ => shown,
+                };
+                match side.car_state(id) {
+                    Some(state) => camera.follow(state.position),
+                    // The car finished its trip (in the replay it might just not have spawned yet)
+                    None if replay.is_none() => selected = None,
+                    None => (),
+                }
+            }
+        }
 
-            let cars_clone = cars.clone();
-            if !paused {
-                traffic_light.update();
+        if let Some(position) = event.mouse_cursor_args() {
+            cursor = position;
+        }
+        if let Some([_, scroll]) = event.mouse_scroll_args() {
+            camera.zoom(scroll);
+        }
 
-                if spawn_start.elapsed() >= spawn_increment {
-                    let minimum_time = 650.0; // 550
-                    spawn_increment = time::Duration::from_millis(
-                        (spawn_increment.as_millis() as f64 * 0.9975).max(minimum_time) as u64,
-                    );
+        window.draw_2d(&event, |context, graphics, device| {
+            clear(MAP_STYLE.background, graphics);
 
-                    let mut origin = origins[rand::thread_rng().gen_range(0..origins.len())];
-                    // 630
-                    if spawn_increment.as_millis() <= 700 {
-                        origin = origins[origin_index];
-                        origin_index = (origin_index + 1) % origins.len();
-                    }
-                    let direction = car::Direction::from(rand::thread_rng().gen_range(0..=2));
-                    cars.push(car::Car::new(id, origin, direction));
-                    traffic_light.add_car(traffic_light::SimplifiedCar::new(origin, direction));
-                    id += 1;
-                    if id > 1000 {
-                        id = 0;
-                    }
+            // Each simulation with the context to draw it (and its stats) with
+            let sides = match &comparison {
+                Some(comparison) => vec![
+                    (shown, split_screen_context(&context, 0)),
+                    (comparison, split_screen_context(&context, 1)),
+                ],
+                None => vec![(shown, context)],
+            };
+            for (index, (side, context)) in sides.into_iter().enumerate() {
+                let map_context = camera.apply(&context);
+                draw_map(&MAP_STYLE, &map_context, graphics);
 
-                    spawn_start = time::Instant::now();
+                side.draw(&sprites, dim_stopped, &map_context, graphics);
+                if show_targets {
+                    side.draw_targets(&map_context, graphics);
                 }
-
-                cars.iter_mut().for_each(|car| {
-                    car.update(&cars_clone, &mut traffic_light);
-                });
-
-                for i in (0..cars.len()).rev() {
-                    if cars[i].finished {
-                        cars.remove(i);
-                    }
+                if let (true, Some(glyphs)) = (show_debug, &mut glyphs) {
+                    let selected = selected.filter(|_| index == selected_side);
+                    side.draw_stats(selected, glyphs, &context, graphics);
                 }
             }
+            let glyphs = match &mut glyphs {
+                Some(glyphs) => glyphs,
+                None => return,
+            };
+            if let Some(variation) = COMPARE {
+                let labels = ["As configured".to_string(), format!("With {:?}", variation)];
+                for (side, label) in labels.iter().enumerate() {
+                    text::Text::new_color([1.0, 1.0, 1.0, 1.0], 20)
+                        .draw(
+                            label,
+                            glyphs,
+                            &context.draw_state,
+                            context.transform.trans(
+                                side as f64 * WIDTH as f64 / 2.0 + 20.0,
+                                HEIGHT as f64 / 4.0 - 20.0,
+                            ),
+                            graphics,
+                        )
+                        .unwrap();
+                }
+            }
+            if show_debug {
+                text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
+                    .draw(
+                        format!("Render skip: {}x", RENDER_SKIPS[render_skip]).as_str(),
+                        glyphs,
+                        &context.draw_state,
+                        context.transform.trans(WIDTH as f64 - 420.0, 75.0),
+                        graphics,
+                    )
+                    .unwrap();
+                text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
+                    .draw(
+                        format!("On collision: {:?}", simulation.collision_policy()).as_str(),
+                        glyphs,
+                        &context.draw_state,
+                        context.transform.trans(WIDTH as f64 - 420.0, 155.0),
+                        graphics,
+                    )
+                    .unwrap();
+                if !simulation.spawning() {
+                    text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
+                        .draw(
+                            "Spawning off",
+                            glyphs,
+                            &context.draw_state,
+                            context.transform.trans(WIDTH as f64 - 420.0, 115.0),
+                            graphics,
+                        )
+                        .unwrap();
+                }
+            }
+            if replay.is_some() {
+                text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
+                    .draw(
+                        format!(
+                            "Replay: {:.02}s ({:.02}s behind)",
+                            shown.time().as_secs_f64(),
+                            (simulation.time() - shown.time()).as_secs_f64()
+                        )
+                        .as_str(),
+                        glyphs,
+                        &context.draw_state,
+                        context.transform.trans(WIDTH as f64 - 420.0, 35.0),
+                        graphics,
+                    )
+                    .unwrap();
+            }
 
-            traffic_light.draw(&context, graphics);
-            traffic_light.draw_stats(&mut glyphs, &context, graphics);
-
-            cars.iter_mut()
-                .for_each(|car| car.draw(&cars_clone, &context, graphics));
-
-            text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
-                .draw(
-                    format!("Spawn increment: {:?}", spawn_increment).as_str(),
-                    &mut glyphs,
-                    &context.draw_state,
-                    context.transform.trans(20.0, 35.0),
-                    graphics,
-                )
-                .unwrap();
             glyphs.factory.encoder.flush(device);
         });
+        if finished && event.render_args().is_some() {
+            save_screenshot(&mut window, SCREENSHOT_FOLDER.unwrap());
+            break;
+        }
 
         event.button(|button| {
             if button.state != ButtonState::Press {
                 return;
             }
+            // Clicking selects the car under the cursor (or deselects on empty space)
+            if button.button == Button::Mouse(MouseButton::Left) {
+                let shown = replay.map_or(&simulation, |index| &history[index]);
+                selected = match &comparison {
+                    Some(comparison) => {
+                        let (side, point) = from_split_screen(cursor);
+                        selected_side = side;
+                        [shown, comparison][side].car_at(camera.to_map(point))
+                    }
+                    None => shown.car_at(camera.to_map(cursor)),
+                };
+            }
             if let Button::Keyboard(key) = button.button {
                 match key {
                     Key::Space => {
+                        paused = !paused;
+                        replay = None;
+                    }
+                    // Advance a single update while paused
+                    Key::Period => {
                         if paused {
-                            paused = false;
-                            traffic_light.unpause(last_paused.elapsed());
-                            spawn_start += last_paused.elapsed();
+                            simulation.step(TICK);
+                            if let Some(comparison) = &mut comparison {
+                                comparison.step(TICK);
+                            }
+                            replay = None;
+                        }
+                    }
+                    // Scrub through the saved states while paused (going past the newest one
+                    // goes back to the live simulation)
+                    // Only the left side has saved states, so there's no replay in split screen
+                    Key::Left if paused && comparison.is_none() => {
+                        replay = match replay {
+                            Some(index) => Some(index.saturating_sub(1)),
+                            None => history.len().checked_sub(1),
+                        };
+                    }
+                    Key::Right if paused && comparison.is_none() => {
+                        replay = replay.and_then(|index| {
+                            Some(index + 1).filter(|&index| index < history.len())
+                        });
+                    }
+                    Key::H => show_debug = !show_debug,
+                    Key::T => show_targets = !show_targets,
+                    Key::Q => dim_stopped = !dim_stopped,
+                    Key::F => render_skip = (render_skip + 1) % RENDER_SKIPS.len(),
+                    Key::C => {
+                        let policy = simulation.collision_policy().next();
+                        simulation.set_collision_policy(policy);
+                        if let Some(comparison) = &mut comparison {
+                            comparison.set_collision_policy(policy);
+                        }
+                    }
+                    Key::Minus | Key::NumPadMinus | Key::Equals | Key::Plus | Key::NumPadPlus => {
+                        let step = if matches!(key, Key::Minus | Key::NumPadMinus) {
+                            1.0 / DEMAND_SCALE_STEP
                         } else {
-                            last_paused = Instant::now();
-                            paused = true;
+                            DEMAND_SCALE_STEP
+                        };
+                        let scale = simulation.demand_scale() * step;
+                        simulation.set_demand_scale(scale);
+                        if let Some(comparison) = &mut comparison {
+                            comparison.set_demand_scale(scale);
+                        }
+                    }
+                    // Let the current traffic drain without new arrivals
+                    Key::S => {
+                        let spawning = !simulation.spawning();
+                        simulation.set_spawning(spawning);
+                        if let Some(comparison) = &mut comparison {
+                            comparison.set_spawning(spawning);
                         }
                     }
+                    Key::Escape => {
+                        if selected.is_some() {
+                            selected = None;
+                        } else {
+                            window.set_should_close(true);
+                        }
+                    }
+                    Key::Left => camera.pan((-1.0, 0.0)),
+                    Key::Right => camera.pan((1.0, 0.0)),
+                    Key::Up => camera.pan((0.0, -1.0)),
+                    Key::Down => camera.pan((0.0, 1.0)),
+                    Key::D0 => {
+                        selected = None;
+                        camera.reset();
+                    }
                     _ => (),
                 }
             };
@@ -0,0 +1,394 @@
+use piston_window::*;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    car::{self, Direction, Origin},
+    controller::{self, Controller, DrawController, Queues},
+    phase_log::PhaseEvent,
+    traffic_light::{SimplifiedCar, TrafficLight},
+    FAIRNESS_WEIGHT, INITIAL_GREEN,
+};
+
+/// Movements that are always given the green together
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseGroup {
+    pub name: &'static str,
+    pub movements: &'static [(Origin, Direction)],
+}
+
+impl PhaseGroup {
+    /// Straight and right turns of opposite approaches together, and each approach's left turns
+    /// and U-turns on their own (opposite left turns cross each other's path here)
+    pub const STANDARD: [PhaseGroup; 6] = [
+        PhaseGroup {
+            name: "North/South through",
+            movements: &[
+                (Origin::North, Direction::Straight),
+                (Origin::North, Direction::Right),
+                (Origin::South, Direction::Straight),
+                (Origin::South, Direction::Right),
+            ],
+        },
+        PhaseGroup {
+            name: "North left",
+            movements: &[
+                (Origin::North, Direction::Left),
+                (Origin::North, Direction::Right),
+                (Origin::North, Direction::UTurn),
+            ],
+        },
+        PhaseGroup {
+            name: "South left",
+            movements: &[
+                (Origin::South, Direction::Left),
+                (Origin::South, Direction::Right),
+                (Origin::South, Direction::UTurn),
+            ],
+        },
+        PhaseGroup {
+            name: "East/West through",
+            movements: &[
+                (Origin::East, Direction::Straight),
+                (Origin::East, Direction::Right),
+                (Origin::West, Direction::Straight),
+                (Origin::West, Direction::Right),
+            ],
+        },
+        PhaseGroup {
+            name: "East left",
+            movements: &[
+                (Origin::East, Direction::Left),
+                (Origin::East, Direction::Right),
+                (Origin::East, Direction::UTurn),
+            ],
+        },
+        PhaseGroup {
+            name: "West left",
+            movements: &[
+                (Origin::West, Direction::Left),
+                (Origin::West, Direction::Right),
+                (Origin::West, Direction::UTurn),
+            ],
+        },
+    ];
+
+    pub fn contains(&self, origin: Origin, direction: Direction) -> bool {
+        self.movements.contains(&(origin, direction))
+    }
+}
+
+/// Gives the green to whole phase groups instead of one approach at a time. Works like the light
+/// does with single approaches: the group with the most waiting cars takes over once it has
+/// clearly more than the current one, with a yellow and then an all red long enough for the last
+/// cars to clear in between.
+#[derive(Clone, Debug)]
+pub struct PhaseGroups {
+    groups: &'static [PhaseGroup],
+    /// All red time needed between the green of one group (first index) and the next (second)
+    clearances: Vec<Vec<Duration>>,
+    /// The group with the green, or that just had it while changing to the next one
+    current: usize,
+    next: Option<usize>,
+    /// When the current green started, or the change to the next one did
+    since: Duration,
+    /// When each group last had the green
+    last_served: Vec<Duration>,
+    queues: Queues,
+    /// How much each waiting car of a movement counts when comparing groups
+    movement_weights: HashMap<(Origin, Direction), f64>,
+    minimum_green_time: Duration,
+    yellow_times: [Duration; 4],
+    now: Duration,
+    phase_log: Vec<PhaseEvent>,
+    /// Cars removed without a car of their movement waiting
+    queue_underflows: usize,
+}
+
+impl PhaseGroups {
+    /// Serves the groups, starting with the first one (or the first one of `INITIAL_GREEN`). The
+    /// timing, the weights and the conflicts between movements come from the light.
+    pub fn new(groups: &'static [PhaseGroup], traffic_light: &TrafficLight) -> PhaseGroups {
+        let movements = TrafficLight::all_movements();
+        for group in groups {
+            for &(origin, direction) in group.movements {
+                for &(other_origin, other_direction) in group.movements {
+                    assert!(
+                        !traffic_light.conflicts(
+                            &SimplifiedCar::new(origin, direction),
+                            &SimplifiedCar::new(other_origin, other_direction)
+                        ),
+                        "Phase group {} has conflicting movements {:?} and {:?}",
+                        group.name,
+                        (origin, direction),
+                        (other_origin, other_direction)
+                    );
+                }
+            }
+        }
+        for movement in &movements {
+            assert!(
+                !car::movement_allowed(movement.origin, movement.direction)
+                    || groups
+                        .iter()
+                        .any(|group| group.contains(movement.origin, movement.direction)),
+                "{:?} isn't in any phase group",
+                movement.movement()
+            );
+        }
+
+        // The all red has to let every moving car of the last group clear before any waiting car
+        // of the next one can go
+        let clearances = groups
+            .iter()
+            .map(|from| {
+                groups
+                    .iter()
+                    .map(|to| {
+                        from.movements
+                            .iter()
+                            .flat_map(|&(origin, direction)| {
+                                to.movements.iter().map(move |&(waiting_origin, waiting)| {
+                                    (
+                                        SimplifiedCar::new(origin, direction),
+                                        SimplifiedCar::new(waiting_origin, waiting),
+                                    )
+                                })
+                            })
+                            .filter(|(moving_car, waiting_car)| {
+                                traffic_light.conflicts(moving_car, waiting_car)
+                            })
+                            .map(|(moving_car, waiting_car)| {
+                                traffic_light.conflict_delay(&moving_car, &waiting_car)
+                            })
+                            .max()
+                            .unwrap_or(Duration::ZERO)
+                    })
+                    .collect()
+            })
+            .collect();
+        let first = INITIAL_GREEN
+            .and_then(|origin| {
+                groups.iter().position(|group| {
+                    group
+                        .movements
+                        .iter()
+                        .any(|&(group_origin, _)| group_origin == origin)
+                })
+            })
+            .unwrap_or(0);
+
+        let mut phase_groups = PhaseGroups {
+            groups,
+            clearances,
+            current: first,
+            next: None,
+            since: Duration::ZERO,
+            last_served: vec![Duration::ZERO; groups.len()],
+            queues: Queues::default(),
+            movement_weights: movements
+                .iter()
+                .map(|car| {
+                    (
+                        car.movement(),
+                        traffic_light.movement_weight(car.origin, car.direction),
+                    )
+                })
+                .collect(),
+            minimum_green_time: traffic_light.minimum_green_time(),
+            yellow_times: traffic_light.yellow_times(),
+            now: Duration::ZERO,
+            phase_log: Vec::new(),
+            queue_underflows: 0,
+        };
+        phase_groups.log_phase();
+        phase_groups
+    }
+
+    /// Decides whether to change groups given the (weighted) cars waiting for each of them
+    fn choose_group(&mut self, demands: &[f64]) {
+        let now = self.now;
+        // The next group waits for the longest yellow of them all
+        let yellow_time = self.yellow_times.iter().copied().max().unwrap();
+        let elapsed = now.saturating_sub(self.since);
+        if let Some(next) = self.next {
+            if elapsed >= yellow_time + self.clearances[self.current][next] {
+                self.current = next;
+                self.next = None;
+                self.since = now;
+                self.last_served[next] = now;
+            }
+            return;
+        }
+
+        let current_demand = demands[self.current];
+        if elapsed < self.minimum_green_time && current_demand > 0.0 {
+            return;
+        }
+        let priority = |group: usize| {
+            let starved_time = now.saturating_sub(self.last_served[group]);
+            demands[group] * (1.0 + FAIRNESS_WEIGHT * starved_time.as_secs_f64())
+        };
+        // The first group wins ties, so they're always broken the same way
+        let best = (0..self.groups.len())
+            .filter(|&group| group != self.current && demands[group] > 0.0)
+            .fold(None, |best: Option<usize>, group| match best {
+                Some(best) if priority(best) >= priority(group) => Some(best),
+                _ => Some(group),
+            });
+        if let Some(best) = best {
+            if priority(best) >= (current_demand * 1.75).floor() {
+                self.next = Some(best);
+                self.since = now;
+            }
+        }
+    }
+
+    /// Returns the group that has the green, if it isn't changing groups
+    pub fn current_group(&self) -> Option<&PhaseGroup> {
+        match self.next {
+            Some(_) => None,
+            None => Some(&self.groups[self.current]),
+        }
+    }
+
+    /// Returns the group getting the green next while changing groups
+    pub fn next_group(&self) -> Option<&PhaseGroup> {
+        self.next.map(|next| &self.groups[next])
+    }
+
+    /// Records the green that just started
+    fn log_phase(&mut self) {
+        let group = &self.groups[self.current];
+        self.phase_log.push(PhaseEvent {
+            time: self.now,
+            phase: String::from(group.name),
+            movements: TrafficLight::all_movements()
+                .iter()
+                .filter(|car| group.contains(car.origin, car.direction))
+                .map(|car| car.movement())
+                .collect(),
+            queues: car::ORIGINS.map(|origin| self.queues.by_origin(origin)),
+            startup_lost_time: None,
+        });
+    }
+}
+
+impl Controller for PhaseGroups {
+    /// Moves the groups along, counting the cars that can go in each group's green: the ones at
+    /// the front of their approach until the first one that isn't in the group
+    fn update(&mut self, now: Duration) {
+        self.now = now;
+        let demands = self
+            .groups
+            .iter()
+            .map(|group| {
+                car::ORIGINS
+                    .iter()
+                    .map(|&origin| {
+                        self.queues
+                            .get(origin)
+                            .iter()
+                            .take_while(|car| group.contains(car.origin, car.direction))
+                            .map(|car| self.movement_weights[&car.movement()])
+                            .sum::<f64>()
+                    })
+                    .sum::<f64>()
+            })
+            .collect::<Vec<_>>();
+        let was_changing = self.next.is_some();
+        self.choose_group(&demands);
+        if was_changing && self.next.is_none() {
+            self.log_phase();
+        }
+    }
+
+    fn is_green(&self, origin: Origin, direction: Direction) -> bool {
+        self.current_group()
+            .map_or(false, |group| group.contains(origin, direction))
+    }
+
+    /// Cars never go on the yellow between groups
+    fn is_yellow(&self, _origin: Origin) -> bool {
+        false
+    }
+
+    /// Returns if the approach had a green in the group that's changing and it's still yellow
+    fn in_yellow(&self, origin: Origin) -> bool {
+        self.next.is_some()
+            && self.now.saturating_sub(self.since) < self.yellow_times[origin.index()]
+            && self.groups[self.current]
+                .movements
+                .iter()
+                .any(|&(group_origin, _)| group_origin == origin)
+    }
+
+    /// The greens go to movements, not whole approaches
+    fn green(&self) -> Option<Origin> {
+        None
+    }
+
+    fn add_car(&mut self, car: SimplifiedCar) {
+        self.queues.add(car);
+    }
+
+    fn remove_car(&mut self, origin: Origin, direction: Direction) {
+        if !self.queues.remove(origin, direction) {
+            self.queue_underflows += 1;
+        }
+    }
+
+    fn queue_underflows(&self) -> usize {
+        self.queue_underflows
+    }
+
+    fn queue_by_origin(&self, origin: Origin) -> usize {
+        self.queues.by_origin(origin)
+    }
+
+    fn queue_by_movement(&self, origin: Origin, direction: Direction) -> usize {
+        self.queues.by_movement(origin, direction)
+    }
+
+    /// The first car to do it during a green sets the green's startup lost time
+    fn left_stop_line(&mut self, origin: Origin, direction: Direction) {
+        if let Some(event) = self.phase_log.last_mut() {
+            if event.startup_lost_time.is_none() && event.movements.contains(&(origin, direction)) {
+                event.startup_lost_time = Some(self.now.saturating_sub(event.time));
+            }
+        }
+    }
+
+    fn phase_log(&self) -> &[PhaseEvent] {
+        &self.phase_log
+    }
+
+    fn box_clone(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
+
+impl DrawController for PhaseGroups {
+    fn draw(&self, context: &Context, graphics: &mut G2d) {
+        controller::draw_signals(self, context, graphics);
+    }
+
+    fn draw_stats(&self, glyphs: &mut Glyphs, context: &Context, graphics: &mut G2d) {
+        let line = format!(
+            "Phase group: {}",
+            match (self.current_group(), self.next_group()) {
+                (Some(group), _) => String::from(group.name),
+                (None, Some(next)) => format!("changing to {}", next.name),
+                (None, None) => String::from("-"),
+            }
+        );
+        text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
+            .draw(
+                line.as_str(),
+                glyphs,
+                &context.draw_state,
+                context.transform.trans(20.0, 75.0),
+                graphics,
+            )
+            .unwrap();
+    }
+}
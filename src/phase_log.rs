@@ -0,0 +1,49 @@
+use std::{fs, io, time::Duration};
+
+use crate::car::{Direction, Origin};
+
+/// A green starting, with what the queues looked like when the light decided on it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhaseEvent {
+    pub time: Duration,
+    /// The approach that got the green, or the phase group
+    pub phase: String,
+    /// Every movement that has a green right after the change
+    pub movements: Vec<(Origin, Direction)>,
+    /// Cars waiting on each approach (indexed by `Origin::index`)
+    pub queues: [usize; 4],
+    /// How long after the green started the first car that was at rest at its stop line crossed
+    /// it (the startup lost time), once one has
+    pub startup_lost_time: Option<Duration>,
+}
+
+/// Writes the events as CSV, one row per phase change. Movements are written like in light
+/// plans (`North:Straight`), separated by spaces. The startup lost time is left empty for greens
+/// no car went on from rest at the stop line.
+pub fn write_csv(events: &[PhaseEvent], path: &str) -> io::Result<()> {
+    let mut csv = String::from(
+        "time,phase,movements,queue_north,queue_south,queue_east,queue_west,startup_lost_time\n",
+    );
+    for event in events {
+        let movements = event
+            .movements
+            .iter()
+            .map(|(origin, direction)| format!("{:?}:{:?}", origin, direction))
+            .collect::<Vec<_>>()
+            .join(" ");
+        csv.push_str(&format!(
+            "{:.03},{},{},{},{},{},{},{}\n",
+            event.time.as_secs_f64(),
+            event.phase,
+            movements,
+            event.queues[0],
+            event.queues[1],
+            event.queues[2],
+            event.queues[3],
+            event
+                .startup_lost_time
+                .map_or(String::new(), |time| format!("{:.03}", time.as_secs_f64()))
+        ));
+    }
+    fs::write(path, csv)
+}
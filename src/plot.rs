@@ -0,0 +1,119 @@
+use piston_window::*;
+use std::{collections::VecDeque, time::Duration};
+
+use crate::PLOT_WINDOW;
+
+const WAITING_COLOR: [f32; 4] = [0.85, 0.1, 0.1, 1.0];
+const FINISHED_COLOR: [f32; 4] = [0.1, 0.3, 0.9, 1.0];
+
+/// How many cars were waiting and how many had finished over the last `PLOT_WINDOW` of simulated
+/// time, for a scrolling plot that shows if the queues are building up or swinging back and forth
+#[derive(Clone, Debug, Default)]
+pub struct LivePlot {
+    /// (time, waiting cars, finished cars) of every update, oldest first
+    samples: VecDeque<(Duration, usize, usize)>,
+}
+
+impl LivePlot {
+    /// Adds the update's sample and forgets the ones that scrolled out of the window
+    pub fn record(&mut self, time: Duration, waiting: usize, finished: usize) {
+        self.samples.push_back((time, waiting, finished));
+        while self.samples.front().map_or(false, |&(sample_time, _, _)| {
+            sample_time + PLOT_WINDOW < time
+        }) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Draws both curves in the given [x, y, width, height] rectangle. Waiting cars go from 0 to
+    /// the most in the window, finished cars from the fewest to the most in the window (only how
+    /// fast they go up matters).
+    pub fn draw(&self, rect: [f64; 4], glyphs: &mut Glyphs, context: &Context, graphics: &mut G2d) {
+        let [x, y, width, height] = rect;
+        rectangle([1.0, 1.0, 1.0, 0.7], rect, context.transform, graphics);
+        Rectangle::new_border([0.0, 0.0, 0.0, 1.0], 1.0).draw(
+            rect,
+            &context.draw_state,
+            context.transform,
+            graphics,
+        );
+
+        let (&(end, waiting, finished), &(_, _, first_finished)) =
+            match (self.samples.back(), self.samples.front()) {
+                (Some(last), Some(first)) => (last, first),
+                _ => return,
+            };
+        let start = end.saturating_sub(PLOT_WINDOW);
+        let max_waiting = self
+            .samples
+            .iter()
+            .map(|&(_, waiting, _)| waiting)
+            .max()
+            .unwrap();
+        let waiting_range = max_waiting.max(1) as f64;
+        let finished_range = (finished - first_finished).max(1) as f64;
+
+        let point = |time: Duration, value: f64| {
+            [
+                x + (time - start).as_secs_f64() / PLOT_WINDOW.as_secs_f64() * width,
+                y + height - value * height,
+            ]
+        };
+        // Skip samples so there's about one line per pixel
+        let step = (self.samples.len() as f64 / width).ceil().max(1.0) as usize;
+        let points: Vec<_> = self.samples.iter().step_by(step).collect();
+        for pair in points.windows(2) {
+            let (&(from_time, from_waiting, from_finished), &(to_time, to_waiting, to_finished)) =
+                (pair[0], pair[1]);
+            line_from_to(
+                WAITING_COLOR,
+                1.0,
+                point(from_time, from_waiting as f64 / waiting_range),
+                point(to_time, to_waiting as f64 / waiting_range),
+                context.transform,
+                graphics,
+            );
+            line_from_to(
+                FINISHED_COLOR,
+                1.0,
+                point(
+                    from_time,
+                    (from_finished - first_finished) as f64 / finished_range,
+                ),
+                point(
+                    to_time,
+                    (to_finished - first_finished) as f64 / finished_range,
+                ),
+                context.transform,
+                graphics,
+            );
+        }
+
+        let labels = [
+            (
+                WAITING_COLOR,
+                format!("Waiting: {} (max {})", waiting, max_waiting),
+            ),
+            (
+                FINISHED_COLOR,
+                format!(
+                    "Finished: {} (+{} in {}s)",
+                    finished,
+                    finished - first_finished,
+                    PLOT_WINDOW.as_secs()
+                ),
+            ),
+        ];
+        for (i, (color, label)) in labels.iter().enumerate() {
+            text::Text::new_color(*color, 16)
+                .draw(
+                    label,
+                    glyphs,
+                    &context.draw_state,
+                    context.transform.trans(x + 5.0, y + 18.0 + i as f64 * 20.0),
+                    graphics,
+                )
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use piston_window::*;
+use std::{fs, time::Duration};
+
+use crate::{
+    car::{Direction, Origin},
+    controller::{self, Controller, DrawController, Queues},
+    traffic_light::SimplifiedCar,
+};
+
+/// Plays a fixed light plan on a loop, ignoring the traffic.
+///
+/// Plans are text files with one step per line: the step's duration in seconds followed by the
+/// movements that have a green for it, e.g. `10 North:Straight North:Right South:Straight`.
+/// A step without movements is all red. Empty lines and lines starting with `#` are ignored.
+#[derive(Clone, Debug)]
+pub struct ScriptedController {
+    steps: Vec<(Vec<(Origin, Direction)>, Duration)>,
+    /// How long the whole plan takes
+    cycle: Duration,
+    now: Duration,
+    queues: Queues,
+    /// Cars removed without a car of their movement waiting
+    queue_underflows: usize,
+}
+
+impl ScriptedController {
+    pub fn new(steps: Vec<(Vec<(Origin, Direction)>, Duration)>) -> ScriptedController {
+        let cycle = steps.iter().map(|(_, duration)| *duration).sum();
+        ScriptedController {
+            steps,
+            cycle,
+            now: Duration::ZERO,
+            queues: Queues::default(),
+            queue_underflows: 0,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<ScriptedController, String> {
+        let contents = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        ScriptedController::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<ScriptedController, String> {
+        let mut steps = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let seconds: f64 = parts
+                .next()
+                .unwrap()
+                .parse()
+                .map_err(|_| format!("line {}: invalid duration", i + 1))?;
+            if seconds <= 0.0 || !seconds.is_finite() {
+                return Err(format!("line {}: duration must be positive", i + 1));
+            }
+            let movements = parts
+                .map(|movement| {
+                    parse_movement(movement)
+                        .ok_or_else(|| format!("line {}: invalid movement {}", i + 1, movement))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            steps.push((movements, Duration::from_secs_f64(seconds)));
+        }
+        if steps.is_empty() {
+            return Err(String::from("the plan has no steps"));
+        }
+        Ok(ScriptedController::new(steps))
+    }
+
+    /// Returns the movements that have a green at the given time
+    pub fn green_movements(&self, now: Duration) -> &[(Origin, Direction)] {
+        let mut time = Duration::from_nanos((now.as_nanos() % self.cycle.as_nanos()) as u64);
+        for (movements, duration) in &self.steps {
+            if time < *duration {
+                return movements;
+            }
+            time -= *duration;
+        }
+        &[]
+    }
+}
+
+impl Controller for ScriptedController {
+    fn update(&mut self, now: Duration) {
+        self.now = now;
+    }
+
+    fn is_green(&self, origin: Origin, direction: Direction) -> bool {
+        self.green_movements(self.now)
+            .contains(&(origin, direction))
+    }
+
+    /// Light plans go straight from green to red
+    fn is_yellow(&self, _origin: Origin) -> bool {
+        false
+    }
+
+    fn in_yellow(&self, _origin: Origin) -> bool {
+        false
+    }
+
+    /// The greens go to movements, not whole approaches
+    fn green(&self) -> Option<Origin> {
+        None
+    }
+
+    fn add_car(&mut self, car: SimplifiedCar) {
+        self.queues.add(car);
+    }
+
+    fn remove_car(&mut self, origin: Origin, direction: Direction) {
+        if !self.queues.remove(origin, direction) {
+            self.queue_underflows += 1;
+        }
+    }
+
+    fn queue_underflows(&self) -> usize {
+        self.queue_underflows
+    }
+
+    fn queue_by_origin(&self, origin: Origin) -> usize {
+        self.queues.by_origin(origin)
+    }
+
+    fn queue_by_movement(&self, origin: Origin, direction: Direction) -> usize {
+        self.queues.by_movement(origin, direction)
+    }
+
+    fn box_clone(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
+
+impl DrawController for ScriptedController {
+    fn draw(&self, context: &Context, graphics: &mut G2d) {
+        controller::draw_signals(self, context, graphics);
+    }
+}
+
+/// Parses a movement like `North:Left`
+fn parse_movement(movement: &str) -> Option<(Origin, Direction)> {
+    let (origin, direction) = movement.split_once(':')?;
+    let origin = match origin {
+        "North" => Origin::North,
+        "South" => Origin::South,
+        "East" => Origin::East,
+        "West" => Origin::West,
+        _ => return None,
+    };
+    let direction = match direction {
+        "Straight" => Direction::Straight,
+        "Left" => Direction::Left,
+        "Right" => Direction::Right,
+        "UTurn" => Direction::UTurn,
+        _ => return None,
+    };
+    Some((origin, direction))
+}
@@ -0,0 +1,1735 @@
+use piston_window::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use crate::{
+    box_log::BoxCrossing,
+    car::{self, Car, Driver, VehicleClass, ORIGINS},
+    conflicts::{ConflictCounts, ConflictLog},
+    controller::{self, Controller},
+    demand::{DemandProfile, DetectorData},
+    intersection::Occupancy,
+    phase_log::PhaseEvent,
+    plot::LivePlot,
+    sprites::Sprites,
+    traffic_light::{DemandSource, SimplifiedCar},
+    units, APPROACH_LENGTHS, APPROACH_SKEWS, ARRIVAL_RATES, COLLISION_PADDING, COLLISION_POLICY,
+    CONSERVATION_LOG, DEMAND_PROFILE, DEMAND_SCALE_RANGE, DEMAND_SOURCE, DRIVER_VARIABILITY,
+    END_CONDITION, HEIGHT, MIN_SPAWN_GAP, RECALL, REMOVE_STUCK_CARS, SEED, SHOW_GREEN_MOVEMENTS,
+    STOP_LINE_OFFSETS, STUCK_TIMEOUT, TURN_FRACTIONS, VEHICLE_MIX, WARM_UP, WIDTH,
+};
+
+/// Simulated time covered by a single update
+pub const TICK: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// When a simulation run is over
+#[derive(Clone, Copy, Debug)]
+pub enum EndCondition {
+    Never,
+    /// After this many cars have made it through the intersection
+    CarsFinished(usize),
+    /// After this much simulated time
+    Time(Duration),
+}
+
+/// What happens when two cars collide
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Flag the cars and keep going, so the run finishes and reports how many there were
+    Continue,
+    /// Stop to inspect it: the window pauses and headless runs end there
+    Halt,
+    /// Take the crashed cars off the map
+    Remove,
+}
+
+impl CollisionPolicy {
+    /// The policy after this one, for cycling through them
+    pub fn next(self) -> CollisionPolicy {
+        match self {
+            CollisionPolicy::Continue => CollisionPolicy::Halt,
+            CollisionPolicy::Halt => CollisionPolicy::Remove,
+            CollisionPolicy::Remove => CollisionPolicy::Continue,
+        }
+    }
+}
+
+/// The share of each vehicle class among spawned cars (relative weights, they don't need to add
+/// up to 1)
+#[derive(Clone, Copy, Debug)]
+pub struct VehicleMix {
+    pub car: f64,
+    pub truck: f64,
+    pub bus: f64,
+}
+
+impl VehicleMix {
+    fn sample(&self, rng: &mut StdRng) -> VehicleClass {
+        // Only cars: don't use up a random number so runs stay the same as without a mix
+        if self.truck <= 0.0 && self.bus <= 0.0 {
+            return VehicleClass::Car;
+        }
+        let (car, truck, bus) = (self.car.max(0.0), self.truck.max(0.0), self.bus.max(0.0));
+        let pick = rng.gen_range(0.0..car + truck + bus);
+        if pick < car {
+            VehicleClass::Car
+        } else if pick < car + truck {
+            VehicleClass::Truck
+        } else {
+            VehicleClass::Bus
+        }
+    }
+}
+
+/// Wait times of a group of cars, in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WaitStats {
+    pub mean: f64,
+    pub p50: f64,
+    pub p85: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl WaitStats {
+    /// Returns `None` without any waits
+    fn new(waits: &[Duration]) -> Option<WaitStats> {
+        if waits.is_empty() {
+            return None;
+        }
+        let mut sorted = waits.to_vec();
+        sorted.sort();
+        // Nearest rank: the shortest wait that the given share of the cars waited at most
+        let percentile = |share: f64| {
+            let rank = (share * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64()
+        };
+        let total: Duration = sorted.iter().sum();
+        Some(WaitStats {
+            mean: total.as_secs_f64() / sorted.len() as f64,
+            p50: percentile(0.5),
+            p85: percentile(0.85),
+            p95: percentile(0.95),
+            max: sorted.last().unwrap().as_secs_f64(),
+        })
+    }
+}
+
+impl fmt::Display for WaitStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.02}s mean {:.02}s p50 {:.02}s p85 {:.02}s p95 {:.02}s max",
+            self.mean, self.p50, self.p85, self.p95, self.max
+        )
+    }
+}
+
+/// Running totals of a simulation, to take the warm-up's off the summary
+#[derive(Clone, Default, Debug, PartialEq)]
+struct Totals {
+    /// Finished cars of each approach
+    cars_finished: [usize; 4],
+    stops: usize,
+    violations: usize,
+    collisions: usize,
+    near_misses: usize,
+    conflicts: ConflictCounts,
+    yellow_traps: usize,
+    dilemma_zone_extensions: usize,
+    box_holds: usize,
+    conflicting_greens: usize,
+    stuck_cars: usize,
+    /// How long each approach has had a green (not counting the yellow)
+    green_time: [Duration; 4],
+    /// How much of it one of the approach's cars was crossing the intersection
+    used_green_time: [Duration; 4],
+    /// Greens each approach has had that started with cars waiting (and are over)
+    greens: [usize; 4],
+    /// Greens that ended with cars that were waiting when they started still waiting
+    cycle_failures: [usize; 4],
+}
+
+impl Totals {
+    /// Returns the share of each approach's green since `start` that one of its cars was crossing
+    /// the intersection (`None` for approaches that had no green)
+    fn green_utilization(&self, start: &Totals) -> [Option<f64>; 4] {
+        ORIGINS.map(|origin| {
+            let i = origin.index();
+            let green = self.green_time[i].saturating_sub(start.green_time[i]);
+            let used = self.used_green_time[i].saturating_sub(start.used_green_time[i]);
+            if green.is_zero() {
+                None
+            } else {
+                Some(used.as_secs_f64() / green.as_secs_f64())
+            }
+        })
+    }
+}
+
+/// Results of a simulation run (leaving out the `WARM_UP`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub time: Duration,
+    pub cars_finished: usize,
+    /// Finished cars per hour of simulated time
+    pub throughput: f64,
+    pub waits: Vec<(car::Origin, WaitStats)>,
+    /// Waits of every finished car, whatever its approach
+    pub overall_wait: Option<WaitStats>,
+    /// Average times a finished car came to a stop
+    pub stops_per_car: f64,
+    /// Cars that crossed the stop line when they should have stopped
+    pub violations: usize,
+    pub collisions: usize,
+    /// Times two cars got within `COLLISION_PADDING` of each other without colliding
+    pub near_misses: usize,
+    /// Pairs of finished cars whose paths crossed within `CONFLICT_WINDOW` of each other
+    pub conflicts: ConflictCounts,
+    /// Times a left turner got a yellow while the oncoming traffic kept its short green
+    pub yellow_traps: usize,
+    /// Greens held for a car in the dilemma zone
+    pub dilemma_zone_extensions: usize,
+    /// Times a finished car waited at a green for room past the intersection
+    pub box_holds: usize,
+    /// Updates that ended with conflicting movements green at once
+    pub conflicting_greens: usize,
+    /// Cars that went too long without getting anywhere for no reason
+    pub stuck_cars: usize,
+    /// Share of each approach's green one of its cars was crossing the intersection (the rest of
+    /// it went unused), for the approaches that had a green
+    pub green_utilization: Vec<(car::Origin, f64)>,
+    /// Mean time from the start of a green to the first car at rest at the stop line crossing
+    /// it, over the greens such a car went on
+    pub startup_lost_time: Option<Duration>,
+    /// Greens of each approach that didn't get through every car that was waiting when they
+    /// started, out of its greens that started with cars waiting (for the approaches that had
+    /// one)
+    pub cycle_failures: Vec<(car::Origin, usize, usize)>,
+}
+
+impl Summary {
+    /// Columns of `csv_row`
+    pub const CSV_HEADER: &'static str = "time,cars_finished,throughput,violations,collisions,near_misses,yellow_traps,dilemma_zone_extensions,box_holds,conflicting_greens,stuck_cars,wait_mean,wait_p50,wait_p85,wait_p95,wait_max,stops_per_car,startup_lost_time,cycle_failures,conflicts_safe,conflicts_marginal,conflicts_collision";
+
+    /// Returns the summary as a line of CSV (without the per-approach waits). The waits are left
+    /// empty if no car finished.
+    pub fn csv_row(&self) -> String {
+        let waits = match &self.overall_wait {
+            Some(wait) => format!(
+                "{:.03},{:.03},{:.03},{:.03},{:.03}",
+                wait.mean, wait.p50, wait.p85, wait.p95, wait.max
+            ),
+            None => String::from(",,,,"),
+        };
+        format!(
+            "{:.03},{},{:.01},{},{},{},{},{},{},{},{},{},{:.03},{},{},{},{},{}",
+            self.time.as_secs_f64(),
+            self.cars_finished,
+            self.throughput,
+            self.violations,
+            self.collisions,
+            self.near_misses,
+            self.yellow_traps,
+            self.dilemma_zone_extensions,
+            self.box_holds,
+            self.conflicting_greens,
+            self.stuck_cars,
+            waits,
+            self.stops_per_car,
+            self.startup_lost_time
+                .map_or(String::new(), |time| format!("{:.03}", time.as_secs_f64())),
+            self.cycle_failures
+                .iter()
+                .map(|(_, failures, _)| failures)
+                .sum::<usize>(),
+            self.conflicts.safe,
+            self.conflicts.marginal,
+            self.conflicts.collision
+        )
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "time: {:.02}s, finished: {}, throughput: {:.01} cars/h, violations: {}, collisions: {}, near misses: {}, conflicts: {}, yellow traps: {}, dilemma zone extensions: {}, box holds: {}, conflicting greens: {}, stuck cars: {}",
+            self.time.as_secs_f64(),
+            self.cars_finished,
+            self.throughput,
+            self.violations,
+            self.collisions,
+            self.near_misses,
+            self.conflicts,
+            self.yellow_traps,
+            self.dilemma_zone_extensions,
+            self.box_holds,
+            self.conflicting_greens,
+            self.stuck_cars
+        )?;
+        if let Some(wait) = &self.overall_wait {
+            write!(f, ", wait: {}", wait)?;
+        }
+        write!(f, ", stops per car: {:.02}", self.stops_per_car)?;
+        if let Some(time) = self.startup_lost_time {
+            write!(f, ", startup lost time: {:.02}s", time.as_secs_f64())?;
+        }
+        for (origin, wait) in &self.waits {
+            write!(f, ", {:?} wait: {}", origin, wait)?;
+        }
+        for (origin, utilization) in &self.green_utilization {
+            write!(f, ", {:?} green used: {:.0}%", origin, utilization * 100.0)?;
+        }
+        for (origin, failures, greens) in &self.cycle_failures {
+            write!(
+                f,
+                ", {:?} cycle failures: {} of {} greens with a queue",
+                origin, failures, greens
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Simulation {
+    cars: Vec<Car>,
+    traffic_light: Box<dyn Controller>,
+    /// Cars in the intersection, for `STRICT_SINGLE_CAR`
+    occupancy: Occupancy,
+    /// Id of the next car to spawn. Ids are never reused, so one always means the same car.
+    id: usize,
+    rng: StdRng,
+    /// Simulated time since the start of the simulation
+    time: Duration,
+    spawn_start: Duration,
+    spawn_increment: Duration,
+    origin_index: usize,
+    /// Arrival rate of each approach, limited to what can physically spawn (Poisson arrivals)
+    arrival_rates: [f64; 4],
+    /// What the arrival rates (or the ramp's spawn rate) are multiplied by, changed while running
+    demand_scale: f64,
+    /// When the next car of each approach arrives (Poisson arrivals and platoons)
+    next_arrivals: Vec<Duration>,
+    /// When each approach's cars arrive, oldest first (replayed detector data)
+    scheduled_arrivals: [VecDeque<Duration>; 4],
+    /// How many platoons have started on each approach
+    platoons: [usize; 4],
+    /// Cars still to arrive in each approach's current platoon
+    platoon_left: [usize; 4],
+    /// Share of each approach's cars making each movement (see `normalize_turn_fractions`)
+    turn_fractions: [[f64; 4]; 4],
+    /// Approaches where left turning cars are spilling out of the turn bay
+    spillover: Vec<car::Origin>,
+    /// How long each finished car waited, by approach
+    wait_times: HashMap<car::Origin, Vec<Duration>>,
+    /// Stops made by every finished car
+    stops: usize,
+    /// Times every finished car waited at a green for room past the intersection
+    box_holds: usize,
+    /// When every finished car crossed the stop line and entered and cleared the box
+    box_crossings: Vec<BoxCrossing>,
+    /// Compares every finished car with the ones before it whose paths cross its own
+    conflicts: ConflictLog,
+    /// If new cars arrive and spawn (turned off to let the traffic drain)
+    spawning: bool,
+    /// Cars that have arrived but can't spawn yet because the start of their approach is full
+    pending: HashMap<car::Origin, VecDeque<(SimplifiedCar, VehicleClass, Option<usize>)>>,
+    violations: usize,
+    collisions: usize,
+    near_misses: usize,
+    /// Cars the watchdog found stuck
+    stuck_cars: usize,
+    /// How long each approach has had a green (not counting the yellow)
+    green_time: [Duration; 4],
+    /// How much of it one of the approach's cars was crossing the intersection
+    used_green_time: [Duration; 4],
+    /// Ids of the cars that were waiting on each approach when its green started (`None` while it
+    /// doesn't have one)
+    green_queues: [Option<Vec<usize>>; 4],
+    /// Greens each approach has had that started with cars waiting (and are over)
+    greens: [usize; 4],
+    /// Greens that ended with cars that were waiting when they started still waiting
+    cycle_failures: [usize; 4],
+    /// Cars that spawned onto the map, by approach
+    inflow: [usize; 4],
+    /// Stuck (`REMOVE_STUCK_CARS`) and crashed (`CollisionPolicy::Remove`) cars taken off the
+    /// map, by approach
+    removed: [usize; 4],
+    /// When the next `CONSERVATION_LOG` line is due
+    next_conservation_log: Duration,
+    /// Totals at the end of the `WARM_UP` (so far, while it's still going)
+    warm_up: Totals,
+    /// Waiting and finished cars over the last `PLOT_WINDOW`, for the debug overlay
+    plot: LivePlot,
+    collision_policy: CollisionPolicy,
+    /// If the last update had a new collision while the policy is `CollisionPolicy::Halt`
+    halted: bool,
+    /// Ids of the pairs of cars currently on top of each other
+    colliding: HashSet<(usize, usize)>,
+    /// Ids of the pairs of cars currently within the collision padding of each other (but not
+    /// colliding)
+    near: HashSet<(usize, usize)>,
+}
+
+impl Simulation {
+    pub fn new() -> Simulation {
+        Simulation::with_controller(controller::from_config(|_| ()))
+    }
+
+    /// A simulation as configured, with the given controller running the light
+    pub fn with_controller(traffic_light: Box<dyn Controller>) -> Simulation {
+        Simulation::with_seed(traffic_light, SEED)
+    }
+
+    /// A simulation as configured but with its randomness seeded from `seed` instead of `SEED`,
+    /// with the given controller running the light
+    pub fn with_seed(traffic_light: Box<dyn Controller>, seed: u64) -> Simulation {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let max_rate = 3600.0 / min_spawn_interval().as_secs_f64();
+        let mut arrival_rates = ARRIVAL_RATES;
+        for (origin, rate) in ORIGINS.iter().zip(arrival_rates.iter_mut()) {
+            if DEMAND_PROFILE != DemandProfile::Ramp && *rate > max_rate {
+                eprintln!(
+                    "Warning: {:?} arrival rate of {} cars/h is more than the {:.0} cars/h that can spawn, using {:.0} cars/h",
+                    origin, rate, max_rate, max_rate
+                );
+                *rate = max_rate;
+            }
+        }
+        if let DemandProfile::Platoons { size, .. } = DEMAND_PROFILE {
+            assert!(size > 0, "Platoons need at least one car");
+        }
+        let next_arrivals = arrival_rates
+            .iter()
+            .map(|&rate| {
+                if rate > 0.0 {
+                    sample_inter_arrival(&mut rng, rate / platoon_size() as f64)
+                } else {
+                    Duration::ZERO
+                }
+            })
+            .collect();
+
+        let scheduled_arrivals = match DEMAND_PROFILE {
+            DemandProfile::DetectorData(path) => {
+                let detector_data = DetectorData::load(path)
+                    .unwrap_or_else(|err| panic!("Couldn't load the detector data: {}", err));
+                ORIGINS.map(|origin| detector_data.arrivals(origin, &mut rng))
+            }
+            _ => Default::default(),
+        };
+
+        let turn_fractions = normalize_turn_fractions(TURN_FRACTIONS)
+            .unwrap_or_else(|err| panic!("Invalid TURN_FRACTIONS: {}", err));
+
+        for origin in ORIGINS {
+            let length = APPROACH_LENGTHS[origin.index()];
+            let stop_line_offset = STOP_LINE_OFFSETS[origin.index()];
+            assert!(
+                length >= stop_line_offset + VehicleClass::Bus.length(),
+                "The {:?} approach ({}) is too short to fit a bus before its stop line ({})",
+                origin,
+                length,
+                stop_line_offset
+            );
+        }
+
+        Simulation {
+            cars: Vec::new(),
+            traffic_light,
+            occupancy: Occupancy::default(),
+            id: 0,
+            rng,
+            time: Duration::ZERO,
+            spawn_start: Duration::ZERO,
+            spawn_increment: Duration::from_millis(1000),
+            origin_index: 0,
+            arrival_rates,
+            demand_scale: 1.0,
+            next_arrivals,
+            scheduled_arrivals,
+            platoons: [0; 4],
+            platoon_left: [0; 4],
+            turn_fractions,
+            spillover: Vec::new(),
+            wait_times: HashMap::new(),
+            stops: 0,
+            box_holds: 0,
+            box_crossings: Vec::new(),
+            conflicts: ConflictLog::default(),
+            spawning: true,
+            pending: ORIGINS.iter().map(|&o| (o, VecDeque::new())).collect(),
+            violations: 0,
+            collisions: 0,
+            near_misses: 0,
+            stuck_cars: 0,
+            inflow: [0; 4],
+            removed: [0; 4],
+            green_time: [Duration::ZERO; 4],
+            used_green_time: [Duration::ZERO; 4],
+            green_queues: Default::default(),
+            greens: [0; 4],
+            cycle_failures: [0; 4],
+            collision_policy: COLLISION_POLICY,
+            halted: false,
+            next_conservation_log: CONSERVATION_LOG.unwrap_or(Duration::ZERO),
+            warm_up: Totals::default(),
+            plot: LivePlot::default(),
+            colliding: HashSet::new(),
+            near: HashSet::new(),
+        }
+    }
+
+    /// Advances the simulation by a single update. This is the only thing that moves the
+    /// simulation forward, so the window and headless mode have to both go through it (with
+    /// `TICK`) for a seed to always give the same run.
+    pub fn step(&mut self, dt: Duration) {
+        self.time += dt;
+
+        self.spillover = car::fill_turn_bays(&mut self.cars);
+        let cars_clone = self.cars.clone();
+
+        let mut dilemma_zones = [false; 4];
+        for car in self.cars.iter().filter(|car| car.in_dilemma_zone()) {
+            dilemma_zones[car.origin.index()] = true;
+        }
+        self.traffic_light.detect_dilemma_zones(dilemma_zones);
+        self.traffic_light.update(self.time);
+
+        if self.spawning {
+            self.spawn();
+            self.release_pending();
+        }
+        self.traffic_light.detect_backed_up(ORIGINS.map(|origin| {
+            self.pending[&origin]
+                .iter()
+                .map(|&(car, _, _)| car)
+                .collect()
+        }));
+        self.traffic_light.start_first_green();
+
+        self.cars.iter_mut().for_each(|car| {
+            car.update(
+                &cars_clone,
+                self.traffic_light.as_mut(),
+                &mut self.occupancy,
+                dt,
+            );
+        });
+
+        self.detect_collisions();
+        self.check_stuck_cars();
+        self.measure_green_use(dt);
+
+        self.check_queues();
+
+        for i in (0..self.cars.len()).rev() {
+            if self.cars[i].finished {
+                let car = self.cars.remove(i);
+                if car.ran_red() {
+                    self.violations += 1;
+                }
+                self.wait_times
+                    .entry(car.origin)
+                    .or_default()
+                    .push(TICK * car.wait_ticks());
+                self.stops += car.stops() as usize;
+                self.box_holds += car.box_holds() as usize;
+                self.box_crossings.push(self.box_crossing(&car));
+                self.conflicts.record(&car, self.time);
+            }
+        }
+
+        self.check_conservation();
+        let waiting = self.cars.iter().filter(|car| car.state().stopped).count();
+        self.plot.record(self.time, waiting, self.cars_finished());
+
+        if self.time < WARM_UP {
+            self.warm_up = self.totals();
+        }
+    }
+
+    fn totals(&self) -> Totals {
+        Totals {
+            cars_finished: ORIGINS.map(|origin| self.outflow(origin)),
+            stops: self.stops,
+            violations: self.violations,
+            collisions: self.collisions,
+            near_misses: self.near_misses,
+            conflicts: self.conflicts.counts(),
+            yellow_traps: self.traffic_light.yellow_traps(),
+            dilemma_zone_extensions: self.traffic_light.dilemma_zone_extensions(),
+            box_holds: self.box_holds,
+            conflicting_greens: self.traffic_light.conflicting_greens(),
+            stuck_cars: self.stuck_cars,
+            green_time: self.green_time,
+            used_green_time: self.used_green_time,
+            greens: self.greens,
+            cycle_failures: self.cycle_failures,
+        }
+    }
+
+    /// Adds the update to the green time of the approaches with a green, and to their used green
+    /// time if one of their cars is crossing the intersection. Greens that end with a car that
+    /// was already waiting when they started still short of the stop line count as cycle
+    /// failures.
+    fn measure_green_use(&mut self, dt: Duration) {
+        for origin in ORIGINS {
+            let green = (0..=3).map(car::Direction::from).any(|direction| {
+                car::movement_allowed(origin, direction)
+                    && self.traffic_light.is_green(origin, direction)
+            }) && !self.traffic_light.is_yellow(origin);
+            let i = origin.index();
+            match (green, &self.green_queues[i]) {
+                (true, None) => {
+                    self.green_queues[i] = Some(
+                        self.cars
+                            .iter()
+                            .filter(|car| {
+                                car.origin == origin
+                                    && car.state().stopped
+                                    && !car.through_intersection()
+                            })
+                            .map(|car| car.id)
+                            .collect(),
+                    );
+                }
+                (false, Some(queue)) => {
+                    let failed = self
+                        .cars
+                        .iter()
+                        .any(|car| queue.contains(&car.id) && !car.through_intersection());
+                    if !queue.is_empty() {
+                        self.greens[i] += 1;
+                    }
+                    if failed {
+                        self.cycle_failures[i] += 1;
+                    }
+                    self.green_queues[i] = None;
+                }
+                _ => (),
+            }
+            if !green {
+                continue;
+            }
+            self.green_time[origin.index()] += dt;
+            if self
+                .cars
+                .iter()
+                .any(|car| car.origin == origin && car.crossing())
+            {
+                self.used_green_time[origin.index()] += dt;
+            }
+        }
+    }
+
+    /// Returns how many cars that came from the approach finished their trip
+    fn outflow(&self, origin: car::Origin) -> usize {
+        self.wait_times.get(&origin).map_or(0, |waits| waits.len())
+    }
+
+    /// Makes sure every car that spawned is either still on the map, finished or was taken off
+    /// for being stuck, and logs the counts every `CONSERVATION_LOG`
+    fn check_conservation(&mut self) {
+        for origin in ORIGINS {
+            debug_assert_eq!(
+                self.inflow[origin.index()],
+                self.outflow(origin)
+                    + self.removed[origin.index()]
+                    + self.cars.iter().filter(|car| car.origin == origin).count(),
+                "{:?} lost or double counted cars",
+                origin
+            );
+        }
+
+        let interval = match CONSERVATION_LOG {
+            Some(interval) if self.time >= self.next_conservation_log => interval,
+            _ => return,
+        };
+        self.next_conservation_log += interval;
+        let counts = ORIGINS
+            .iter()
+            .map(|&origin| {
+                format!(
+                    "{:?} in {} out {} removed {} on map {}",
+                    origin,
+                    self.inflow[origin.index()],
+                    self.outflow(origin),
+                    self.removed[origin.index()],
+                    self.cars.iter().filter(|car| car.origin == origin).count()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        eprintln!("Flow at {:.02}s: {}", self.time.as_secs_f64(), counts);
+    }
+
+    /// Makes sure the light's queues match the cars that are actually waiting at it
+    fn check_queues(&self) {
+        for origin in ORIGINS {
+            let directions = (0..=3).map(car::Direction::from);
+            debug_assert_eq!(
+                directions
+                    .clone()
+                    .map(|direction| self.waiting_cars(origin, direction))
+                    .sum::<usize>(),
+                self.traffic_light.queue_by_origin(origin),
+                "{:?} queue out of sync with its cars",
+                origin
+            );
+            for direction in directions {
+                debug_assert_eq!(
+                    self.waiting_cars(origin, direction),
+                    self.traffic_light.queue_by_movement(origin, direction),
+                    "{:?} {:?} queue out of sync with its cars",
+                    origin,
+                    direction
+                );
+            }
+        }
+    }
+
+    /// Returns how many cars making the movement are on the map and haven't gone through the
+    /// intersection yet
+    fn waiting_cars(&self, origin: car::Origin, direction: car::Direction) -> usize {
+        self.cars
+            .iter()
+            .filter(|car| {
+                car.origin == origin && car.direction() == direction && !car.through_intersection()
+            })
+            .count()
+    }
+
+    /// Logs cars that went too long without getting anywhere for no reason, since that means
+    /// something is wrong with the car logic, and takes them off the map if `REMOVE_STUCK_CARS`
+    fn check_stuck_cars(&mut self) {
+        let timeout_ticks = (STUCK_TIMEOUT.as_secs_f64() / TICK.as_secs_f64()).ceil() as u32;
+        for i in (0..self.cars.len()).rev() {
+            if self.cars[i].stuck_ticks() != timeout_ticks {
+                continue;
+            }
+            self.stuck_cars += 1;
+            eprintln!(
+                "Warning: car stuck for {:.02}s at {:.02}s: {:?}",
+                STUCK_TIMEOUT.as_secs_f64(),
+                self.time.as_secs_f64(),
+                self.cars[i].state()
+            );
+            if REMOVE_STUCK_CARS {
+                let mut car = self.cars.remove(i);
+                car.release(self.traffic_light.as_mut(), &mut self.occupancy);
+                self.removed[car.origin.index()] += 1;
+            }
+        }
+    }
+
+    /// Flags the cars that are on top of another car and counts every new pair of them, and of
+    /// cars that got too close (a near miss)
+    fn detect_collisions(&mut self) {
+        let mut colliding = HashSet::new();
+        let mut near = HashSet::new();
+        for (i, car) in self.cars.iter().enumerate() {
+            for other in self.cars.iter().skip(i + 1) {
+                if car.overlaps(other) {
+                    colliding.insert((car.id, other.id));
+                } else if COLLISION_PADDING > 0.0 && car.near(other) {
+                    near.insert((car.id, other.id));
+                }
+            }
+        }
+        self.near_misses += near
+            .iter()
+            .filter(|pair| !self.near.contains(pair) && !self.colliding.contains(pair))
+            .count();
+        self.near = near;
+        for car in &mut self.cars {
+            car.set_colliding(
+                colliding
+                    .iter()
+                    .any(|&(first, second)| car.id == first || car.id == second),
+            );
+        }
+        let new_collisions = colliding.difference(&self.colliding).count();
+        self.collisions += new_collisions;
+        self.halted = self.collision_policy == CollisionPolicy::Halt && new_collisions > 0;
+        if self.halted {
+            eprintln!("Halted on a collision at {:.02}s", self.time.as_secs_f64());
+        }
+        if self.collision_policy == CollisionPolicy::Remove {
+            for i in (0..self.cars.len()).rev() {
+                if self.cars[i].colliding() {
+                    let mut car = self.cars.remove(i);
+                    car.release(self.traffic_light.as_mut(), &mut self.occupancy);
+                    self.removed[car.origin.index()] += 1;
+                }
+            }
+        }
+        self.colliding = colliding;
+    }
+
+    /// Returns true if the last update had a collision and the policy is to stop there
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn collision_policy(&self) -> CollisionPolicy {
+        self.collision_policy
+    }
+
+    pub fn set_collision_policy(&mut self, collision_policy: CollisionPolicy) {
+        self.collision_policy = collision_policy;
+    }
+
+    /// Returns the simulated time since the start of the simulation
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    pub fn spawning(&self) -> bool {
+        self.spawning
+    }
+
+    pub fn demand_scale(&self) -> f64 {
+        self.demand_scale
+    }
+
+    /// Sets what the configured demand is multiplied by, within `DEMAND_SCALE_RANGE`. Cars still
+    /// can't arrive closer together than `min_spawn_interval`, and the ones that arrive while
+    /// their approach is full wait to spawn like always.
+    pub fn set_demand_scale(&mut self, scale: f64) {
+        self.demand_scale = scale.clamp(DEMAND_SCALE_RANGE.0, DEMAND_SCALE_RANGE.1);
+    }
+
+    /// Stops or resumes new cars arriving. While stopped the arrival times keep moving (and
+    /// replayed cars that would have arrived are skipped), so there's no burst of cars once it
+    /// resumes.
+    pub fn set_spawning(&mut self, spawning: bool) {
+        if spawning && !self.spawning {
+            self.spawn_start = self.time;
+            for next_arrival in &mut self.next_arrivals {
+                *next_arrival = (*next_arrival).max(self.time);
+            }
+            for arrivals in &mut self.scheduled_arrivals {
+                while arrivals
+                    .front()
+                    .map_or(false, |&arrival| arrival < self.time)
+                {
+                    arrivals.pop_front();
+                }
+            }
+        }
+        self.spawning = spawning;
+    }
+
+    /// Returns true once the end condition has been reached
+    pub fn finished(&self) -> bool {
+        match END_CONDITION {
+            EndCondition::Never => false,
+            EndCondition::CarsFinished(cars) => self.cars_finished() >= cars,
+            EndCondition::Time(time) => self.time >= time,
+        }
+    }
+
+    /// Returns the average speed (in pixels per update) of the cars on the map
+    pub fn average_speed(&self) -> f64 {
+        if self.cars.is_empty() {
+            return 0.0;
+        }
+        let total: f64 = self.cars.iter().map(|car| car.state().speed).sum();
+        total / self.cars.len() as f64
+    }
+
+    pub fn cars_finished(&self) -> usize {
+        self.wait_times.values().map(|waits| waits.len()).sum()
+    }
+
+    /// Converts the car's box times from its age to simulated time (it was last updated this
+    /// step)
+    fn box_crossing(&self, car: &Car) -> BoxCrossing {
+        let time = |age: Option<u32>| age.map(|age| self.time - TICK * (car.age_ticks() - age));
+        let (crossed_stop_line, entered_box, cleared_box) = car.box_times();
+        BoxCrossing {
+            id: car.id,
+            origin: car.origin,
+            direction: car.direction(),
+            crossed_stop_line: time(crossed_stop_line),
+            entered_box: time(entered_box),
+            cleared_box: time(cleared_box),
+        }
+    }
+
+    /// Returns when every finished car crossed the stop line and entered and cleared the box
+    pub fn box_crossings(&self) -> &[BoxCrossing] {
+        &self.box_crossings
+    }
+
+    /// Returns the results of the run so far, without the cars that finished (and anything that
+    /// happened) during the `WARM_UP`
+    pub fn summary(&self) -> Summary {
+        // Cars finish in order, so the warm-up's are the first of each approach
+        let measured_waits = |origin: &car::Origin| -> &[Duration] {
+            match self.wait_times.get(origin) {
+                Some(waits) => &waits[self.warm_up.cars_finished[origin.index()]..],
+                None => &[],
+            }
+        };
+        let waits = ORIGINS
+            .iter()
+            .filter_map(|origin| Some((*origin, WaitStats::new(measured_waits(origin))?)))
+            .collect();
+        let all_waits = ORIGINS
+            .iter()
+            .flat_map(measured_waits)
+            .copied()
+            .collect::<Vec<_>>();
+        let totals = self.totals();
+        let warm_up = &self.warm_up;
+        let startup_lost_times = self
+            .phase_history()
+            .iter()
+            .filter(|event| event.time >= WARM_UP)
+            .filter_map(|event| event.startup_lost_time)
+            .collect::<Vec<_>>();
+
+        Summary {
+            time: self.time,
+            cars_finished: all_waits.len(),
+            throughput: units::vehicles_per_hour(
+                all_waits.len(),
+                self.time.saturating_sub(WARM_UP),
+            ),
+            waits,
+            overall_wait: WaitStats::new(&all_waits),
+            stops_per_car: if all_waits.is_empty() {
+                0.0
+            } else {
+                (totals.stops - warm_up.stops) as f64 / all_waits.len() as f64
+            },
+            violations: totals.violations - warm_up.violations,
+            collisions: totals.collisions - warm_up.collisions,
+            near_misses: totals.near_misses - warm_up.near_misses,
+            conflicts: totals.conflicts.since(&warm_up.conflicts),
+            yellow_traps: totals.yellow_traps - warm_up.yellow_traps,
+            dilemma_zone_extensions: totals.dilemma_zone_extensions
+                - warm_up.dilemma_zone_extensions,
+            box_holds: totals.box_holds - warm_up.box_holds,
+            conflicting_greens: totals.conflicting_greens - warm_up.conflicting_greens,
+            stuck_cars: totals.stuck_cars - warm_up.stuck_cars,
+            green_utilization: ORIGINS
+                .into_iter()
+                .zip(totals.green_utilization(warm_up))
+                .filter_map(|(origin, utilization)| Some((origin, utilization?)))
+                .collect(),
+            startup_lost_time: if startup_lost_times.is_empty() {
+                None
+            } else {
+                Some(startup_lost_times.iter().sum::<Duration>() / startup_lost_times.len() as u32)
+            },
+            cycle_failures: ORIGINS
+                .into_iter()
+                .map(|origin| {
+                    let i = origin.index();
+                    (
+                        origin,
+                        totals.cycle_failures[i] - warm_up.cycle_failures[i],
+                        totals.greens[i] - warm_up.greens[i],
+                    )
+                })
+                .filter(|&(_, _, greens)| greens > 0)
+                .collect(),
+        }
+    }
+
+    fn spawn(&mut self) {
+        if let DemandProfile::DetectorData(_) = DEMAND_PROFILE {
+            for i in 0..ORIGINS.len() {
+                while self.scheduled_arrivals[i]
+                    .front()
+                    .map_or(false, |&arrival| arrival <= self.time)
+                {
+                    self.scheduled_arrivals[i].pop_front();
+                    if let Some(direction) = self.random_direction(ORIGINS[i]) {
+                        self.arrive(ORIGINS[i], direction, None);
+                    }
+                }
+            }
+        } else if DEMAND_PROFILE != DemandProfile::Ramp {
+            for i in 0..ORIGINS.len() {
+                if self.arrival_rates[i] <= 0.0 {
+                    continue;
+                }
+                while self.next_arrivals[i] <= self.time {
+                    let platoon = self.join_platoon(i);
+                    if let Some(direction) = self.random_direction(ORIGINS[i]) {
+                        self.arrive(ORIGINS[i], direction, platoon);
+                    }
+                    let inter_arrival = self.next_inter_arrival(i);
+                    self.next_arrivals[i] += inter_arrival;
+                }
+            }
+        } else if self.time - self.spawn_start
+            >= self
+                .spawn_increment
+                .div_f64(self.demand_scale)
+                .max(min_spawn_interval())
+        {
+            let minimum_time = (min_spawn_interval().as_millis() as f64).max(650.0); // 550
+            self.spawn_increment = Duration::from_millis(
+                (self.spawn_increment.as_millis() as f64 * 0.9975).max(minimum_time) as u64,
+            );
+
+            let mut origin = ORIGINS[self.rng.gen_range(0..ORIGINS.len())];
+            // 630
+            if self.spawn_increment.as_millis() <= 700 {
+                origin = ORIGINS[self.origin_index];
+                self.origin_index = (self.origin_index + 1) % ORIGINS.len();
+            }
+            if let Some(direction) = self.random_direction(origin) {
+                self.arrive(origin, direction, None);
+            }
+
+            self.spawn_start = self.time;
+        }
+    }
+
+    /// Counts an arriving car of the approach (by index) towards its platoon, starting a new one
+    /// if the last one is complete. Returns which platoon it's in, or None without platoons.
+    fn join_platoon(&mut self, i: usize) -> Option<usize> {
+        let DemandProfile::Platoons { size, .. } = DEMAND_PROFILE else {
+            return None;
+        };
+        if self.platoon_left[i] == 0 {
+            self.platoons[i] += 1;
+            self.platoon_left[i] = size;
+        }
+        self.platoon_left[i] -= 1;
+        Some(self.platoons[i])
+    }
+
+    /// Returns how long after the car that just arrived on the approach (by index) the next one
+    /// does
+    fn next_inter_arrival(&mut self, i: usize) -> Duration {
+        let rate = self.arrival_rates[i] * self.demand_scale;
+        match DEMAND_PROFILE {
+            DemandProfile::Platoons { size, headway } => {
+                let headway = headway.max(min_spawn_interval());
+                if self.platoon_left[i] > 0 {
+                    return headway;
+                }
+                // The platoons start as a Poisson process, but never run into each other
+                let length = headway * (size as u32 - 1);
+                sample_inter_arrival(&mut self.rng, rate / size as f64)
+                    .saturating_sub(length)
+                    .max(headway)
+            }
+            _ => sample_inter_arrival(&mut self.rng, rate).max(min_spawn_interval()),
+        }
+    }
+
+    /// Picks a random direction for a car from the given approach using the turn fractions.
+    /// Returns None if no movement from the approach can be picked.
+    fn random_direction(&mut self, origin: car::Origin) -> Option<car::Direction> {
+        let fractions = self.turn_fractions[origin.index()];
+        if fractions.iter().all(|&fraction| fraction <= 0.0) {
+            return None;
+        }
+
+        // Pick the same way as before there were fractions when they're all the same (and there
+        // are no U-turns) so runs stay the same
+        let configured = TURN_FRACTIONS[origin.index()];
+        if configured[..3]
+            .iter()
+            .any(|&fraction| fraction != configured[0])
+            || configured[3] != 0.0
+        {
+            let mut pick = self.rng.gen_range(0.0..1.0);
+            for (i, fraction) in fractions.iter().enumerate() {
+                if pick < *fraction {
+                    return Some(car::Direction::from(i));
+                }
+                pick -= fraction;
+            }
+            // Rounding errors
+            return fractions
+                .iter()
+                .rposition(|&fraction| fraction > 0.0)
+                .map(car::Direction::from);
+        }
+        loop {
+            let direction = car::Direction::from(self.rng.gen_range(0..=2));
+            if car::movement_allowed(origin, direction) {
+                return Some(direction);
+            }
+        }
+    }
+
+    /// Queues up a car to spawn once there's room at the start of its approach
+    fn arrive(&mut self, origin: car::Origin, direction: car::Direction, platoon: Option<usize>) {
+        let class = VEHICLE_MIX.sample(&mut self.rng);
+        self.pending.get_mut(&origin).unwrap().push_back((
+            SimplifiedCar::new(origin, direction),
+            class,
+            platoon,
+        ));
+    }
+
+    /// Spawns the pending cars (in the order they arrived) of every approach with room for them
+    fn release_pending(&mut self) {
+        for origin in ORIGINS {
+            let class = match self.pending.get(&origin).unwrap().front() {
+                Some((_, class, _)) => *class,
+                None => continue,
+            };
+            if !car::spawn_clear(&self.cars, origin, class) {
+                continue;
+            }
+            let (car, class, platoon) = self.pending.get_mut(&origin).unwrap().pop_front().unwrap();
+            self.spawn_car(car.origin, car.direction, class, 0.0);
+            self.cars.last_mut().unwrap().set_platoon(platoon);
+        }
+    }
+
+    /// Returns true if there are no cars on the map or waiting to spawn
+    pub fn is_idle(&self) -> bool {
+        self.cars.is_empty()
+            && ORIGINS.iter().all(|&origin| {
+                self.pending_spawns(origin) == 0 && self.traffic_light.queue_by_origin(origin) == 0
+            })
+    }
+
+    /// Returns the id of the car at the given point
+    pub fn car_at(&self, point: (f64, f64)) -> Option<usize> {
+        self.cars
+            .iter()
+            .find(|car| car.contains(point))
+            .map(|car| car.id)
+    }
+
+    /// Returns a snapshot of the car with the given id, if it's still on the map
+    pub fn car_state(&self, id: usize) -> Option<car::CarState> {
+        self.car(id).map(|car| car.state())
+    }
+
+    /// Puts a car at the start of its approach right away (even if another car is in the way)
+    /// and returns its id. Each approach only has one lane, so the lane is given by the origin.
+    pub fn spawn_car(
+        &mut self,
+        origin: car::Origin,
+        direction: car::Direction,
+        class: VehicleClass,
+        speed: f64,
+    ) -> usize {
+        let id = self.id;
+        let driver = self.sample_driver();
+        self.inflow[origin.index()] += 1;
+        self.cars.push(
+            Car::new(id, origin, direction)
+                .with_class(class)
+                .with_driver(driver)
+                .with_speed(speed),
+        );
+        self.traffic_light
+            .add_car(SimplifiedCar::new(origin, direction));
+        self.id += 1;
+        id
+    }
+
+    /// Picks how the next driver differs from the average one
+    fn sample_driver(&mut self) -> Driver {
+        // Don't use up random numbers without variability so runs stay the same
+        if DRIVER_VARIABILITY <= 0.0 {
+            return Driver::AVERAGE;
+        }
+        let mut factor = || 1.0 + self.rng.gen_range(-DRIVER_VARIABILITY..=DRIVER_VARIABILITY);
+        Driver {
+            acceleration: factor(),
+            max_speed: factor(),
+            gap: factor(),
+        }
+    }
+
+    /// Returns the share of each approach's cars (North, South, East, West) that turn left, turn
+    /// right, go straight and make a U-turn, after leaving out banned movements and normalizing
+    pub fn turn_fractions(&self) -> [[f64; 4]; 4] {
+        self.turn_fractions
+    }
+
+    /// Returns the car with the given id, if it's still on the map
+    pub fn car(&self, id: usize) -> Option<&Car> {
+        self.cars.iter().find(|car| car.id == id)
+    }
+
+    /// Draws the light and the cars, dimming the stopped ones if `dim_stopped`
+    pub fn draw(
+        &self,
+        sprites: &Sprites,
+        dim_stopped: bool,
+        context: &Context,
+        graphics: &mut G2d,
+    ) {
+        self.traffic_light.draw(context, graphics);
+        self.draw_spawn_zones(context, graphics);
+        if SHOW_GREEN_MOVEMENTS {
+            self.draw_green_movements(context, graphics);
+        }
+
+        self.cars
+            .iter()
+            .for_each(|car| car.draw(sprites, dim_stopped, context, graphics));
+    }
+
+    /// Draws an arrow along the path through the intersection of every movement that has the
+    /// green, and in yellow the ones whose green just ended while their light is still yellow (see
+    /// `SHOW_GREEN_MOVEMENTS`)
+    fn draw_green_movements(&self, context: &Context, graphics: &mut G2d) {
+        let last_green = self
+            .traffic_light
+            .phase_log()
+            .last()
+            .map_or(&[][..], |event| &event.movements[..]);
+        for origin in ORIGINS {
+            let yellow = self.traffic_light.in_yellow(origin);
+            for direction in (0..=3).map(car::Direction::from) {
+                let shown = self.traffic_light.is_green(origin, direction)
+                    || (yellow && last_green.contains(&(origin, direction)));
+                if !car::movement_allowed(origin, direction) || !shown {
+                    continue;
+                }
+                let color = if yellow {
+                    [0.92, 0.95, 0.13, 0.6]
+                } else {
+                    [0.24, 0.96, 0.21, 0.6]
+                };
+                let radius = if direction == car::Direction::Left
+                    && self.traffic_light.permissive_left(origin)
+                {
+                    1.5
+                } else {
+                    3.0
+                };
+
+                let path = car::path_through_intersection(origin, direction);
+                for (i, pair) in path.windows(2).enumerate() {
+                    let [from, to] = [pair[0], pair[1]].map(|(x, y)| [x, y]);
+                    if i == path.len() - 2 {
+                        Line::new_round(color, radius).draw_arrow(
+                            [from[0], from[1], to[0], to[1]],
+                            12.0,
+                            &context.draw_state,
+                            context.transform,
+                            graphics,
+                        );
+                    } else {
+                        line_from_to(color, radius, from, to, context.transform, graphics);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draws where each approach's cars spawn, red if the next car can't spawn yet and green
+    /// otherwise, with a dot for every car waiting to spawn
+    fn draw_spawn_zones(&self, context: &Context, graphics: &mut G2d) {
+        const DOT_SIZE: f64 = 7.0;
+        const DOT_GAP: f64 = 2.5;
+        let dots_per_row = (car::LANE_WIDTH / (DOT_SIZE + DOT_GAP)) as usize;
+        let max_dots = dots_per_row * (car::CAR_WIDTH / (DOT_SIZE + DOT_GAP)) as usize;
+
+        for origin in ORIGINS {
+            let queue = self.pending.get(&origin).unwrap();
+            let class = queue
+                .front()
+                .map_or(VehicleClass::Car, |(_, class, _)| *class);
+            let color = if car::spawn_clear(&self.cars, origin, class) {
+                [0.0, 1.0, 0.0, 0.3]
+            } else {
+                [1.0, 0.0, 0.0, 0.3]
+            };
+            let (x, y) = car::skew(car::get_position(origin));
+            let transform = context
+                .transform
+                .trans(x, y)
+                .rot_deg(APPROACH_SKEWS[origin.index()]);
+            let (width, height) = match origin {
+                car::Origin::North | car::Origin::South => (car::LANE_WIDTH, car::CAR_WIDTH),
+                car::Origin::East | car::Origin::West => (car::CAR_WIDTH, car::LANE_WIDTH),
+            };
+            let corner = (-width / 2.0, -height / 2.0);
+            rectangle(
+                color,
+                [corner.0, corner.1, width, height],
+                transform,
+                graphics,
+            );
+
+            for i in 0..queue.len().min(max_dots) {
+                let (across, along) = (
+                    DOT_GAP + (i % dots_per_row) as f64 * (DOT_SIZE + DOT_GAP),
+                    DOT_GAP + (i / dots_per_row) as f64 * (DOT_SIZE + DOT_GAP),
+                );
+                let (dx, dy) = match origin {
+                    car::Origin::North | car::Origin::South => (across, along),
+                    car::Origin::East | car::Origin::West => (along, across),
+                };
+                rectangle(
+                    [1.0, 1.0, 1.0, 0.8],
+                    [corner.0 + dx, corner.1 + dy, DOT_SIZE, DOT_SIZE],
+                    transform,
+                    graphics,
+                );
+            }
+        }
+    }
+
+    /// Draws where every car is steering towards
+    pub fn draw_targets(&self, context: &Context, graphics: &mut G2d) {
+        self.cars
+            .iter()
+            .for_each(|car| car.draw_target(context, graphics));
+    }
+
+    pub fn draw_stats(
+        &self,
+        selected: Option<usize>,
+        glyphs: &mut Glyphs,
+        context: &Context,
+        graphics: &mut G2d,
+    ) {
+        self.traffic_light.draw_stats(glyphs, context, graphics);
+
+        text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
+            .draw(
+                format!(
+                    "Spawn increment: {:?}, demand: {:.0}%",
+                    self.spawn_increment,
+                    self.demand_scale * 100.0
+                )
+                .as_str(),
+                glyphs,
+                &context.draw_state,
+                context.transform.trans(20.0, 35.0),
+                graphics,
+            )
+            .unwrap();
+        if !self.spillover.is_empty() {
+            text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
+                .draw(
+                    format!("Left turn spillover: {:?}", self.spillover).as_str(),
+                    glyphs,
+                    &context.draw_state,
+                    context.transform.trans(20.0, HEIGHT as f64 - 20.0),
+                    graphics,
+                )
+                .unwrap();
+        }
+
+        let lines = [
+            format!(
+                "Throughput: {:.0} veh/h, average speed: {:.0} km/h",
+                units::vehicles_per_hour(self.cars_finished(), self.time),
+                units::kilometers_per_hour(self.average_speed())
+            ),
+            format!(
+                "Approaches (N, S, E, W): {}, speed limit {:.0} km/h",
+                APPROACH_LENGTHS
+                    .map(|length| format!("{:.0} m", units::meters(length)))
+                    .join(", "),
+                units::kilometers_per_hour(car::MAX_SPEED)
+            ),
+            format!("Wait variance: {:.02}s^2", self.wait_variance()),
+            format!(
+                "Green used (N, S, E, W): {}",
+                self.totals()
+                    .green_utilization(&Totals::default())
+                    .map(|utilization| match utilization {
+                        Some(utilization) => format!("{:.0}%", utilization * 100.0),
+                        None => String::from("-"),
+                    })
+                    .join(", ")
+            ),
+            format!(
+                "Pending spawns: {:?} ({} as demand)",
+                ORIGINS.map(|origin| self.pending_spawns(origin)),
+                match DEMAND_SOURCE {
+                    DemandSource::Physical => "not counted",
+                    DemandSource::Total => "counted",
+                }
+            ),
+            format!("Yellow traps: {}", self.traffic_light.yellow_traps()),
+            format!(
+                "Dilemma zone extensions: {}",
+                self.traffic_light.dilemma_zone_extensions()
+            ),
+            format!("On recall: {:?}", RECALL),
+            format!(
+                "Turn fractions (L, R, S, U): {}",
+                ORIGINS
+                    .iter()
+                    .zip(self.turn_fractions())
+                    .map(|(origin, [left, right, straight, u_turn])| format!(
+                        "{:?} {:.2}/{:.2}/{:.2}/{:.2}",
+                        origin, left, right, straight, u_turn
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            match selected.and_then(|id| self.car_state(id)) {
+                Some(state) => format!(
+                    "Selected: #{} {:?} {:?} {:?}, speed {:.0} km/h, heading {:.0}, point {}, {}{}{}",
+                    state.id,
+                    state.class,
+                    state.origin,
+                    state.direction,
+                    units::kilometers_per_hour(state.speed),
+                    state.rotation,
+                    state.path_index,
+                    if state.stopped { "stopped" } else { "moving" },
+                    if state.through_intersection {
+                        " (through)"
+                    } else {
+                        ""
+                    },
+                    if state.colliding { " (colliding)" } else { "" }
+                ),
+                None => String::from("Selected: None"),
+            },
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            text::Text::new_color([0.0, 0.0, 0.0, 1.0], 20)
+                .draw(
+                    line.as_str(),
+                    glyphs,
+                    &context.draw_state,
+                    context.transform.trans(20.0, 435.0 + i as f64 * 40.0),
+                    graphics,
+                )
+                .unwrap();
+        }
+
+        self.plot.draw(
+            [WIDTH as f64 - 420.0, HEIGHT as f64 - 200.0, 400.0, 180.0],
+            glyphs,
+            context,
+            graphics,
+        );
+    }
+
+    pub fn traffic_light(&self) -> &dyn Controller {
+        self.traffic_light.as_ref()
+    }
+
+    /// Returns every green the light gave so far, oldest first, to check the order a controller
+    /// served the approaches in for a fixed scenario (seed and config)
+    pub fn phase_history(&self) -> &[PhaseEvent] {
+        self.traffic_light.phase_log()
+    }
+
+    pub fn near_miss_count(&self) -> usize {
+        self.near_misses
+    }
+
+    /// Returns how many cars are waiting to spawn on the given approach
+    pub fn pending_spawns(&self, origin: car::Origin) -> usize {
+        self.pending.get(&origin).unwrap().len()
+    }
+
+    /// Returns the average wait (in seconds) of each approach that has had a car finish
+    pub fn average_waits(&self) -> HashMap<car::Origin, f64> {
+        self.wait_times
+            .iter()
+            .filter(|(_, waits)| !waits.is_empty())
+            .map(|(origin, waits)| {
+                let total: Duration = waits.iter().sum();
+                (*origin, total.as_secs_f64() / waits.len() as f64)
+            })
+            .collect()
+    }
+
+    /// Fairness metric: the variance of the approaches' average waits (0 = perfectly fair)
+    pub fn wait_variance(&self) -> f64 {
+        let average_waits = self.average_waits();
+        if average_waits.is_empty() {
+            return 0.0;
+        }
+        let mean = average_waits.values().sum::<f64>() / average_waits.len() as f64;
+        average_waits
+            .values()
+            .map(|wait| (wait - mean).powi(2))
+            .sum::<f64>()
+            / average_waits.len() as f64
+    }
+}
+
+/// Checks that the turn fractions (left, right, straight, U-turn of each approach) aren't negative
+/// and scales each approach's to add up to 1, leaving out banned movements. An approach whose
+/// fractions are all 0 gets no cars.
+fn normalize_turn_fractions(fractions: [[f64; 4]; 4]) -> Result<[[f64; 4]; 4], String> {
+    let mut normalized = fractions;
+    for (origin, row) in ORIGINS.iter().zip(normalized.iter_mut()) {
+        for (i, fraction) in row.iter().enumerate() {
+            let direction = car::Direction::from(i);
+            if !fraction.is_finite() || *fraction < 0.0 {
+                return Err(format!(
+                    "the {:?} {:?} fraction is {}, it can't be negative",
+                    origin, direction, fraction
+                ));
+            }
+        }
+
+        let total: f64 = row.iter().sum();
+        if total > 0.0 && (total - 1.0).abs() > 1e-6 {
+            eprintln!(
+                "Warning: the {:?} turn fractions add up to {}, scaling them to add up to 1",
+                origin, total
+            );
+        }
+        for (i, fraction) in row.iter_mut().enumerate() {
+            if !car::movement_allowed(*origin, car::Direction::from(i)) {
+                *fraction = 0.0;
+            }
+        }
+        let allowed_total: f64 = row.iter().sum();
+        if allowed_total > 0.0 {
+            row.iter_mut()
+                .for_each(|fraction| *fraction /= allowed_total);
+        }
+    }
+    Ok(normalized)
+}
+
+/// Returns the shortest time between two cars spawning on the same approach, so that the first
+/// one (driving at full speed) has moved out of the way of the second
+pub fn min_spawn_interval() -> Duration {
+    TICK.mul_f64((car::CAR_WIDTH + MIN_SPAWN_GAP) / car::MAX_SPEED)
+}
+
+/// Returns how many cars arrive together (1 unless `DEMAND_PROFILE` is `Platoons`)
+fn platoon_size() -> usize {
+    match DEMAND_PROFILE {
+        DemandProfile::Platoons { size, .. } => size,
+        _ => 1,
+    }
+}
+
+/// Samples the time until the next arrival of a Poisson process with the given rate
+/// (vehicles/hour)
+fn sample_inter_arrival(rng: &mut StdRng, rate: f64) -> Duration {
+    let u: f64 = rng.gen_range(0.0..1.0);
+    Duration::from_secs_f64(-(1.0 - u).ln() / rate * 3600.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traffic_light::TrafficLight;
+
+    /// How long the tests that run the whole simulation run for
+    const TEST_DURATION: Duration = Duration::from_secs(60);
+
+    fn simulation() -> Simulation {
+        Simulation::with_seed(controller::from_config(|_| ()), SEED)
+    }
+
+    #[test]
+    fn windowed_runs_match_headless_ones() {
+        let ticks = (TEST_DURATION.as_secs_f64() / TICK.as_secs_f64()) as u64;
+        let mut headless = simulation();
+        for _ in 0..ticks {
+            headless.step(TICK);
+        }
+
+        // The window steps several ticks per update while idle or skipping renders, and keeps
+        // copies of the simulation for the replay (it goes on from one when scrubbing back)
+        let mut windowed = simulation();
+        let mut stepped = 0;
+        for update in 0.. {
+            let idle = update % 3 == 0;
+            let steps = crate::steps_per_update(idle, update % crate::RENDER_SKIPS.len())
+                .min(ticks - stepped);
+            for _ in 0..steps {
+                windowed.step(TICK);
+            }
+            stepped += steps;
+            windowed = windowed.clone();
+            if stepped == ticks {
+                break;
+            }
+        }
+
+        assert_eq!(headless.time(), windowed.time());
+        assert_eq!(headless.totals(), windowed.totals());
+        assert_eq!(headless.summary(), windowed.summary());
+        assert_eq!(headless.phase_history(), windowed.phase_history());
+
+        // Pinned for `SEED` with the config as it is, so drift that shows up in both runs the same
+        // way is caught too (update them when the behavior is meant to change)
+        let totals = headless.totals();
+        assert_eq!(totals.cars_finished, [11, 16, 16, 14]);
+        assert_eq!(totals.stops, 56);
+        assert_eq!(totals.collisions, 0);
+        let phases = headless
+            .phase_history()
+            .iter()
+            .take(8)
+            .map(|event| {
+                let tick = (event.time.as_secs_f64() / TICK.as_secs_f64()).round() as u64;
+                (tick, event.phase.as_str(), event.queues)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            phases,
+            [
+                (61, "South", [0, 1, 0, 0]),
+                (268, "West", [2, 0, 0, 1]),
+                (361, "North", [2, 0, 1, 0]),
+                (493, "South", [0, 0, 2, 2]),
+                (605, "East", [1, 0, 3, 1]),
+                (803, "West", [2, 0, 1, 2]),
+                (1053, "North", [2, 2, 2, 0]),
+                (1200, "South", [1, 2, 3, 1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pausing_doesnt_move_time() {
+        let mut paused = simulation();
+        let mut unpaused = simulation();
+        for _ in 0..180 {
+            paused.step(TICK);
+            unpaused.step(TICK);
+        }
+        let age = paused.car(0).unwrap().age_ticks();
+
+        // While paused the window keeps drawing and asking about the cars without stepping
+        for _ in 0..600 {
+            assert!(paused.car_state(0).is_some());
+            paused.is_idle();
+            paused.summary();
+        }
+        for _ in 0..60 {
+            paused.step(TICK);
+            unpaused.step(TICK);
+        }
+
+        assert_eq!(paused.time(), TICK * 240);
+        assert_eq!(paused.car(0).unwrap().age_ticks(), age + 60);
+        assert_eq!(paused.summary(), unpaused.summary());
+        assert_eq!(paused.phase_history(), unpaused.phase_history());
+    }
+
+    #[test]
+    fn speeds_stay_within_the_drivers_limits() {
+        let mut simulation = simulation();
+        let max_speed = car::MAX_SPEED * (1.0 + DRIVER_VARIABILITY);
+        while simulation.time() < TEST_DURATION {
+            simulation.step(TICK);
+            for car in &simulation.cars {
+                let state = car.state();
+                assert!(
+                    (0.0..=max_speed).contains(&state.speed),
+                    "car {} going {} at {:?}",
+                    state.id,
+                    state.speed,
+                    simulation.time()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn queues_match_the_waiting_cars() {
+        let mut simulation = simulation();
+        while simulation.time() < TEST_DURATION {
+            simulation.step(TICK);
+            for origin in ORIGINS {
+                let mut waiting = 0;
+                for direction in (0..=3).map(car::Direction::from) {
+                    assert_eq!(
+                        simulation
+                            .traffic_light
+                            .queue_by_movement(origin, direction),
+                        simulation.waiting_cars(origin, direction),
+                        "{:?} {:?} at {:?}",
+                        origin,
+                        direction,
+                        simulation.time()
+                    );
+                    waiting += simulation.waiting_cars(origin, direction);
+                }
+                assert_eq!(simulation.traffic_light.queue_by_origin(origin), waiting);
+            }
+        }
+    }
+
+    #[test]
+    fn releasing_a_car_gives_back_its_place_once() {
+        let mut simulation = simulation();
+        simulation.set_spawning(false);
+        for _ in 0..2 {
+            simulation.spawn_car(
+                car::Origin::North,
+                car::Direction::Straight,
+                VehicleClass::Car,
+                0.0,
+            );
+        }
+        simulation.step(TICK);
+        assert_eq!(
+            simulation.traffic_light.queue_by_origin(car::Origin::North),
+            2
+        );
+
+        // Releasing it again doesn't take the other car's place in the queue
+        let mut car = simulation.cars.remove(0);
+        for _ in 0..2 {
+            car.release(simulation.traffic_light.as_mut(), &mut simulation.occupancy);
+        }
+        assert_eq!(
+            simulation.traffic_light.queue_by_origin(car::Origin::North),
+            1
+        );
+        assert_eq!(simulation.traffic_light.queue_underflows(), 0);
+        assert!(simulation.occupancy.is_empty());
+    }
+
+    #[test]
+    fn approaches_are_served_one_after_another() {
+        // A light that only switches once the green approach is empty, with a left turner waiting
+        // on three approaches (left turns can't share the opposite approach's green)
+        let mut traffic_light = TrafficLight::new();
+        traffic_light.set_switch_ratio(10.0);
+        let mut simulation = Simulation::with_seed(Box::new(traffic_light), SEED);
+        simulation.set_spawning(false);
+        for origin in [car::Origin::East, car::Origin::South, car::Origin::North] {
+            simulation.spawn_car(origin, car::Direction::Left, VehicleClass::Car, 0.0);
+        }
+        while simulation.time() < Duration::from_secs(30) {
+            simulation.step(TICK);
+        }
+
+        // Leave out the greens given with nobody waiting for them (the light keeps switching
+        // while every queue is empty), and the ones given again to the same approach
+        let mut served: Vec<&str> = simulation
+            .phase_history()
+            .iter()
+            .filter(|event| {
+                ORIGINS.iter().any(|origin| {
+                    format!("{:?}", origin) == event.phase && event.queues[origin.index()] > 0
+                })
+            })
+            .map(|event| event.phase.as_str())
+            .collect();
+        served.dedup();
+        // North gets the first green (`ORIGINS` order), then South and East tie on having never
+        // gone and South comes first too
+        assert_eq!(served, ["North", "South", "East"]);
+    }
+}
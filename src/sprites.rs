@@ -0,0 +1,41 @@
+use piston_window::*;
+use std::{collections::HashMap, path::Path};
+
+use crate::{car::VehicleClass, VEHICLE_SPRITES};
+
+/// Images to draw the vehicle classes with, loaded once from the assets folder. Classes without
+/// one are drawn as rectangles.
+#[derive(Default)]
+pub struct Sprites {
+    textures: HashMap<VehicleClass, G2dTexture>,
+}
+
+impl Sprites {
+    /// Loads every sprite of `VEHICLE_SPRITES`, skipping (with a warning) the ones that can't be
+    /// loaded
+    pub fn load(window: &mut PistonWindow, assets: &Path) -> Sprites {
+        let mut texture_context = window.create_texture_context();
+        let mut textures = HashMap::new();
+        for &(class, file) in VEHICLE_SPRITES {
+            match G2dTexture::from_path(
+                &mut texture_context,
+                assets.join(file),
+                Flip::None,
+                &TextureSettings::new(),
+            ) {
+                Ok(texture) => {
+                    textures.insert(class, texture);
+                }
+                Err(err) => eprintln!(
+                    "Warning: couldn't load assets/{} ({}), drawing {:?}s as rectangles",
+                    file, err, class
+                ),
+            }
+        }
+        Sprites { textures }
+    }
+
+    pub fn get(&self, class: VehicleClass) -> Option<&G2dTexture> {
+        self.textures.get(&class)
+    }
+}
@@ -0,0 +1,75 @@
+use std::{fmt, time::Duration};
+
+use crate::{
+    car::{Direction, Origin},
+    controller,
+    simulation::{Simulation, Summary, TICK},
+    traffic_light::TrafficLight,
+};
+
+/// A setting changed from the config for one run of a sweep
+#[derive(Clone, Copy, Debug)]
+pub enum Variation {
+    MinimumGreenTime(Duration),
+    YellowTime(Duration),
+    /// See `SWITCH_RATIO`
+    SwitchRatio(f64),
+    /// See `MOVEMENT_WEIGHTS`
+    MovementWeight(Origin, Direction, f64),
+}
+
+impl Variation {
+    pub fn apply(&self, traffic_light: &mut TrafficLight) {
+        match *self {
+            Variation::MinimumGreenTime(time) => traffic_light.set_minimum_green_time(time),
+            Variation::YellowTime(time) => traffic_light.set_yellow_time(time),
+            Variation::SwitchRatio(ratio) => traffic_light.set_switch_ratio(ratio),
+            Variation::MovementWeight(origin, direction, weight) => {
+                traffic_light.set_movement_weight(origin, direction, weight)
+            }
+        }
+    }
+}
+
+/// Written as the parameter and value columns of the CSV
+impl fmt::Display for Variation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variation::MinimumGreenTime(time) => {
+                write!(f, "minimum_green_time,{}", time.as_secs_f64())
+            }
+            Variation::YellowTime(time) => write!(f, "yellow_time,{}", time.as_secs_f64()),
+            Variation::SwitchRatio(ratio) => write!(f, "switch_ratio,{}", ratio),
+            Variation::MovementWeight(origin, direction, weight) => {
+                write!(f, "weight {:?}:{:?},{}", origin, direction, weight)
+            }
+        }
+    }
+}
+
+/// Runs the simulation headless once per variation, with everything else (seed and end condition
+/// included) as configured
+pub fn run(variations: &[Variation]) -> Vec<(Variation, Summary)> {
+    variations
+        .iter()
+        .map(|&variation| {
+            let mut simulation =
+                Simulation::with_controller(controller::from_config(|traffic_light| {
+                    variation.apply(traffic_light)
+                }));
+            while !simulation.finished() && !simulation.halted() {
+                simulation.step(TICK);
+            }
+            (variation, simulation.summary())
+        })
+        .collect()
+}
+
+/// Returns the results as CSV, one row per run
+pub fn to_csv(results: &[(Variation, Summary)]) -> String {
+    let mut csv = format!("parameter,value,{}\n", Summary::CSV_HEADER);
+    for (variation, summary) in results {
+        csv.push_str(&format!("{},{}\n", variation, summary.csv_row()));
+    }
+    csv
+}
@@ -1,13 +1,20 @@
 use piston_window::*;
 use std::{
-    collections::HashMap,
-    f64::consts::PI,
-    time::{Duration, Instant},
+    collections::{HashMap, HashSet},
+    time::Duration,
 };
 
 use crate::{
-    car::{self, Car, LANE_WIDTH, MAX_SPEED},
-    ALLOW_GO_ON_YELLOW, ALLOW_MOVING_ON_RED, HEIGHT, USE_ENTRY_TIME, WIDTH,
+    car::{self, Car, LANE_WIDTH},
+    controller::{self, Controller, DrawController, LIGHT_RADIUS, LIGHT_SPACING, YELLOW},
+    demand::EstimatedDemand,
+    phase_log::PhaseEvent,
+    simulation::TICK,
+    ALLOW_GO_ON_YELLOW, CONFLICT_DELAY_OVERRIDES, DECELERATION, DEMAND_DECAY, DEMAND_SOURCE,
+    DILEMMA_ZONE_MAX_GREEN, DILEMMA_ZONE_PROTECTION, FAIRNESS_WEIGHT, FLASHING_YELLOW_ARROW,
+    INITIAL_GREEN, LEFT_TURN_ORDER, LEFT_TURN_PHASE_TIME, MIN_CARS_TO_SWITCH, MOVEMENT_WEIGHTS,
+    PREVENT_YELLOW_TRAP, RECALL, RED_CLEARANCE_FACTOR, SHOW_CONFLICTS, STALE_GREEN_TIMEOUT,
+    SWITCH_RATIO, USE_ENTRY_TIME, USE_ESTIMATED_DEMAND, YELLOW_TIMES,
 };
 
 #[derive(Clone, Copy, Debug)]
@@ -20,116 +27,378 @@ impl SimplifiedCar {
     pub fn new(origin: car::Origin, direction: car::Direction) -> SimplifiedCar {
         SimplifiedCar { origin, direction }
     }
+
+    pub fn movement(&self) -> (car::Origin, car::Direction) {
+        (self.origin, self.direction)
+    }
+}
+
+/// Which cars the controller counts as demand. Cars that don't fit on the approach wait to spawn
+/// past its start (a virtual queue), which a real controller's detectors can't see.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DemandSource {
+    /// Only the cars on the map
+    Physical,
+    /// The cars on the map and the ones backed up past the start of the approach (not with
+    /// `USE_ESTIMATED_DEMAND`, which only has the detectors to go on)
+    Total,
 }
 
+/// How long each approach's yellow is
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum YellowTimes {
+    /// The same for every approach (1.5s, or what a sweep sets)
+    Uniform,
+    /// Long enough for a car coming up to the light at top speed to react and brake to a stop
+    /// (the ITE formula, see `yellow_time_for_speed`). Every approach has the same top speed, so
+    /// they all get the same yellow for now.
+    Computed,
+    /// Set for each approach (in `ORIGINS` order)
+    PerApproach([Duration; 4]),
+}
+
+/// How long drivers take to notice the yellow and start braking, for `YellowTimes::Computed`
+const PERCEPTION_REACTION_TIME: Duration = Duration::from_secs(1);
+
+/// Returns how long the yellow has to be for a car coming up to the light at the given speed (in
+/// pixels per update) to react and stop at the usual deceleration: t + v / 2a
+pub fn yellow_time_for_speed(speed: f64) -> Duration {
+    PERCEPTION_REACTION_TIME + TICK.mul_f64(speed / (2.0 * DECELERATION))
+}
+
+/// When the green approach's left turners get their protected part of the green, apart from the
+/// opposite approach's short green.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LeftTurnOrder {
+    /// Whenever a left turner is at the front of the queue
+    AsNeeded,
+    /// At the start of the green, before the opposite approach goes
+    Leading,
+    /// At the end of the green, after the opposite approach went
+    Lagging,
+    /// At the start of the green like `Leading`, after which the left turners can still go when
+    /// there's a big enough gap in the oncoming traffic (see `PERMISSIVE_LEFT_GAP`)
+    ProtectedPermissive,
+}
+
+#[derive(Clone)]
 pub struct TrafficLight {
-    queue: HashMap<car::Origin, Vec<SimplifiedCar>>,
-    last_went: HashMap<car::Origin, Instant>,
-    /// When the last time someone turned left was
-    last_intersection_obstruction: Instant,
+    queues: controller::Queues,
+    last_went: HashMap<car::Origin, Duration>,
+    /// When a car of each movement last went into the intersection
+    last_entered: HashMap<(car::Origin, car::Direction), Duration>,
     /// The last car to go through the intersection
     latest_car: Option<SimplifiedCar>,
+    /// When the latest car went through
+    latest_car_time: Duration,
+    /// If no approach has had a green yet. Every approach is red until the first car shows up
+    /// (see `start_first_green`).
     start: bool,
+    /// If `MIN_CARS_TO_SWITCH` cars have been waiting at once yet (the first green is kept until
+    /// then)
+    enough_cars: bool,
     green: Option<car::Origin>,
     next_green: Option<car::Origin>,
     red_clearance_time: Duration,
     entry_time: Duration,
-    yellow_time: Duration,
+    /// Yellow time of each approach
+    yellow_times: [Duration; 4],
     minimum_green_time: Duration,
+    /// See `SWITCH_RATIO`
+    switch_ratio: f64,
     green_time: Duration,
-    phase_start: Instant,
-    red_start: Instant,
+    phase_start: Duration,
+    red_start: Duration,
     should_switch: bool,
     past_green: Option<car::Origin>,
+    /// How many left turners are waiting for a gap inside the intersection (`LEFT_TURN_CREEP`)
+    creeping_cars: usize,
+    /// The current simulated time
+    now: Duration,
+    /// How many times a left turner was shown a yellow while the opposite direction kept going,
+    /// keyed by (left turning approach, opposite approach)
+    yellow_traps: HashMap<(car::Origin, car::Origin), usize>,
+    /// Manually set conflict delays, keyed by (moving movement, waiting movement)
+    conflict_delay_overrides:
+        HashMap<((car::Origin, car::Direction), (car::Origin, car::Direction)), Duration>,
+    /// How much each waiting car of a movement counts for when comparing queues (1 if not set)
+    movement_weights: HashMap<(car::Origin, car::Direction), f64>,
+    /// Pairs of movements whose paths cross, keyed by (moving movement, waiting movement)
+    conflicts: HashSet<((car::Origin, car::Direction), (car::Origin, car::Direction))>,
+    /// `conflict_delay` of every pair in `conflicts`
+    conflict_delays:
+        HashMap<((car::Origin, car::Direction), (car::Origin, car::Direction)), Duration>,
+    /// How many updates ended with conflicting movements green at once
+    conflicting_greens: usize,
+    /// Cars removed from an approach with nobody left waiting on it
+    queue_underflows: usize,
+    /// Queue lengths from the detectors, used instead of the exact queues when set
+    estimated_demand: Option<EstimatedDemand>,
+    /// If the green approach's left turners have the green to themselves (for leading/lagging
+    /// left turns)
+    protected_left: bool,
+    /// The approach whose green started the current cycle (a cycle ends when it gets the next one)
+    cycle_origin: Option<car::Origin>,
+    cycle_start: Duration,
+    /// Cars of each approach that went through the intersection this cycle
+    cycle_served: [usize; 4],
+    /// Length and cars served of the last complete cycle
+    last_cycle: Option<(Duration, [usize; 4])>,
+    /// Every green the light gave, oldest first
+    phase_log: Vec<PhaseEvent>,
+    /// Approaches with a moving car over their dilemma zone detector (see `DILEMMA_ZONE`)
+    dilemma_zones: [bool; 4],
+    /// Cars of each approach waiting to spawn, for `DemandSource::Total`
+    backed_up: [Vec<SimplifiedCar>; 4],
+    /// When the last green that was held for a car in the dilemma zone started
+    extended_green: Option<Duration>,
+    /// How many greens were held for a car in the dilemma zone
+    dilemma_zone_extensions: usize,
+    /// When a car of each approach last went into the intersection
+    last_crossed: [Duration; 4],
+    /// If the light is changing because nobody on the green approach was moving (see
+    /// `STALE_GREEN_TIMEOUT`), so it doesn't go right back to it
+    yielding_stale_green: bool,
 }
 
 impl TrafficLight {
     pub fn new() -> TrafficLight {
         let mut last_went = HashMap::new();
-        last_went.insert(car::Origin::North, Instant::now());
-        last_went.insert(car::Origin::South, Instant::now());
-        last_went.insert(car::Origin::East, Instant::now());
-        last_went.insert(car::Origin::West, Instant::now());
-        let mut queue: HashMap<car::Origin, Vec<SimplifiedCar>> = HashMap::new();
-        queue.insert(car::Origin::North, Vec::new());
-        queue.insert(car::Origin::South, Vec::new());
-        queue.insert(car::Origin::East, Vec::new());
-        queue.insert(car::Origin::West, Vec::new());
-        TrafficLight {
-            queue,
+        last_went.insert(car::Origin::North, Duration::ZERO);
+        last_went.insert(car::Origin::South, Duration::ZERO);
+        last_went.insert(car::Origin::East, Duration::ZERO);
+        last_went.insert(car::Origin::West, Duration::ZERO);
+        let mut traffic_light = TrafficLight {
+            queues: controller::Queues::default(),
             last_went,
-            last_intersection_obstruction: Instant::now(),
+            last_entered: HashMap::new(),
             latest_car: None,
+            latest_car_time: Duration::ZERO,
             start: true,
+            enough_cars: MIN_CARS_TO_SWITCH == 0,
             green: None,
             next_green: None,
             red_clearance_time: Duration::from_secs(2),
             entry_time: Duration::from_secs(0),
-            yellow_time: Duration::from_secs_f64(1.5),
+            yellow_times: match YELLOW_TIMES {
+                YellowTimes::Uniform => [Duration::from_secs_f64(1.5); 4],
+                YellowTimes::Computed => [yellow_time_for_speed(car::MAX_SPEED); 4],
+                YellowTimes::PerApproach(yellow_times) => yellow_times,
+            },
             minimum_green_time: Duration::from_secs_f64(2.2),
+            switch_ratio: SWITCH_RATIO,
             green_time: Duration::from_secs(0),
-            phase_start: Instant::now(),
-            red_start: Instant::now(),
+            phase_start: Duration::ZERO,
+            red_start: Duration::ZERO,
             should_switch: false,
             past_green: None,
+            creeping_cars: 0,
+            now: Duration::ZERO,
+            yellow_traps: HashMap::new(),
+            conflict_delay_overrides: CONFLICT_DELAY_OVERRIDES
+                .iter()
+                .map(|&(moving, waiting, delay)| {
+                    ((moving, waiting), Duration::from_secs_f64(delay))
+                })
+                .collect(),
+            movement_weights: HashMap::new(),
+            conflicts: TrafficLight::all_movements()
+                .iter()
+                .flat_map(|moving_car| {
+                    TrafficLight::all_movements()
+                        .into_iter()
+                        .filter(|waiting_car| {
+                            TrafficLight::movements_conflict(moving_car, waiting_car)
+                        })
+                        .map(|waiting_car| (moving_car.movement(), waiting_car.movement()))
+                })
+                .collect(),
+            conflict_delays: HashMap::new(),
+            conflicting_greens: 0,
+            queue_underflows: 0,
+            estimated_demand: USE_ESTIMATED_DEMAND.then(|| EstimatedDemand::new(DEMAND_DECAY)),
+            protected_left: false,
+            cycle_origin: None,
+            cycle_start: Duration::ZERO,
+            cycle_served: [0; 4],
+            last_cycle: None,
+            phase_log: Vec::new(),
+            dilemma_zones: [false; 4],
+            backed_up: Default::default(),
+            extended_green: None,
+            dilemma_zone_extensions: 0,
+            last_crossed: [Duration::ZERO; 4],
+            yielding_stale_green: false,
+        };
+        traffic_light.conflict_delays = traffic_light
+            .conflicts
+            .iter()
+            .map(|&(moving, waiting)| {
+                let delay = traffic_light.conflict_delay(
+                    &SimplifiedCar::new(moving.0, moving.1),
+                    &SimplifiedCar::new(waiting.0, waiting.1),
+                );
+                ((moving, waiting), delay)
+            })
+            .collect();
+        for &(origin, direction, weight) in MOVEMENT_WEIGHTS {
+            traffic_light.set_movement_weight(origin, direction, weight);
+        }
+        if let Some(origin) = INITIAL_GREEN {
+            traffic_light.start_green(origin);
         }
+        traffic_light
     }
 
-    pub fn update(&mut self) {
-        if !self.should_switch {
-            self.green_time = self.phase_start.elapsed();
+    /// Gives the first green of the run to the approach
+    fn start_green(&mut self, origin: car::Origin) {
+        self.green = Some(origin);
+        self.phase_start = self.now;
+        self.start = false;
+        self.start_left_turn_phase();
+        self.update_cycle();
+        self.log_phase();
+    }
+
+    /// Holds the green while a car of its approach is in the dilemma zone (up to
+    /// `DILEMMA_ZONE_MAX_GREEN`), so it doesn't have to choose between braking hard and running
+    /// the red
+    fn hold_for_dilemma_zone(&mut self) -> bool {
+        let green = match self.green {
+            Some(green) if DILEMMA_ZONE_PROTECTION => green,
+            _ => return false,
+        };
+        if !self.dilemma_zones[green.index()]
+            || self.since(self.phase_start) >= DILEMMA_ZONE_MAX_GREEN
+        {
+            return false;
+        }
+        if self.extended_green != Some(self.phase_start) {
+            self.extended_green = Some(self.phase_start);
+            self.dilemma_zone_extensions += 1;
         }
+        true
+    }
 
-        if self.should_switch_phase() {
-            if self.longest_queue().1 >= ((self.current_queue() as f64) * 1.75) as usize {
-                self.should_switch = true;
-                self.red_start = Instant::now();
-                self.past_green = self.green;
-                self.green = None;
+    fn left_turn_order(origin: car::Origin) -> LeftTurnOrder {
+        // North/South share the first setting and East/West the second
+        LEFT_TURN_ORDER[origin.index() / 2]
+    }
 
-                self.next_green = Some(self.longest_queue().0);
+    /// Starts the new green with its protected left turns if they lead
+    fn start_left_turn_phase(&mut self) {
+        self.protected_left = self.green.map_or(false, |green| {
+            matches!(
+                TrafficLight::left_turn_order(green),
+                LeftTurnOrder::Leading | LeftTurnOrder::ProtectedPermissive
+            )
+        });
+    }
 
-                self.calculate_clearance_time();
+    /// Ends a leading left turn phase once its left turners are gone (or it ran too long), and
+    /// starts a lagging one once the opposite approach has nothing left to send (or had long
+    /// enough)
+    fn update_left_turn_phase(&mut self) {
+        let green = match self.green {
+            Some(green) => green,
+            None => return,
+        };
+        let left_turners = self.queue_by_movement(green, car::Direction::Left)
+            + self.queue_by_movement(green, car::Direction::UTurn);
+        let timed_out = self.since(self.phase_start).as_secs_f64() >= LEFT_TURN_PHASE_TIME;
+        match TrafficLight::left_turn_order(green) {
+            LeftTurnOrder::AsNeeded => (),
+            LeftTurnOrder::Leading | LeftTurnOrder::ProtectedPermissive => {
+                if left_turners == 0 || timed_out {
+                    self.protected_left = false;
+                }
+            }
+            LeftTurnOrder::Lagging => {
+                // Only the first car of the opposite approach can use its part of the green
+                let opposite_done = self
+                    .queues
+                    .get(green.opposite())
+                    .first()
+                    .map_or(true, |car| car.direction.crosses_oncoming());
+                if left_turners > 0 && (opposite_done || timed_out) {
+                    self.protected_left = true;
+                }
             }
         }
-        // If it's past yellow and the direction that just went will go again, just go back to
-        // green
-        if self.should_switch
-            && self.red_start.elapsed() >= self.yellow_time
-            && self.past_green.is_some()
-            && self.longest_queue().0 == self.past_green.unwrap()
-        {
-            self.should_switch = false;
-            self.phase_start = Instant::now();
-            self.green = self.past_green;
-            self.next_green = None;
-        }
-        // // If it's past yellow, change the light
-        if self.should_switch && self.red_start.elapsed() >= self.red_clearance_time {
-            self.phase_start = Instant::now();
-
-            self.green = self.next_green;
-            self.next_green = None;
+    }
 
-            self.last_went
-                .entry(self.green.unwrap())
-                .and_modify(|x| *x = Instant::now());
+    /// Records the green that just started
+    fn log_phase(&mut self) {
+        let phase = match self.green {
+            Some(green) => format!("{:?}", green),
+            None => return,
+        };
+        let movements = TrafficLight::all_movements()
+            .iter()
+            .filter(|car| self.is_green(car.origin, car.direction))
+            .map(|car| car.movement())
+            .collect();
+        let queues = car::ORIGINS.map(|origin| self.queue_by_origin(origin));
+        self.phase_log.push(PhaseEvent {
+            time: self.now,
+            phase,
+            movements,
+            queues,
+            startup_lost_time: None,
+        });
+    }
 
-            self.should_switch = false;
+    /// Ends the cycle when the green gets back to the approach that started it
+    fn update_cycle(&mut self) {
+        let green = match self.green {
+            Some(green) => green,
+            None => return,
+        };
+        match self.cycle_origin {
+            Some(origin) if origin == green => {
+                self.last_cycle = Some((self.since(self.cycle_start), self.cycle_served));
+                self.cycle_start = self.now;
+                self.cycle_served = [0; 4];
+            }
+            Some(_) => (),
+            None => {
+                self.cycle_origin = Some(green);
+                self.cycle_start = self.now;
+            }
         }
     }
 
     fn change_light(&mut self) {
         self.should_switch = false;
-        self.phase_start = Instant::now();
+        self.phase_start = self.now;
         self.green = self.next_green;
         self.next_green = None;
-        self.last_intersection_obstruction = Instant::now();
     }
 
     /// Calculates the entry time of a car into the intersection given the car already in the
     /// intersection and the currently waiting car
-    fn calculate_entry_time(&mut self, moving_car: &SimplifiedCar, waiting_car: &SimplifiedCar) {
+    fn calculate_entry_time(moving_car: &SimplifiedCar, waiting_car: &SimplifiedCar) -> Duration {
+        let distance_to_collision = match TrafficLight::conflict_point(moving_car, waiting_car) {
+            Some((_, waiting_path_index)) => {
+                TrafficLight::distance_to_conflict(waiting_car, waiting_path_index)
+            }
+            // Don't intersect
+            None => return Duration::from_secs(100),
+        };
+
+        // Function: d = (1/2)at^2 assuming initial velocity is 0
+        // So: t = sqrt(2d/a)
+        let num_frames = (2.0 * distance_to_collision / car::ACCELERATION).sqrt();
+
+        TICK.mul_f64(num_frames)
+    }
+
+    /// Walks both paths for the first points where the two cars would be on top of each other.
+    /// Returns the index of that point in the moving car's path and in the waiting car's path.
+    pub fn conflict_point(
+        moving_car: &SimplifiedCar,
+        waiting_car: &SimplifiedCar,
+    ) -> Option<(usize, usize)> {
         let moving_car_path = car::Car::calculate_path(moving_car);
         let waiting_car_path = car::Car::calculate_path(waiting_car);
 
@@ -161,17 +430,20 @@ impl TrafficLight {
             }
         }
 
-        // Don't intersect
         if moving_path_index == 0 {
-            self.entry_time = Duration::from_secs(100);
-            return;
+            return None;
         }
+        Some((moving_path_index, waiting_path_index))
+    }
 
+    /// Returns how far the waiting car drives from its stop line to get to the given point of
+    /// its path
+    fn distance_to_conflict(waiting_car: &SimplifiedCar, waiting_path_index: usize) -> f64 {
+        let waiting_car_path = car::Car::calculate_path(waiting_car);
         let end_index = (waiting_path_index - 1)
             .min(waiting_car_path.len() - 1)
             .max(0);
-        let distance_to_collision = (car::Car::calculate_waiting_point_index(waiting_car)
-            ..=end_index)
+        (car::Car::calculate_waiting_point_index(waiting_car)..=end_index)
             .map(|i| i as f64)
             .reduce(|acc, i| {
                 let distance =
@@ -181,35 +453,135 @@ impl TrafficLight {
                     .sqrt();
                 acc + distance
             })
-            .unwrap_or(0.0);
+            .unwrap_or(0.0)
+    }
 
-        // Function: d = (1/2)at^2 assuming initial velocity is 0
-        // So: t = sqrt(2d/a)
-        let num_frames = (2.0 * distance_to_collision / car::ACCELERATION).sqrt();
+    /// Returns if the paths of two movements from different approaches cross
+    pub fn movements_conflict(first: &SimplifiedCar, second: &SimplifiedCar) -> bool {
+        first.origin != second.origin
+            && TrafficLight::calculate_entry_time(first, second) < Duration::from_secs(100)
+    }
 
-        let frame_duration = 1000.0 / 60.0;
-        self.entry_time = Duration::from_millis((num_frames * frame_duration) as u64);
+    /// Returns if the moving car's path crosses the waiting car's, so the waiting car can't go
+    /// until the moving car is through
+    pub fn conflicts(&self, moving_car: &SimplifiedCar, waiting_car: &SimplifiedCar) -> bool {
+        self.conflicts
+            .contains(&(moving_car.movement(), waiting_car.movement()))
     }
 
-    fn calculate_red_clearance_time(&mut self, direction: car::Direction) {
-        let straight_distance = LANE_WIDTH * 4.0;
-        let left_distance = std::f64::consts::PI * LANE_WIDTH * 3.0 / 2.0;
-        let right_distance = std::f64::consts::PI * LANE_WIDTH / 2.0;
-
-        let distance_covered = match direction {
-            car::Direction::Straight => straight_distance,
-            car::Direction::Left => left_distance,
-            car::Direction::Right => right_distance,
-        };
+    /// Returns every movement through the intersection
+    pub fn all_movements() -> Vec<SimplifiedCar> {
+        car::ORIGINS
+            .iter()
+            .flat_map(|&origin| {
+                [
+                    car::Direction::Straight,
+                    car::Direction::Left,
+                    car::Direction::Right,
+                    car::Direction::UTurn,
+                ]
+                .map(|direction| SimplifiedCar::new(origin, direction))
+            })
+            .collect()
+    }
 
-        let speed = MAX_SPEED;
-        let frame_duration = 1000.0 / 60.0;
+    /// Returns a table of every pair of movements, with the conflict delay (in seconds) of the
+    /// ones whose paths cross and `-` for the ones that can go together
+    pub fn dump_conflict_matrix(&self) -> String {
+        let movements = TrafficLight::all_movements();
+        let name = |car: &SimplifiedCar| format!("{:?}:{:?}", car.origin, car.direction);
 
-        let mut clearance_time = distance_covered / speed * frame_duration; // Raw all red time
+        let mut table = format!("{:<15}", "moving\\waiting");
+        for waiting_car in &movements {
+            table += &format!("{:>15}", name(waiting_car));
+        }
+        for moving_car in &movements {
+            table += &format!("\n{:<15}", name(moving_car));
+            for waiting_car in &movements {
+                let cell = if self
+                    .conflicts
+                    .contains(&(moving_car.movement(), waiting_car.movement()))
+                {
+                    format!(
+                        "{:.2}",
+                        self.conflict_delay(moving_car, waiting_car).as_secs_f64()
+                    )
+                } else {
+                    String::from("-")
+                };
+                table += &format!("{:>15}", cell);
+            }
+        }
+        table
+    }
+
+    /// Returns the pairs of conflicting movements that are currently both allowed to go
+    fn green_conflicts(&self) -> Vec<(SimplifiedCar, SimplifiedCar)> {
+        let going: Vec<SimplifiedCar> = TrafficLight::all_movements()
+            .into_iter()
+            .filter(|car| self.is_green(car.origin, car.direction))
+            .collect();
+        let mut conflicts = Vec::new();
+        for (i, first) in going.iter().enumerate() {
+            for second in &going[i + 1..] {
+                if self
+                    .conflicts
+                    .contains(&(first.movement(), second.movement()))
+                    || self
+                        .conflicts
+                        .contains(&(second.movement(), first.movement()))
+                {
+                    conflicts.push((*first, *second));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Counts (and panics on, in debug builds) conflicting movements that are green at once
+    fn check_conflicts(&mut self) {
+        let conflicts = self.green_conflicts();
+        debug_assert!(
+            conflicts.is_empty(),
+            "conflicting movements are green at once: {:?}",
+            conflicts
+        );
+        if !conflicts.is_empty() {
+            self.conflicting_greens += 1;
+        }
+    }
+
+    /// Returns how far a car drives from entering the intersection to leaving it
+    fn crossing_distance(direction: car::Direction) -> f64 {
+        match direction {
+            car::Direction::Straight => LANE_WIDTH * 4.0,
+            car::Direction::Left => std::f64::consts::PI * LANE_WIDTH * 3.0 / 2.0,
+            car::Direction::Right => std::f64::consts::PI * LANE_WIDTH / 2.0,
+            // In, around a half circle and back out (the longest of them)
+            car::Direction::UTurn => LANE_WIDTH * 2.0 + std::f64::consts::PI * LANE_WIDTH,
+        }
+    }
+
+    /// Returns how long (in milliseconds) it takes a car at its turning speed to cross the
+    /// intersection
+    fn calculate_crossing_time(direction: car::Direction) -> f64 {
+        let ticks = TrafficLight::crossing_distance(direction) / car::turning_speed(direction);
+        ticks * TICK.as_secs_f64() * 1000.0
+    }
+
+    /// Returns how long the light assumes a car takes to get through the intersection making
+    /// the movement
+    pub fn crossing_time(direction: car::Direction) -> Duration {
+        Duration::from_secs_f64(TrafficLight::calculate_crossing_time(direction) / 1000.0)
+    }
+
+    fn calculate_red_clearance_time(&mut self, direction: car::Direction) {
+        let mut clearance_time = TrafficLight::calculate_crossing_time(direction); // Raw all red time
         if USE_ENTRY_TIME {
             clearance_time -= self.entry_time.as_millis() as f64; // Subtract entry time
         }
-        clearance_time += self.yellow_time.as_millis() as f64; // Add in yellow at the start
+        clearance_time *= RED_CLEARANCE_FACTOR; // Safety margin
+        clearance_time += self.yellow_time().as_millis() as f64; // Add in yellow at the start
 
         // TODO: maybe allow negative red clearance time? (meaning < yellow_time)
         // Note: right now entry time gets set to max f64 if they don't every collide
@@ -219,35 +591,163 @@ impl TrafficLight {
     }
 
     fn should_switch_phase(&self) -> bool {
+        // Approaches on recall get at least the minimum green, even without any cars
+        let on_recall = self.green.map_or(false, |green| RECALL.contains(&green));
         !self.should_switch
-            && (self.phase_start.elapsed() >= self.minimum_green_time || self.current_queue() == 0)
+            && self.enough_cars
+            && (self.since(self.phase_start) >= self.minimum_green_time
+                || (self.current_queue() == 0.0 && !on_recall))
+    }
+
+    /// Returns the approach on recall that is owed a green: every other approach with cars has
+    /// gone since it last did
+    fn recall_due(&self) -> Option<car::Origin> {
+        RECALL.iter().copied().find(|&recalled| {
+            let last_went = *self.last_went.get(&recalled).unwrap();
+            self.green != Some(recalled)
+                && car::ORIGINS.iter().all(|origin| {
+                    *origin == recalled
+                        || self.demand(*origin) == 0
+                        || *self.last_went.get(origin).unwrap() > last_went
+                })
+                && self
+                    .last_went
+                    .iter()
+                    .any(|(origin, went)| *origin != recalled && *went > last_went)
+        })
+    }
+
+    /// Returns how many cars the controller thinks are waiting on the given approach
+    fn demand(&self, origin: car::Origin) -> usize {
+        match &self.estimated_demand {
+            Some(estimated_demand) => estimated_demand.get(origin),
+            None => self.queues.by_origin(origin),
+        }
     }
 
-    fn current_queue(&self) -> usize {
+    pub fn set_minimum_green_time(&mut self, minimum_green_time: Duration) {
+        self.minimum_green_time = minimum_green_time;
+    }
+
+    pub fn minimum_green_time(&self) -> Duration {
+        self.minimum_green_time
+    }
+
+    /// Gives every approach the same yellow time
+    pub fn set_yellow_time(&mut self, yellow_time: Duration) {
+        self.yellow_times = [yellow_time; 4];
+    }
+
+    /// Returns each approach's yellow time (indexed by `Origin::index`)
+    pub fn yellow_times(&self) -> [Duration; 4] {
+        self.yellow_times
+    }
+
+    /// Returns the yellow time of the approach whose green is on or just ended
+    fn yellow_time(&self) -> Duration {
+        self.green.or(self.past_green).map_or_else(
+            || self.longest_yellow_time(),
+            |origin| self.yellow_times[origin.index()],
+        )
+    }
+
+    fn longest_yellow_time(&self) -> Duration {
+        self.yellow_times.iter().copied().max().unwrap()
+    }
+
+    pub fn set_switch_ratio(&mut self, switch_ratio: f64) {
+        assert!(
+            switch_ratio.is_finite() && switch_ratio > 0.0,
+            "the switch ratio is {}, it has to be more than 0",
+            switch_ratio
+        );
+        self.switch_ratio = switch_ratio;
+    }
+
+    /// Makes every waiting car of the movement count `weight` times when the controller compares
+    /// queues, to favor (or hold back) some movements
+    pub fn set_movement_weight(
+        &mut self,
+        origin: car::Origin,
+        direction: car::Direction,
+        weight: f64,
+    ) {
+        assert!(
+            weight.is_finite() && weight > 0.0,
+            "the {:?} {:?} weight is {}, it has to be more than 0",
+            origin,
+            direction,
+            weight
+        );
+        self.movement_weights.insert((origin, direction), weight);
+    }
+
+    pub fn movement_weight(&self, origin: car::Origin, direction: car::Direction) -> f64 {
+        self.movement_weights
+            .get(&(origin, direction))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Returns the waiting cars of the given approach, each counted by its movement's weight. The
+    /// detectors can't tell which way cars are turning, so estimated demand isn't weighted.
+    fn weighted_demand(&self, origin: car::Origin) -> f64 {
+        if let Some(estimated_demand) = &self.estimated_demand {
+            return estimated_demand.get(origin) as f64;
+        }
+        let backed_up: &[SimplifiedCar] = match DEMAND_SOURCE {
+            DemandSource::Physical => &[],
+            DemandSource::Total => &self.backed_up[origin.index()],
+        };
+        self.queues
+            .get(origin)
+            .iter()
+            .chain(backed_up)
+            .map(|car| self.movement_weight(car.origin, car.direction))
+            .sum()
+    }
+
+    fn current_queue(&self) -> f64 {
         if let Some(green) = self.green {
-            let mut len = self.queue.get(&green).unwrap().len();
-            for car in self.queue.get(&green.right()).unwrap() {
+            let mut len = self.weighted_demand(green);
+            // The detectors can't tell which way cars are turning
+            if self.estimated_demand.is_some() {
+                return len;
+            }
+            for car in self.queues.get(green.right()) {
                 if car.direction != car::Direction::Right {
                     break;
                 }
-                len += 1;
+                len += self.movement_weight(car.origin, car.direction);
             }
             len
         } else {
-            0
+            0.0
         }
     }
 
-    fn longest_queue(&self) -> (car::Origin, usize) {
-        let mut queue_lengths: Vec<(car::Origin, usize)> = self
-            .queue
+    fn longest_queue(&self) -> (car::Origin, f64) {
+        self.longest_queue_where(|_| true)
+    }
+
+    /// Returns the longest queue of the approaches the filter keeps (see `longest_queue`)
+    fn longest_queue_where(&self, filter: impl Fn(car::Origin) -> bool) -> (car::Origin, f64) {
+        // Go through the approaches in a fixed order (not the map's) so ties are always broken
+        // the same way
+        let mut queue_lengths: Vec<(car::Origin, f64)> = car::ORIGINS
             .iter()
-            .map(|(origin, queue)| (*origin, queue.len()))
+            .map(|origin| (*origin, self.weighted_demand(*origin)))
             .collect();
 
-        // Count right turns as part of the queue on their left
-        for (origin, cars) in &self.queue {
-            for car in cars {
+        // Count right turns as part of the queue on their left (the detectors can't tell which
+        // way cars are turning)
+        let origins: &[car::Origin] = if self.estimated_demand.is_some() {
+            &[]
+        } else {
+            &car::ORIGINS
+        };
+        for &origin in origins {
+            for car in self.queues.get(origin) {
                 if car.direction != car::Direction::Right {
                     break;
                 }
@@ -256,174 +756,622 @@ impl TrafficLight {
                     .iter_mut()
                     .find(|(o, _)| *o == left)
                     .unwrap()
-                    .1 += 1;
+                    .1 += self.movement_weight(car.origin, car.direction);
             }
         }
 
-        // Return highest queue length
+        // Return highest priority queue. Ties go to the approach that has gone the longest
+        // without a green, then to the first one in `car::ORIGINS` (`max_by` keeps the last of
+        // equal elements, so the later origin compares as smaller)
         queue_lengths
             .into_iter()
-            .max_by(|(_, a), (_, b)| a.cmp(b))
+            .enumerate()
+            .filter(|(_, (origin, _))| filter(*origin))
+            .max_by(|(a_index, (a_origin, a)), (b_index, (b_origin, b))| {
+                self.queue_priority(*a_origin, *a)
+                    .partial_cmp(&self.queue_priority(*b_origin, *b))
+                    .unwrap()
+                    .then_with(|| {
+                        self.last_went
+                            .get(b_origin)
+                            .unwrap()
+                            .cmp(self.last_went.get(a_origin).unwrap())
+                    })
+                    .then_with(|| b_index.cmp(a_index))
+            })
+            .map(|(_, queue)| queue)
             .unwrap()
     }
 
-    pub fn draw(&self, context: &Context, graphics: &mut G2d) {
-        let light_radius = 15.0;
-        let light_spacing = 10.0;
-        let green = [0.24, 0.96, 0.21, 1.0];
-        let yellow = [0.92, 0.95, 0.13, 1.0];
-        let red = [0.96, 0.19, 0.19, 1.0];
-        let dark_green = [0.05, 0.22, 0.04, 1.0];
-        let dark_yellow = [0.3, 0.32, 0.04, 1.0];
-        let dark_red = [0.34, 0.06, 0.06, 1.0];
-        let origins = [
-            car::Origin::North,
-            car::Origin::East,
-            car::Origin::South,
-            car::Origin::West,
-        ];
+    /// Returns how urgently the given queue should be served. Without a fairness weight this is
+    /// just the (weighted) queue length, otherwise it grows the longer the approach has gone
+    /// without a green.
+    fn queue_priority(&self, origin: car::Origin, length: f64) -> f64 {
+        let starved_time = if self.green == Some(origin) {
+            Duration::ZERO
+        } else {
+            self.since(*self.last_went.get(&origin).unwrap())
+        };
+        length * (1.0 + FAIRNESS_WEIGHT * starved_time.as_secs_f64())
+    }
 
-        for origin in origins.iter() {
-            let final_position = match origin {
-                car::Origin::North => Position {
-                    x: LANE_WIDTH as i32 * 2,
-                    y: LANE_WIDTH as i32 * 2,
-                },
-                car::Origin::East => Position {
-                    x: LANE_WIDTH as i32 * 2,
-                    y: LANE_WIDTH as i32 * 2,
-                },
-                car::Origin::South => Position {
-                    x: LANE_WIDTH as i32 * 2,
-                    y: LANE_WIDTH as i32 * 2,
-                },
-                car::Origin::West => Position {
-                    x: LANE_WIDTH as i32 * 2,
-                    y: LANE_WIDTH as i32 * 2,
-                },
-            };
+    /// Returns the approach to give the green to instead of the green one, if cars are waiting on
+    /// the green but none of them has gone for `STALE_GREEN_TIMEOUT` (they're held up past the
+    /// intersection, or by the turn they are waiting to make) while another approach has cars
+    /// waiting
+    fn stale_green_yield(&self) -> Option<car::Origin> {
+        let (timeout, green) = match (STALE_GREEN_TIMEOUT, self.green) {
+            (Some(timeout), Some(green)) => (timeout, green),
+            _ => return None,
+        };
+        let last_crossed = self.last_crossed[green.index()].max(self.phase_start);
+        if self.current_queue() == 0.0 || self.since(last_crossed) < timeout {
+            return None;
+        }
+        let (origin, length) = self.longest_queue_where(|origin| origin != green);
+        (length > 0.0).then_some(origin)
+    }
 
-            let transform = context
-                .transform
-                .trans(WIDTH as f64 / 2.0, HEIGHT as f64 / 2.0)
-                .rot_rad(match origin {
-                    car::Origin::North => PI,
-                    car::Origin::East => 3.0 * PI / 2.0,
-                    car::Origin::South => 0.0,
-                    car::Origin::West => PI / 2.0,
-                })
-                .trans(final_position.x as f64, final_position.y as f64);
-            Rectangle::new_round([0.0, 0.0, 0.0, 1.0], light_radius * 2.5).draw(
-                [
-                    0.0,
-                    0.0,
-                    light_radius * 5.0,
-                    (light_radius * 2.0 + light_spacing) * 3.0 + light_spacing * 2.0,
-                ],
-                &context.draw_state,
-                transform,
-                graphics,
-            );
-            Rectangle::new_round_border([1.0; 4], light_radius * 2.5, 1.5).draw(
-                [
-                    0.0,
-                    0.0,
-                    light_radius * 5.0,
-                    (light_radius * 2.0 + light_spacing) * 3.0 + light_spacing * 2.0,
-                ],
-                &context.draw_state,
-                transform,
+    /// Returns the first car waiting on the approach the light would give the green to next
+    fn next_candidate(&self) -> Option<SimplifiedCar> {
+        let origin = self.next_green.unwrap_or_else(|| self.longest_queue().0);
+        if self.green == Some(origin) {
+            return None;
+        }
+        self.queues.get(origin).first().copied()
+    }
+
+    /// Draws the paths of the movements that have the green (or of the last car to go, while the
+    /// light is changing) and of the next candidate. Where the candidate would first meet each of
+    /// them is circled, with the candidate's path up to there (what its entry time is worked out
+    /// from) drawn thicker.
+    fn draw_conflicts(&self, context: &Context, graphics: &mut G2d) {
+        let candidate = match self.next_candidate() {
+            Some(candidate) => candidate,
+            None => return,
+        };
+        let mut moving: Vec<SimplifiedCar> = TrafficLight::all_movements()
+            .into_iter()
+            .filter(|car| {
+                car::movement_allowed(car.origin, car.direction)
+                    && self.is_green(car.origin, car.direction)
+            })
+            .collect();
+        if moving.is_empty() {
+            moving.extend(self.latest_car);
+        }
+
+        let draw_path =
+            |points: &[(f64, f64)], color: [f32; 4], radius: f64, graphics: &mut G2d| {
+                for pair in points.windows(2) {
+                    line_from_to(
+                        color,
+                        radius,
+                        [pair[0].0, pair[0].1],
+                        [pair[1].0, pair[1].1],
+                        context.transform,
+                        graphics,
+                    );
+                }
+            };
+        let candidate_path = car::Car::calculate_path(&candidate);
+        let waiting_index = car::Car::calculate_waiting_point_index(&candidate);
+        draw_path(
+            &car::path_through_intersection(candidate.origin, candidate.direction),
+            [0.1, 0.3, 1.0, 0.8],
+            1.5,
+            graphics,
+        );
+        for moving_car in moving
+            .iter()
+            .filter(|moving_car| moving_car.origin != candidate.origin)
+        {
+            draw_path(
+                &car::path_through_intersection(moving_car.origin, moving_car.direction),
+                [1.0, 1.0, 1.0, 0.8],
+                1.5,
                 graphics,
             );
-            let show_yellow = if let Some(green_light) = self.past_green() {
-                if green_light == *origin && self.red_start.elapsed() < self.yellow_time {
-                    true
+            if let Some((_, conflict_index)) = TrafficLight::conflict_point(moving_car, &candidate)
+            {
+                draw_path(
+                    &candidate_path[waiting_index..=conflict_index],
+                    [0.1, 0.3, 1.0, 0.8],
+                    3.5,
+                    graphics,
+                );
+                let (x, y) = candidate_path[conflict_index];
+                Ellipse::new_border([0.96, 0.19, 0.19, 1.0], 2.0).draw(
+                    [x - 8.0, y - 8.0, 16.0, 16.0],
+                    &context.draw_state,
+                    context.transform,
+                    graphics,
+                );
+            }
+        }
+    }
+
+    /// Calculates entry time and red clearance time
+    fn calculate_clearance_time(&mut self) {
+        if let Some(moving_car) = self.latest_car {
+            let waiting_car = if let Some(green) = self.next_green {
+                if let Some(car) = self.queues.get(green).first() {
+                    Some(SimplifiedCar {
+                        origin: green,
+                        direction: car.direction,
+                    })
                 } else {
-                    false
+                    None
                 }
             } else {
-                false
-            };
-            let final_red = match self.green() {
-                // Light is green, make all other lights red and this one dark_red
-                Some(green_light) => {
-                    if green_light == *origin {
-                        dark_red
-                    } else {
-                        if show_yellow {
-                            dark_red
-                        } else {
-                            red
-                        }
-                    }
-                }
-                None => {
-                    if show_yellow {
-                        dark_red
-                    } else {
-                        red
-                    }
-                }
+                None
             };
-            let final_yellow = if show_yellow { yellow } else { dark_yellow };
-            // // Show yellow before turning to green
-            // let final_yellow = if self.red_start.elapsed() >= self.yellow_time {
-            //     if let Some(green_light) = self.next_green {
-            //         if green_light == *origin {
-            //             yellow
-            //         } else {
-            //             final_yellow
-            //         }
-            //     } else {
-            //         final_yellow
-            //     }
-            // } else {
-            //     final_yellow
-            // };
-            let final_green = if let Some(green_light) = self.green() {
-                if green_light == *origin {
-                    green
-                } else {
-                    dark_green
+            if let Some(waiting_car) = waiting_car {
+                self.entry_time = TrafficLight::calculate_entry_time(&moving_car, &waiting_car);
+
+                if self
+                    .conflict_delay_overrides
+                    .contains_key(&(moving_car.movement(), waiting_car.movement()))
+                {
+                    self.red_clearance_time =
+                        self.yellow_time() + self.conflict_delay(&moving_car, &waiting_car);
+                    return;
                 }
-            } else {
-                dark_green
-            };
-            let colors = [final_red, final_yellow, final_green];
-            for i in 0..3 {
-                ellipse(
-                    colors[i],
-                    [
-                        light_radius + light_radius * 0.5,
-                        light_radius + (i as f64 * (light_radius * 2.0 + light_spacing)),
-                        light_radius * 2.0,
-                        light_radius * 2.0,
-                    ],
+            }
+
+            self.calculate_red_clearance_time(moving_car.direction);
+        }
+    }
+
+    /// Returns how long after the yellow ends the waiting car has to wait for the moving car to
+    /// clear the intersection
+    pub fn conflict_delay(
+        &self,
+        moving_car: &SimplifiedCar,
+        waiting_car: &SimplifiedCar,
+    ) -> Duration {
+        if let Some(delay) = self
+            .conflict_delay_overrides
+            .get(&(moving_car.movement(), waiting_car.movement()))
+        {
+            return *delay;
+        }
+
+        let mut delay = TrafficLight::calculate_crossing_time(moving_car.direction);
+        if USE_ENTRY_TIME {
+            delay -= TrafficLight::calculate_entry_time(moving_car, waiting_car).as_millis() as f64;
+        }
+        Duration::from_millis((delay * RED_CLEARANCE_FACTOR).max(0.0) as u64)
+    }
+
+    /// Returns if the approach's flashing yellow arrow is lit right now (it's only shown during
+    /// permissive left turns, on approaches with `FLASHING_YELLOW_ARROW`)
+    pub fn flashing_yellow_arrow(&self, origin: car::Origin) -> bool {
+        FLASHING_YELLOW_ARROW[origin.index()]
+            && self.permissive_left(origin)
+            && self.now.as_millis() / 500 % 2 == 0
+    }
+
+    /// Short green lets the movements of the approach opposite the green that don't cross the
+    /// green's through and right turning traffic (like its own through traffic) go with it
+    fn short_green(&self, origin: car::Origin, direction: car::Direction) -> bool {
+        let using_green = match self.green.or(self.past_green) {
+            Some(green) => green,
+            None => return false,
+        };
+
+        // Letting the opposite direction keep going through the yellow is what makes a yellow
+        // trap, so that can be turned off
+        let during_yellow = self.should_switch
+            && self.since(self.red_start) < self.yellow_time()
+            && !PREVENT_YELLOW_TRAP;
+
+        let compatible = [car::Direction::Straight, car::Direction::Right]
+            .into_iter()
+            .all(|green_direction| {
+                !self.conflicts(
+                    &SimplifiedCar::new(using_green, green_direction),
+                    &SimplifiedCar::new(origin, direction),
+                )
+            });
+
+        (!self.should_switch || during_yellow)
+            && using_green == origin.opposite()
+            && compatible
+            && self.clear_of_conflicts(origin, direction)
+            && match TrafficLight::left_turn_order(using_green) {
+                LeftTurnOrder::AsNeeded => self
+                    .queues
+                    .get(using_green)
+                    .first()
+                    .map_or(false, |car| !car.direction.crosses_oncoming()),
+                LeftTurnOrder::Leading
+                | LeftTurnOrder::Lagging
+                | LeftTurnOrder::ProtectedPermissive => !self.protected_left,
+            }
+    }
+
+    /// Returns if every car that went in on a movement crossing the given one has had its
+    /// conflict delay to get out of its way. Movements that don't cross it (like the opposite
+    /// through traffic) never hold it up.
+    fn clear_of_conflicts(&self, origin: car::Origin, direction: car::Direction) -> bool {
+        self.last_entered.iter().all(|(&moving, &time)| {
+            match self.conflict_delays.get(&(moving, (origin, direction))) {
+                Some(&delay) => self.since(time) > delay,
+                None => true,
+            }
+        })
+    }
+
+    /// Flags a yellow trap if the approach that just got a yellow has left turners while the
+    /// opposite approach keeps its short green. The left turners see their light ending and
+    /// assume the oncoming traffic is stopping too, when it isn't.
+    fn check_yellow_trap(&mut self) {
+        let yellow = match self.past_green {
+            Some(yellow) => yellow,
+            None => return,
+        };
+        let has_left_turners = self
+            .queues
+            .get(yellow)
+            .iter()
+            .any(|car| car.direction.crosses_oncoming());
+        if has_left_turners && self.short_green(yellow.opposite(), car::Direction::Straight) {
+            *self
+                .yellow_traps
+                .entry((yellow, yellow.opposite()))
+                .or_insert(0) += 1;
+        }
+    }
+
+    pub fn green_time(&self) -> Duration {
+        self.green_time
+    }
+
+    /// Returns how much simulated time has passed since the given time
+    fn since(&self, time: Duration) -> Duration {
+        self.now.saturating_sub(time)
+    }
+}
+
+impl Controller for TrafficLight {
+    fn update(&mut self, now: Duration) {
+        if let Some(estimated_demand) = &mut self.estimated_demand {
+            estimated_demand.update(now.saturating_sub(self.now));
+        }
+        self.now = now;
+
+        if self.start {
+            return;
+        }
+
+        if !self.should_switch {
+            self.green_time = self.since(self.phase_start);
+        }
+
+        if !self.enough_cars {
+            self.enough_cars = car::ORIGINS
+                .into_iter()
+                .map(|origin| self.queues.by_origin(origin))
+                .sum::<usize>()
+                >= MIN_CARS_TO_SWITCH;
+        }
+
+        if self.should_switch_phase() {
+            let (longest_origin, longest_length) = self.longest_queue();
+            let recall = self.recall_due();
+            let stale_green_yield = self.stale_green_yield();
+            if (recall.is_some()
+                || stale_green_yield.is_some()
+                || self.queue_priority(longest_origin, longest_length)
+                    >= (self.current_queue() * self.switch_ratio).floor())
+                && !self.hold_for_dilemma_zone()
+            {
+                self.should_switch = true;
+                self.red_start = self.now;
+                self.past_green = self.green;
+                self.green = None;
+
+                self.next_green = recall
+                    .or(stale_green_yield)
+                    .or(Some(self.longest_queue().0));
+                self.yielding_stale_green = stale_green_yield.is_some();
+
+                self.check_yellow_trap();
+
+                self.calculate_clearance_time();
+            }
+        }
+        // If it's past yellow and the direction that just went will go again, just go back to
+        // green
+        if self.should_switch
+            && self.since(self.red_start) >= self.yellow_time()
+            && self.past_green.is_some()
+            && self.longest_queue().0 == self.past_green.unwrap()
+            && self.next_green != self.recall_due()
+            && !self.yielding_stale_green
+        {
+            self.should_switch = false;
+            self.phase_start = self.now;
+            self.green = self.past_green;
+            self.next_green = None;
+            self.start_left_turn_phase();
+            self.log_phase();
+        }
+        // // If it's past yellow, change the light (once nobody is left waiting in the intersection)
+        if self.should_switch
+            && self.since(self.red_start) >= self.red_clearance_time
+            && self.creeping_cars == 0
+        {
+            self.phase_start = self.now;
+
+            self.green = self.next_green;
+            self.next_green = None;
+
+            self.last_went
+                .entry(self.green.unwrap())
+                .and_modify(|x| *x = self.now);
+
+            self.should_switch = false;
+            self.yielding_stale_green = false;
+            self.start_left_turn_phase();
+            self.update_cycle();
+            self.log_phase();
+        }
+        self.update_left_turn_phase();
+
+        self.check_conflicts();
+    }
+
+    /// Sets which approaches' dilemma zone detectors have a car over them
+    fn detect_dilemma_zones(&mut self, occupied: [bool; 4]) {
+        self.dilemma_zones = occupied;
+    }
+
+    /// Sets the cars of each approach that are waiting to spawn
+    fn detect_backed_up(&mut self, backed_up: [Vec<SimplifiedCar>; 4]) {
+        self.backed_up = backed_up;
+    }
+
+    fn dilemma_zone_extensions(&self) -> usize {
+        self.dilemma_zone_extensions
+    }
+
+    fn phase_log(&self) -> &[PhaseEvent] {
+        &self.phase_log
+    }
+
+    /// Returns how many updates ended with conflicting movements green at once
+    fn conflicting_greens(&self) -> usize {
+        self.conflicting_greens
+    }
+
+    fn queue_underflows(&self) -> usize {
+        self.queue_underflows
+    }
+
+    fn add_car(&mut self, car: SimplifiedCar) {
+        self.queues.add(car);
+        if let Some(estimated_demand) = &mut self.estimated_demand {
+            estimated_demand.actuate(car.origin);
+        }
+    }
+
+    /// Without an INITIAL_GREEN the first car to arrive gets it. Cars that arrive in the same
+    /// update tie, so this is called once they have all been added and the tie goes to the
+    /// approach that comes first in `ORIGINS`, whatever order they were added in.
+    fn start_first_green(&mut self) {
+        if !self.start {
+            return;
+        }
+        let first = car::ORIGINS
+            .into_iter()
+            .find(|origin| self.queues.by_origin(*origin) > 0);
+        if let Some(origin) = first {
+            self.start_green(origin);
+        }
+    }
+
+    fn remove_car(&mut self, origin: car::Origin, direction: car::Direction) {
+        // A car removed twice would take another car's place in the queue, so leave the queue
+        // alone and count it instead
+        if !self.queues.remove(origin, direction) {
+            self.queue_underflows += 1;
+            return;
+        }
+        self.cycle_served[origin.index()] += 1;
+        if let Some(estimated_demand) = &mut self.estimated_demand {
+            estimated_demand.cross(origin);
+        }
+        self.last_entered.insert((origin, direction), self.now);
+        self.last_crossed[origin.index()] = self.now;
+
+        // If they turned right on red, don't use them for clearance time
+        if ((self.green.is_some() && Some(origin) != self.green)
+            || (self.green.is_none()
+                && self.past_green.is_some()
+                && Some(origin) != self.past_green))
+            && direction == car::Direction::Right
+        {
+            return;
+        }
+        // Once the light is changing, a car that gets in can only make the all red longer, the
+        // car before it might still be in the intersection (less so the longer before the
+        // yellow it went)
+        let clearance = self
+            .red_clearance_time
+            .saturating_sub(self.red_start.saturating_sub(self.latest_car_time));
+        self.latest_car = Some(SimplifiedCar { origin, direction });
+        self.latest_car_time = self.now;
+        self.calculate_clearance_time();
+        if self.should_switch {
+            self.red_clearance_time = self.red_clearance_time.max(clearance);
+        }
+    }
+
+    /// The first car to do it during a green sets the green's startup lost time
+    fn left_stop_line(&mut self, origin: car::Origin, direction: car::Direction) {
+        if let Some(event) = self.phase_log.last_mut() {
+            if event.startup_lost_time.is_none() && event.movements.contains(&(origin, direction)) {
+                event.startup_lost_time = Some(self.now.saturating_sub(event.time));
+            }
+        }
+    }
+
+    fn green(&self) -> Option<car::Origin> {
+        self.green
+    }
+
+    /// Returns if the approach's green just ended and the next one hasn't started yet
+    fn clearing(&self, origin: car::Origin) -> bool {
+        self.green.is_none() && self.past_green == Some(origin)
+    }
+
+    /// Returns how many cars from the given approach are waiting for the light
+    fn queue_by_origin(&self, origin: car::Origin) -> usize {
+        self.queues.by_origin(origin)
+    }
+
+    /// Returns how many cars making the given movement are waiting for the light
+    fn queue_by_movement(&self, origin: car::Origin, direction: car::Direction) -> usize {
+        self.queues.by_movement(origin, direction)
+    }
+
+    /// Returns if the light is green or yellow for the given origin.
+    fn is_green(&self, origin: car::Origin, direction: car::Direction) -> bool {
+        // Movements crossing the oncoming traffic have to wait while it has a short green, and
+        // then until the last of it is through
+        if direction.crosses_oncoming()
+            && ((0..=3).map(car::Direction::from).any(|oncoming| {
+                self.short_green(origin.opposite(), oncoming)
+                    && self.conflicts(
+                        &SimplifiedCar::new(origin.opposite(), oncoming),
+                        &SimplifiedCar::new(origin, direction),
+                    )
+            }) || !self.clear_of_conflicts(origin, direction))
+        {
+            return false;
+        }
+
+        let has_green = self.green == Some(origin);
+        let has_yellow = self.is_yellow(origin);
+
+        has_green || has_yellow || self.short_green(origin, direction)
+    }
+
+    /// Returns if the approach's left turners can go after yielding to the oncoming traffic (the
+    /// permissive part of a `LeftTurnOrder::ProtectedPermissive` green)
+    fn permissive_left(&self, origin: car::Origin) -> bool {
+        self.green == Some(origin)
+            && TrafficLight::left_turn_order(origin) == LeftTurnOrder::ProtectedPermissive
+            && !self.protected_left
+    }
+
+    /// Returns how many yellow traps have been detected in total
+    fn yellow_traps(&self) -> usize {
+        self.yellow_traps.values().sum()
+    }
+
+    /// Returns true if the light is yellow, but only for the first part of the yellow
+    /// Only the first part so that cars don't enter the intersection right before it turns red
+    /// (and never once the next green started, which can be sooner when the red clearance is short)
+    fn is_yellow(&self, origin: car::Origin) -> bool {
+        if ALLOW_GO_ON_YELLOW {
+            self.past_green == Some(origin)
+                && self.green.is_none()
+                && self.since(self.red_start).as_millis()
+                    < (self.yellow_times[origin.index()].as_millis() as f64 * 0.3) as u128
+        } else {
+            false
+        }
+    }
+
+    /// Returns if the approach's light is yellow, for the whole yellow (until the next green
+    /// starts)
+    fn in_yellow(&self, origin: car::Origin) -> bool {
+        self.past_green == Some(origin)
+            && self.green.is_none()
+            && self.since(self.red_start) < self.yellow_times[origin.index()]
+    }
+
+    /// A left turner pulled into the intersection to wait for its gap, so the next green has to
+    /// wait for it
+    fn start_creeping(&mut self) {
+        self.creeping_cars += 1;
+    }
+
+    /// A left turner that was waiting in the intersection got going. If the light is already
+    /// changing, the red clearance starts over (after the yellow) so it has time to get through.
+    fn stop_creeping(&mut self) {
+        self.creeping_cars = self.creeping_cars.saturating_sub(1);
+        if self.should_switch {
+            self.red_start = self
+                .red_start
+                .max(self.now.saturating_sub(self.yellow_time()));
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
+
+impl DrawController for TrafficLight {
+    fn draw(&self, context: &Context, graphics: &mut G2d) {
+        for origin in car::ORIGINS.iter() {
+            let transform = controller::draw_signal_head(
+                *origin,
+                controller::signal_colors(self, *origin),
+                context,
+                graphics,
+            );
+
+            // Left arrow in the green light while the left turners have the green to themselves
+            if self.protected_left && self.green == Some(*origin) {
+                let x = LIGHT_RADIUS + LIGHT_RADIUS * 0.5;
+                let y = LIGHT_RADIUS + 2.0 * (LIGHT_RADIUS * 2.0 + LIGHT_SPACING) + LIGHT_RADIUS;
+                Line::new_round([0.0, 0.0, 0.0, 1.0], 2.0).draw_arrow(
+                    [x + LIGHT_RADIUS * 2.0 - 5.0, y, x + 5.0, y],
+                    10.0,
+                    &DrawState::default(),
+                    transform,
+                    graphics,
+                );
+            }
+            // Flashing yellow arrow while they have to yield to the oncoming traffic
+            if self.flashing_yellow_arrow(*origin) {
+                let x = LIGHT_RADIUS + LIGHT_RADIUS * 0.5;
+                let y = LIGHT_RADIUS + (LIGHT_RADIUS * 2.0 + LIGHT_SPACING) + LIGHT_RADIUS;
+                Line::new_round(YELLOW, 2.0).draw_arrow(
+                    [x + LIGHT_RADIUS * 2.0 - 5.0, y, x + 5.0, y],
+                    10.0,
+                    &DrawState::default(),
                     transform,
                     graphics,
                 );
             }
 
-            if (self.green.is_some() && &self.green.unwrap().right() == origin)
+            if ((self.green.is_some() && &self.green.unwrap().right() == origin)
                 || (self.green.is_none()
                     && self.past_green.is_some()
-                    && &self.past_green.unwrap().right() == origin)
+                    && &self.past_green.unwrap().right() == origin))
+                && car::movement_allowed(*origin, car::Direction::Right)
             {
-                let x = light_radius + light_radius * 0.5;
-                let green_y = light_radius + 3.0 * (light_radius * 2.0 + light_spacing)
-                    - light_radius * 2.0
+                let x = LIGHT_RADIUS + LIGHT_RADIUS * 0.5;
+                let green_y = LIGHT_RADIUS + 3.0 * (LIGHT_RADIUS * 2.0 + LIGHT_SPACING)
+                    - LIGHT_RADIUS * 2.0
                     + 5.0;
                 let mut y = green_y;
-                if self.phase_start.elapsed() > self.minimum_green_time
-                    && self.red_start.elapsed() < self.yellow_time
+                if self.since(self.phase_start) > self.minimum_green_time
+                    && self.since(self.red_start) < self.yellow_time()
                 {
-                    y -= (light_radius * 2.0 - light_spacing) * 2.0;
+                    y -= (LIGHT_RADIUS * 2.0 - LIGHT_SPACING) * 2.0;
                 }
                 if !self.should_switch
-                    || (self.should_switch && self.red_start.elapsed() < self.yellow_time)
+                    || (self.should_switch && self.since(self.red_start) < self.yellow_time())
                 {
                     Line::new_round([0.8; 4], 2.0).draw_arrow(
-                        [x + 5.0, y, x + light_radius * 2.0 - 5.0, y],
+                        [x + 5.0, y, x + LIGHT_RADIUS * 2.0 - 5.0, y],
                         10.0,
                         &DrawState::default(),
                         transform,
@@ -432,9 +1380,13 @@ impl TrafficLight {
                 }
             }
         }
+
+        if SHOW_CONFLICTS {
+            self.draw_conflicts(context, graphics);
+        }
     }
 
-    pub fn draw_stats(&self, glyphs: &mut Glyphs, context: &Context, graphics: &mut G2d) {
+    fn draw_stats(&self, glyphs: &mut Glyphs, context: &Context, graphics: &mut G2d) {
         let lines = [
             format!(
                 "Green time: {:.02}s",
@@ -449,10 +1401,12 @@ impl TrafficLight {
                 (self.red_clearance_time.as_millis() as f64 / 1000.0)
             ),
             format!(
-                "Red time (w/0 yellow): {:.02}s",
-                // (self.red_clearance_time - self.yellow_time).as_millis() as f64 / 1000.0
-                (self.red_clearance_time.as_millis() as f64 - self.yellow_time.as_millis() as f64)
-                    / 1000.0
+                "Red time (w/0 yellow): {:.02}s ({}x safety factor)",
+                // (self.red_clearance_time - self.yellow_time()).as_millis() as f64 / 1000.0
+                (self.red_clearance_time.as_millis() as f64
+                    - self.yellow_time().as_millis() as f64)
+                    / 1000.0,
+                RED_CLEARANCE_FACTOR
             ),
             format!(
                 "Next Green: {:?}",
@@ -462,6 +1416,31 @@ impl TrafficLight {
                     String::from("None")
                 }
             ),
+            format!(
+                "Left turns: {}",
+                match self.green {
+                    Some(green) if self.protected_left => format!("{:?} protected", green),
+                    Some(green) if self.permissive_left(green) => {
+                        format!("{:?} permissive", green)
+                    }
+                    Some(green) if self.short_green(green.opposite(), car::Direction::Straight) => {
+                        format!("{:?} held for {:?}", green, green.opposite())
+                    }
+                    _ => String::from("-"),
+                }
+            ),
+            format!(
+                "Cycle: {:.02}s, last: {}",
+                self.since(self.cycle_start).as_secs_f64(),
+                match self.last_cycle {
+                    Some((length, served)) => format!(
+                        "{:.02}s, served (N, S, E, W) {:?}",
+                        length.as_secs_f64(),
+                        served
+                    ),
+                    None => String::from("-"),
+                }
+            ),
             format!(
                 "Latest Car: {:?}",
                 if let Some(car) = self.latest_car {
@@ -486,147 +1465,338 @@ impl TrafficLight {
                 .unwrap();
         }
     }
+}
 
-    pub fn add_car(&mut self, car: SimplifiedCar) {
-        self.queue.entry(car.origin).or_insert(Vec::new()).push(car);
-        if self.start {
-            self.green = Some(car.origin);
-            self.phase_start = Instant::now();
-            self.start = false;
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{simulation::Simulation, SEED};
 
-    pub fn remove_car(&mut self, origin: car::Origin, direction: car::Direction) {
-        if let Some(queue) = self.queue.get_mut(&origin) {
-            queue.remove(0);
-            if direction == car::Direction::Left {
-                self.last_intersection_obstruction = Instant::now();
-            }
+    fn movement(origin: car::Origin, direction: car::Direction) -> SimplifiedCar {
+        SimplifiedCar::new(origin, direction)
+    }
 
-            // If they turned right on red, don't use them for clearance time
-            if ((self.green.is_some() && Some(origin) != self.green)
-                || (self.green.is_none()
-                    && self.past_green.is_some()
-                    && Some(origin) != self.past_green))
-                && direction == car::Direction::Right
-            {
-                return;
+    #[test]
+    fn conflicts_go_both_ways() {
+        let traffic_light = TrafficLight::new();
+        for first in TrafficLight::all_movements() {
+            for second in TrafficLight::all_movements() {
+                assert_eq!(
+                    traffic_light
+                        .conflicts
+                        .contains(&(first.movement(), second.movement())),
+                    traffic_light
+                        .conflicts
+                        .contains(&(second.movement(), first.movement())),
+                    "{:?} and {:?}",
+                    first,
+                    second
+                );
             }
-            self.latest_car = Some(SimplifiedCar { origin, direction });
-            self.calculate_clearance_time();
         }
+
+        // Opposing throughs pass each other, opposing left turns cut across them
+        let north_straight = (car::Origin::North, car::Direction::Straight);
+        let south_straight = (car::Origin::South, car::Direction::Straight);
+        let south_left = (car::Origin::South, car::Direction::Left);
+        assert!(!traffic_light
+            .conflicts
+            .contains(&(north_straight, south_straight)));
+        assert!(traffic_light
+            .conflicts
+            .contains(&(north_straight, south_left)));
+        assert!(traffic_light
+            .conflicts
+            .contains(&(south_left, north_straight)));
     }
 
-    /// Calculates entry time and red clearance time
-    fn calculate_clearance_time(&mut self) {
-        if let Some(moving_car) = self.latest_car {
-            let waiting_car = if let Some(green) = self.next_green {
-                if let Some(car) = self.queue.get(&green).and_then(|queue| queue.get(0)) {
-                    Some(SimplifiedCar {
-                        origin: green,
-                        direction: car.direction,
-                    })
-                } else {
-                    None
+    #[test]
+    fn conflict_matrix() {
+        let traffic_light = TrafficLight::new();
+        let conflict = |first: (car::Origin, car::Direction), second| {
+            traffic_light.conflicts.contains(&(first, second))
+        };
+        for origin in car::ORIGINS {
+            for first in (0..=3).map(car::Direction::from) {
+                for second in (0..=3).map(car::Direction::from) {
+                    assert!(!conflict((origin, first), (origin, second)));
+                }
+                // Right turns stay in their corner, out of the way of the opposite approach's
+                // through traffic and everything from the left but its U-turns
+                if !first.crosses_oncoming() {
+                    assert!(!conflict(
+                        (origin, car::Direction::Right),
+                        (origin.opposite(), first)
+                    ));
+                }
+                if first != car::Direction::UTurn {
+                    assert!(!conflict(
+                        (origin, car::Direction::Right),
+                        (origin.left(), first)
+                    ));
                 }
-            } else {
-                None
-            };
-            if let Some(waiting_car) = waiting_car {
-                self.calculate_entry_time(&moving_car, &waiting_car);
             }
+            // Crossing throughs, and right turns into the lane of the through traffic from the
+            // right
+            assert!(conflict(
+                (origin, car::Direction::Straight),
+                (origin.right(), car::Direction::Straight)
+            ));
+            assert!(conflict(
+                (origin, car::Direction::Right),
+                (origin.right(), car::Direction::Straight)
+            ));
+        }
 
-            self.calculate_red_clearance_time(moving_car.direction);
+        let matrix = traffic_light.dump_conflict_matrix();
+        assert_eq!(
+            matrix.lines().count(),
+            TrafficLight::all_movements().len() + 1
+        );
+    }
+
+    #[test]
+    fn conflicting_movements_are_never_green_together() {
+        let mut simulation = Simulation::with_seed(Box::new(TrafficLight::new()), SEED);
+        while simulation.time() < Duration::from_secs(60) {
+            simulation.step(TICK);
+            assert_eq!(simulation.traffic_light().conflicting_greens(), 0);
         }
     }
 
-    pub fn green(&self) -> Option<car::Origin> {
-        self.green
+    #[test]
+    fn conflict_delay_overrides_are_one_way() {
+        let mut traffic_light = TrafficLight::new();
+        let moving = movement(car::Origin::North, car::Direction::Straight);
+        let waiting = movement(car::Origin::South, car::Direction::Left);
+        let computed = traffic_light.conflict_delay(&waiting, &moving);
+
+        traffic_light.conflict_delay_overrides.insert(
+            (moving.movement(), waiting.movement()),
+            Duration::from_millis(700),
+        );
+        assert_eq!(
+            traffic_light.conflict_delay(&moving, &waiting),
+            Duration::from_millis(700)
+        );
+        assert_eq!(traffic_light.conflict_delay(&waiting, &moving), computed);
     }
 
-    pub fn past_green(&self) -> Option<car::Origin> {
-        self.past_green
+    #[test]
+    fn zero_conflict_delay_leaves_just_the_yellow() {
+        let mut traffic_light = TrafficLight::new();
+        let moving = movement(car::Origin::North, car::Direction::Straight);
+        let waiting = movement(car::Origin::East, car::Direction::Straight);
+        traffic_light
+            .conflict_delay_overrides
+            .insert((moving.movement(), waiting.movement()), Duration::ZERO);
+
+        traffic_light.add_car(waiting);
+        traffic_light.latest_car = Some(moving);
+        traffic_light.next_green = Some(car::Origin::East);
+        traffic_light.calculate_clearance_time();
+        assert_eq!(
+            traffic_light.conflict_delay(&moving, &waiting),
+            Duration::ZERO
+        );
+        assert_eq!(
+            traffic_light.red_clearance_time,
+            traffic_light.yellow_time()
+        );
     }
 
-    /// Returns if the light is green or yellow for the given origin.
-    pub fn is_green(&self, origin: car::Origin, direction: car::Direction) -> bool {
-        let has_green = self.green == Some(origin);
-        let has_yellow = self.is_yellow(origin);
+    /// A light that gave North the green with 2 cars waiting, updated after the minimum green
+    /// with 3 cars waiting on East
+    fn north_green_with_east_waiting(switch_ratio: f64) -> TrafficLight {
+        let mut traffic_light = TrafficLight::new();
+        traffic_light.set_switch_ratio(switch_ratio);
+        for _ in 0..2 {
+            traffic_light.add_car(movement(car::Origin::North, car::Direction::Straight));
+        }
+        traffic_light.start_first_green();
+        for _ in 0..3 {
+            traffic_light.add_car(movement(car::Origin::East, car::Direction::Straight));
+        }
+        traffic_light.update(Duration::from_secs(3));
+        traffic_light
+    }
 
-        let using_green = if self.green.is_some() {
-            self.green.unwrap()
-        } else {
-            self.past_green.unwrap()
-        };
-<<<<<<< HEAD
-
-        // * 2 for more of a buffer on the clearance time
-        // Short green allows some cars in opposite direction of the current green to go
-        let mut short_green = false;
-        if ALLOW_MOVING_ON_RED {
-            short_green = (!self.should_switch
-                || (self.should_switch && self.red_start.elapsed() < self.yellow_time))
-                && direction != car::Direction::Left
-                && using_green == origin.opposite()
-                && self.last_intersection_obstruction.elapsed()
-                    > Duration::from_millis(
-                        (self.red_clearance_time.as_millis() as f64
-                            - self.yellow_time.as_millis() as f64)
-                            .max(0.0) as u64
-                            * 2,
-                    )
-                && self.queue.get(&using_green).unwrap().len() > 0
-                && self
-                    .queue
-                    .get(&using_green)
-                    .unwrap()
-                    .get(0)
-                    .unwrap()
-                    .direction
-                    != car::Direction::Left;
-            if short_green && self.green.is_none() && self.past_green.is_none() {
-                short_green = false;
-            }
+    #[test]
+    fn yellow_lasts_until_the_yellow_time() {
+        let mut traffic_light = north_green_with_east_waiting(1.0);
+        assert!(traffic_light.in_yellow(car::Origin::North));
+        assert!(!traffic_light.in_yellow(car::Origin::East));
+
+        traffic_light.update(Duration::from_secs(3) + traffic_light.yellow_times[0] - TICK);
+        assert!(traffic_light.in_yellow(car::Origin::North));
+        traffic_light.update(Duration::from_secs(3) + traffic_light.yellow_times[0]);
+        assert!(!traffic_light.in_yellow(car::Origin::North));
+        assert_eq!(traffic_light.green(), None);
+    }
+
+    #[test]
+    fn switch_ratio_sets_how_much_longer_the_queue_has_to_be() {
+        // 3 cars against the green's 2: enough at a ratio of 1
+        let traffic_light = north_green_with_east_waiting(1.0);
+        assert_eq!(traffic_light.green(), None);
+        assert_eq!(traffic_light.past_green, Some(car::Origin::North));
+        assert_eq!(traffic_light.next_green, Some(car::Origin::East));
+
+        // But not at 4, however long they wait
+        let mut traffic_light = north_green_with_east_waiting(4.0);
+        assert_eq!(traffic_light.green(), Some(car::Origin::North));
+        assert_eq!(traffic_light.next_green, None);
+        traffic_light.update(Duration::from_secs(30));
+        assert_eq!(traffic_light.green(), Some(car::Origin::North));
+    }
+
+    #[test]
+    fn faster_approaches_get_longer_yellows() {
+        assert!(
+            yellow_time_for_speed(car::MAX_SPEED) > yellow_time_for_speed(car::MAX_SPEED / 2.0)
+        );
+
+        // North's 4s yellow is still on when East's 1s one would be over
+        let mut traffic_light = TrafficLight::new();
+        traffic_light.yellow_times = [
+            Duration::from_secs(4),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        ];
+        traffic_light.set_switch_ratio(1.0);
+        for _ in 0..2 {
+            traffic_light.add_car(movement(car::Origin::North, car::Direction::Straight));
         }
-=======
-        let short_green = (!self.should_switch
-            || (self.should_switch && self.red_start.elapsed() < self.yellow_time))
-            && direction != car::Direction::Left
-            && using_green == origin.opposite()
-            && self.last_intersection_obstruction.elapsed()
-                > self.red_clearance_time - self.yellow_time;
-        // && self.queue.get(&using_green).unwrap().len() > 0
-        // && self
-        //     .queue
-        //     .get(&using_green)
-        //     .unwrap()
-        //     .get(0)
-        //     .unwrap()
-        //     .direction
-        //     != car::Direction::Left;
->>>>>>> parent of c4edcb6 (cars go straight/right when possible touch buggy?)
-        has_green || has_yellow || short_green
+        traffic_light.start_first_green();
+        for _ in 0..3 {
+            traffic_light.add_car(movement(car::Origin::East, car::Direction::Straight));
+        }
+        traffic_light.update(Duration::from_secs(3));
+        assert_eq!(traffic_light.yellow_time(), Duration::from_secs(4));
+        traffic_light.update(Duration::from_secs_f64(4.5));
+        assert!(traffic_light.in_yellow(car::Origin::North));
     }
 
-    /// Returns true if the light is yellow, but only for the first part of the yellow
-    /// Only the first part so that cars don't enter the intersection right before it turns red
-    pub fn is_yellow(&self, origin: car::Origin) -> bool {
-        if ALLOW_GO_ON_YELLOW {
-            self.past_green == Some(origin)
-                && self.red_start.elapsed().as_millis()
-                    < (self.yellow_time.as_millis() as f64 * 0.3) as u128
-        } else {
-            false
+    #[test]
+    fn first_green_tie_goes_by_origins_order() {
+        let mut traffic_light = TrafficLight::new();
+        for origin in [car::Origin::West, car::Origin::East, car::Origin::South] {
+            traffic_light.add_car(movement(origin, car::Direction::Straight));
         }
+        traffic_light.start_first_green();
+        assert_eq!(traffic_light.green(), Some(car::Origin::South));
+
+        let mut traffic_light = TrafficLight::new();
+        traffic_light.add_car(movement(car::Origin::West, car::Direction::Left));
+        traffic_light.start_first_green();
+        assert_eq!(traffic_light.green(), Some(car::Origin::West));
     }
 
-    pub fn green_time(&self) -> Duration {
-        self.green_time
+    #[test]
+    fn queue_ties_go_by_origins_order() {
+        let mut traffic_light = TrafficLight::new();
+        for _ in 0..2 {
+            traffic_light.add_car(movement(car::Origin::West, car::Direction::Straight));
+            traffic_light.add_car(movement(car::Origin::East, car::Direction::Straight));
+        }
+        assert_eq!(traffic_light.longest_queue(), (car::Origin::East, 2.0));
+
+        // Unless the other one has gone longer without a green
+        traffic_light.update(Duration::from_secs(10));
+        traffic_light
+            .last_went
+            .insert(car::Origin::East, Duration::from_secs(5));
+        assert_eq!(traffic_light.longest_queue(), (car::Origin::West, 2.0));
     }
 
-    pub fn unpause(&mut self, time_elapsed: Duration) {
-        self.last_intersection_obstruction += time_elapsed;
-        self.phase_start += time_elapsed;
-        self.red_start += time_elapsed;
+    /// Debug builds stop at the first car removed without one of its movement waiting, release
+    /// builds count it and leave the queue alone
+    #[test]
+    #[cfg_attr(
+        debug_assertions,
+        should_panic(expected = "removed without one waiting")
+    )]
+    fn removing_a_car_that_isnt_waiting_is_caught() {
+        let mut traffic_light = TrafficLight::new();
+        traffic_light.add_car(movement(car::Origin::North, car::Direction::Straight));
+        // The straight car keeps its place
+        traffic_light.remove_car(car::Origin::North, car::Direction::Left);
+        assert_eq!(traffic_light.queue_by_origin(car::Origin::North), 1);
+        assert_eq!(traffic_light.queue_underflows(), 1);
+
+        traffic_light.remove_car(car::Origin::North, car::Direction::Straight);
+        assert_eq!(traffic_light.queue_by_origin(car::Origin::North), 0);
+        traffic_light.remove_car(car::Origin::North, car::Direction::Straight);
+        assert_eq!(traffic_light.queue_by_origin(car::Origin::North), 0);
+        assert_eq!(traffic_light.queue_underflows(), 2);
+
+        // And the next car is still counted
+        traffic_light.add_car(movement(car::Origin::North, car::Direction::Straight));
+        assert_eq!(traffic_light.queue_by_origin(car::Origin::North), 1);
+    }
+
+    #[test]
+    fn fresh_light_is_all_red() {
+        let mut traffic_light = TrafficLight::new();
+        traffic_light.update(TICK);
+        traffic_light.start_first_green();
+
+        assert_eq!(traffic_light.green(), None);
+        for car in TrafficLight::all_movements() {
+            assert!(
+                !traffic_light.is_green(car.origin, car.direction),
+                "{:?}",
+                car
+            );
+        }
+        for origin in car::ORIGINS {
+            assert!(!traffic_light.is_yellow(origin));
+            assert!(!traffic_light.in_yellow(origin));
+            assert!(!traffic_light.clearing(origin));
+            assert!(!traffic_light.permissive_left(origin));
+            assert_eq!(traffic_light.queue_by_origin(origin), 0);
+            assert_eq!(
+                controller::signal_colors(&traffic_light, origin),
+                [
+                    controller::RED,
+                    controller::DARK_YELLOW,
+                    controller::DARK_GREEN
+                ]
+            );
+        }
+        assert_eq!(traffic_light.longest_queue(), (car::Origin::North, 0.0));
+        assert_eq!(traffic_light.yellow_traps(), 0);
+        assert_eq!(traffic_light.conflicting_greens(), 0);
+        assert!(traffic_light.phase_log().is_empty());
+    }
+
+    #[test]
+    fn crossing_time_follows_turning_speed() {
+        for direction in [
+            car::Direction::Straight,
+            car::Direction::Left,
+            car::Direction::Right,
+            car::Direction::UTurn,
+        ] {
+            let ticks = TrafficLight::crossing_distance(direction) / car::turning_speed(direction);
+            let crossing_time = TrafficLight::crossing_time(direction).as_secs_f64();
+            assert!(
+                (crossing_time - TICK.as_secs_f64() * ticks).abs() < 1e-6,
+                "{:?}",
+                direction
+            );
+        }
+
+        // Tighter turns are taken slower, so a left turn takes longer than going straight even
+        // though the two are about as long
+        assert!(car::turning_speed(car::Direction::Left) < car::MAX_SPEED);
+        assert!(
+            car::turning_speed(car::Direction::Right) < car::turning_speed(car::Direction::Left)
+        );
+        assert!(
+            TrafficLight::crossing_time(car::Direction::Left)
+                > TrafficLight::crossing_time(car::Direction::Straight)
+        );
     }
 }
@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+use crate::{simulation::TICK, METERS_PER_PIXEL};
+
+/// Converts a distance in pixels to meters
+pub fn meters(pixels: f64) -> f64 {
+    pixels * METERS_PER_PIXEL
+}
+
+/// Converts a speed in pixels per update to km/h
+pub fn kilometers_per_hour(pixels_per_tick: f64) -> f64 {
+    meters(pixels_per_tick) / TICK.as_secs_f64() * 3.6
+}
+
+/// Converts a number of vehicles over a stretch of simulated time to vehicles per hour
+pub fn vehicles_per_hour(vehicles: usize, time: Duration) -> f64 {
+    if time.is_zero() {
+        0.0
+    } else {
+        vehicles as f64 / time.as_secs_f64() * 3600.0
+    }
+}